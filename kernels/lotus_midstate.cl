@@ -0,0 +1,167 @@
+// Lotus kernel with everything that doesn't depend on the nonce computed on
+// the host, like poclbm's PreVal/D1A arguments. `LotusPrecalc` in sha256.rs
+// computes the arguments and mirrors this kernel step by step.
+
+typedef uint num_t;
+
+__constant uint H[8] = {
+	0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19
+};
+
+__constant uint K[64] = {
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2
+};
+
+// K[i] + w[i] of the chain layer's padding block, whose schedule is constant
+__constant uint CHAIN_LAYER_PAD_KW[64] = {
+    0xc28a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf374,
+    0x649b69c1, 0xf0fe4786, 0x0fe1edc6, 0x240cf254, 0x4fe9346f, 0x6cc984be, 0x61b9411e, 0x16f988fa,
+    0xf2c65152, 0xa88e5a6d, 0xb019fc65, 0xb9d99ec7, 0x9a1231c3, 0xe70eeaa0, 0xfdb1232b, 0xc7353eb0,
+    0x3069bad5, 0xcb976d5f, 0x5a0f118f, 0xdc1eeefd, 0x0a35b689, 0xde0b7a04, 0x58f4ca9d, 0xe15d5b16,
+    0x007f3e86, 0x37088980, 0xa507ea32, 0x6fab9537, 0x17406110, 0x0d8cd6f1, 0xcdaa3b6d, 0xc0bbbe37,
+    0x83613bda, 0xdb48a363, 0x0b02e931, 0x6fd15ca7, 0x521afaca, 0x31338431, 0x6ed41a95, 0x6d437890,
+    0xc39c91f2, 0x9eccabbd, 0xb5c9a0e6, 0x532fb63c, 0xd2c741c6, 0x07237ea3, 0xa4954b68, 0x4c191d76
+};
+
+// output[RESULT_COUNT] counts the nonces found, output[RESULT_OVERFLOW] is set
+// if more than MAX_RESULTS were found, and output[RESULTS..] holds the first
+// MAX_RESULTS of them. MAX_RESULTS is defined by the host.
+#define RESULT_COUNT 0
+#define RESULT_OVERFLOW 1
+#define RESULTS 2
+
+void report_nonce(__global uint *output, uint nonce) {
+    uint idx = atomic_inc(&output[RESULT_COUNT]);
+    if (idx < MAX_RESULTS) {
+        output[RESULTS + idx] = nonce;
+    } else {
+        output[RESULT_OVERFLOW] = 1;
+    }
+}
+
+#define rotr(x, y) rotate((num_t)x, (num_t)(32-y))
+
+num_t sigma0(num_t a) {
+    return rotr(a, 2) ^ rotr(a, 13) ^ rotr(a, 22);
+}
+
+num_t sigma1(num_t e) {
+    return rotr(e, 6) ^ rotr(e, 11) ^ rotr(e, 25);
+}
+
+num_t choose(num_t e, num_t f, num_t g) {
+    return bitselect(g, f, e);
+}
+
+num_t majority(num_t a, num_t b, num_t c) {
+    return bitselect(b, a, b ^ c);
+}
+
+num_t schedule0(num_t w) {
+    return rotr(w, 7) ^ rotr(w, 18) ^ (w >> 3);
+}
+
+num_t schedule1(num_t w) {
+    return rotr(w, 17) ^ rotr(w, 19) ^ (w >> 10);
+}
+
+void sha256_round(
+    __private num_t *state,
+    num_t kw
+) {
+    num_t tmp1 = state[7] + sigma1(state[4]) + choose(state[4], state[5], state[6]) + kw;
+    num_t tmp2 = sigma0(state[0]) + majority(state[0], state[1], state[2]);
+    state[7] = state[6];
+    state[6] = state[5];
+    state[5] = state[4];
+    state[4] = state[3] + tmp1;
+    state[3] = state[2];
+    state[2] = state[1];
+    state[1] = state[0];
+    state[0] = tmp1 + tmp2;
+}
+
+void sha256_extend(
+    __private num_t *w,
+    uint start
+) {
+    for (uint i = start; i < 64; ++i) {
+        w[i] = w[i-16] + schedule0(w[i-15]) + w[i-7] + schedule1(w[i-2]);
+    }
+}
+
+__kernel void search(
+    const uint offset,
+    // pow layer state after round 3, minus the nonce
+    const uint PreVal0, const uint B1, const uint C1, const uint D1,
+    const uint PreVal4, const uint F1, const uint G1, const uint H1,
+    // pow layer message words after the nonce
+    const uint W4, const uint W5, const uint W6, const uint W7, const uint W8,
+    const uint W9, const uint W10, const uint W11, const uint W12,
+    // pow layer schedule words, minus the nonce's share
+    const uint W16, const uint W17, const uint PreW18, const uint PreW19,
+    // chain layer state after round 7, and its message words 0..7 (the
+    // previous block hash)
+    const uint CA, const uint CB, const uint CC, const uint CD,
+    const uint CE, const uint CF, const uint CG, const uint CH,
+    const uint Prev0, const uint Prev1, const uint Prev2, const uint Prev3,
+    const uint Prev4, const uint Prev5, const uint Prev6, const uint Prev7,
+    __global uint *output
+) {
+    num_t pow_w[64];
+    pow_w[4] = W4; pow_w[5] = W5; pow_w[6] = W6; pow_w[7] = W7; pow_w[8] = W8;
+    pow_w[9] = W9; pow_w[10] = W10; pow_w[11] = W11; pow_w[12] = W12;
+    pow_w[13] = 0x80000000; pow_w[14] = 0x00000000; pow_w[15] = 0x000001a0;
+    pow_w[16] = W16; pow_w[17] = W17;
+    num_t chain_w[64];
+    chain_w[0] = Prev0; chain_w[1] = Prev1; chain_w[2] = Prev2; chain_w[3] = Prev3;
+    chain_w[4] = Prev4; chain_w[5] = Prev5; chain_w[6] = Prev6; chain_w[7] = Prev7;
+
+    for (uint iteration = 0; iteration < ITERATIONS; ++iteration) {
+        num_t nonce = offset + get_global_id(0) * ITERATIONS + iteration;
+
+        // pow layer; rounds 0..2 ran on the host, round 3 only adds the nonce
+        num_t state[8] = {
+            PreVal0 + nonce, B1, C1, D1, PreVal4 + nonce, F1, G1, H1
+        };
+        pow_w[3] = nonce;
+        pow_w[18] = PreW18 + schedule0(nonce);
+        pow_w[19] = PreW19 + nonce;
+        sha256_extend(pow_w, 20);
+        for (uint i = 4; i < 64; ++i) {
+            sha256_round(state, K[i] + pow_w[i]);
+        }
+
+        // chain layer; rounds 0..7 only depend on the previous block hash
+        for (uint i = 0; i < 8; ++i) {
+            chain_w[i + 8] = state[i] + H[i];
+        }
+        sha256_extend(chain_w, 16);
+        num_t chain_state[8] = { CA, CB, CC, CD, CE, CF, CG, CH };
+        for (uint i = 8; i < 64; ++i) {
+            sha256_round(chain_state, K[i] + chain_w[i]);
+        }
+        for (uint i = 0; i < 8; ++i) {
+            chain_state[i] += H[i];
+            state[i] = chain_state[i];
+        }
+
+        // padding block; the last hash word is e after round 60 plus the
+        // input state, so the last three rounds are skipped
+        for (uint i = 0; i < 61; ++i) {
+            sha256_round(state, CHAIN_LAYER_PAD_KW[i]);
+        }
+
+        if (chain_state[7] + state[4] == 0) {
+            report_nonce(output, nonce);
+        }
+    }
+}
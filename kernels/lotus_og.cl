@@ -30,8 +30,21 @@ __constant uint CHAIN_LAYER_SCHEDULE_ARRAY[64] = {
     0x4f0d0f04, 0x2627484e, 0x310128d2, 0xc668b434, 0x420841cc, 0x62d311b8, 0xe59ba771, 0x85a7a484,
 };
 
-#define FOUND (0x80)
-#define NFLAG (0x7F)
+// output[RESULT_COUNT] counts the nonces found, output[RESULT_OVERFLOW] is set
+// if more than MAX_RESULTS were found, and output[RESULTS..] holds the first
+// MAX_RESULTS of them. MAX_RESULTS is defined by the host.
+#define RESULT_COUNT 0
+#define RESULT_OVERFLOW 1
+#define RESULTS 2
+
+void report_nonce(__global uint *output, uint nonce) {
+    uint idx = atomic_inc(&output[RESULT_COUNT]);
+    if (idx < MAX_RESULTS) {
+        output[RESULTS + idx] = nonce;
+    } else {
+        output[RESULT_OVERFLOW] = 1;
+    }
+}
 
 #define rot(x, y) rotate((num_t)x, (num_t)y)
 #define rotr(x, y) rotate((num_t)x, (num_t)(32-y))
@@ -157,8 +170,7 @@ __kernel void search(
         sha256_chain_layer(chain_layer, hash);
         
         if (hash[7] == 0) {
-            output[FOUND] = 1;
-            output[NFLAG & nonce] = nonce;
+            report_nonce(output, nonce);
         }
     }
 }
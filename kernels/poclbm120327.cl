@@ -5,14 +5,30 @@
 // This file is taken and modified from the public-domain poclbm project, and
 // we have therefore decided to keep it public-domain in Phoenix.
 
+// Ported from Bitcoin's double SHA-256 to Lotus hashing. It takes the same
+// host precalculation as lotus_midstate (`LotusPrecalc` in sha256.rs) and
+// keeps poclbm's vectors and AMD rotations. With VECTORS2 or VECTORS4, each
+// work item tests 2 or 4 consecutive nonces per iteration, so ITERATIONS
+// must be a multiple of the vector width.
+
 #ifdef VECTORS4
 	typedef uint4 u;
+	#define VECTOR_WIDTH 4
+	#define LANES ((u)(0, 1, 2, 3))
 #elif defined VECTORS2
 	typedef uint2 u;
+	#define VECTOR_WIDTH 2
+	#define LANES ((u)(0, 1))
 #else
 	typedef uint u;
+	#define VECTOR_WIDTH 1
+	#define LANES 0
 #endif
 
+__constant uint IV[8] = {
+	0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19
+};
+
 __constant uint K[64] = { 
     0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
     0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
@@ -24,14 +40,33 @@ __constant uint K[64] = {
     0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2
 };
 
+// K[i] + w[i] of the chain layer's padding block, whose schedule is constant
+__constant uint CHAIN_LAYER_PAD_KW[64] = {
+    0xc28a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf374,
+    0x649b69c1, 0xf0fe4786, 0x0fe1edc6, 0x240cf254, 0x4fe9346f, 0x6cc984be, 0x61b9411e, 0x16f988fa,
+    0xf2c65152, 0xa88e5a6d, 0xb019fc65, 0xb9d99ec7, 0x9a1231c3, 0xe70eeaa0, 0xfdb1232b, 0xc7353eb0,
+    0x3069bad5, 0xcb976d5f, 0x5a0f118f, 0xdc1eeefd, 0x0a35b689, 0xde0b7a04, 0x58f4ca9d, 0xe15d5b16,
+    0x007f3e86, 0x37088980, 0xa507ea32, 0x6fab9537, 0x17406110, 0x0d8cd6f1, 0xcdaa3b6d, 0xc0bbbe37,
+    0x83613bda, 0xdb48a363, 0x0b02e931, 0x6fd15ca7, 0x521afaca, 0x31338431, 0x6ed41a95, 0x6d437890,
+    0xc39c91f2, 0x9eccabbd, 0xb5c9a0e6, 0x532fb63c, 0xd2c741c6, 0x07237ea3, 0xa4954b68, 0x4c191d76
+};
 
-// This part is not from the stock poclbm kernel. It's part of an optimization
-// added in the Phoenix Miner.
-
-// Some AMD devices have a BFI_INT opcode, which behaves exactly like the
-// SHA-256 ch function, but provides it in exactly one instruction. If
-// detected, use it for ch. Otherwise, construct ch out of simpler logical
-// primitives.
+// output[RESULT_COUNT] counts the nonces found, output[RESULT_OVERFLOW] is set
+// if more than MAX_RESULTS were found, and output[RESULTS..] holds the first
+// MAX_RESULTS of them. MAX_RESULTS is defined by the host.
+#define RESULT_COUNT 0
+#define RESULT_OVERFLOW 1
+#define RESULTS 2
+
+void report_nonce(__global uint *output, uint nonce) {
+	uint idx = atomic_inc(&output[RESULT_COUNT]);
+	if (idx < MAX_RESULTS) {
+		output[RESULTS + idx] = nonce;
+	} else {
+		output[RESULT_OVERFLOW] = 1;
+	}
+}
 
 #ifdef BITALIGN
 	#pragma OPENCL EXTENSION cl_amd_media_ops : enable
@@ -39,1315 +74,136 @@ __constant uint K[64] = {
 #else
 	#define rotr(x, y) rotate((u)x, (u)(32 - y))
 #endif
-#ifdef BFI_INT
-	// Well, slight problem... It turns out BFI_INT isn't actually exposed to
-	// OpenCL (or CAL IL for that matter) in any way. However, there is 
-	// a similar instruction, BYTE_ALIGN_INT, which is exposed to OpenCL via
-	// amd_bytealign, takes the same inputs, and provides the same output. 
-	// We can use that as a placeholder for BFI_INT and have the application 
-	// patch it after compilation.
-	
-	// This is the BFI_INT function
-	#define ch(x, y, z) amd_bytealign(x, y, z)
-	
-	// Ma can also be implemented in terms of BFI_INT...
-	#define Ma(x, y, z) amd_bytealign( (z^x), (y), (x) )
 
-	// AMD's KernelAnalyzer throws errors compiling the kernel if we use
-	// amd_bytealign on constants with vectors enabled, so we use this to avoid
-	// problems. (this is used 4 times, and likely optimized out by the compiler.)
-	#define Ma2(x, y, z) bitselect((u)x, (u)y, (u)z ^ (u)x)
-#else // BFI_INT
-	//GCN actually fails if manually patched with BFI_INT
+// The original patched BYTE_ALIGN_INT to BFI_INT in the compiled binary.
+// Current AMD compilers emit BFI_INT for bitselect by themselves, so ch and
+// Ma always use it.
+#define ch(x, y, z) bitselect((u)z, (u)y, (u)x)
+#define Ma(x, y, z) bitselect((u)x, (u)y, (u)z ^ (u)x)
+
+#define Sigma0(a) (rotr(a, 2) ^ rotr(a, 13) ^ rotr(a, 22))
+#define Sigma1(e) (rotr(e, 6) ^ rotr(e, 11) ^ rotr(e, 25))
+#define sigma0(w) (rotr(w, 7) ^ rotr(w, 18) ^ ((w) >> 3U))
+#define sigma1(w) (rotr(w, 17) ^ rotr(w, 19) ^ ((w) >> 10U))
+
+// One round; instead of moving the state around, the next round is called
+// with the registers rotated by one, so a and e are always freshly written.
+#define R(a, b, c, d, e, f, g, h, kw) { \
+	u T1 = h + Sigma1(e) + ch(e, f, g) + (kw); \
+	d += T1; \
+	h = T1 + Sigma0(a) + Ma(a, b, c); \
+}
 
-	#define ch(x, y, z) bitselect((u)z, (u)y, (u)x)
-	#define Ma(x, y, z) bitselect((u)x, (u)y, (u)z ^ (u)x)
-	#define Ma2(x, y, z) Ma(x, y, z)
-#endif
+// Rounds i..i+7, ending with the state back in A..H
+#define R8(i, KW) \
+	R(A, B, C, D, E, F, G, H, KW(i)); \
+	R(H, A, B, C, D, E, F, G, KW(i + 1)); \
+	R(G, H, A, B, C, D, E, F, KW(i + 2)); \
+	R(F, G, H, A, B, C, D, E, KW(i + 3)); \
+	R(E, F, G, H, A, B, C, D, KW(i + 4)); \
+	R(D, E, F, G, H, A, B, C, KW(i + 5)); \
+	R(C, D, E, F, G, H, A, B, KW(i + 6)); \
+	R(B, C, D, E, F, G, H, A, KW(i + 7));
+
+#define POW_KW(i) (K[i] + W[i])
+#define CHAIN_KW(i) (K[i] + CW[i])
+#define PAD_KW(i) CHAIN_LAYER_PAD_KW[i]
 
+#define EXTEND(W, i) W[i] = W[i - 16] + sigma0(W[i - 15]) + W[i - 7] + sigma1(W[i - 2])
 
 __kernel
 __attribute__((vec_type_hint(u)))
 __attribute__((reqd_work_group_size(WORKSIZE, 1, 1)))
-void search(const uint state0, const uint state1, const uint state2, const uint state3,
-	const uint state4, const uint state5, const uint state6, const uint state7,
-	const uint b1, const uint c1,
-	const uint f1, const uint g1, const uint h1,
-#ifndef GOFFSET
-	const u base,
-#endif
-	const uint fw0, const uint fw1, const uint fw2, const uint fw3, const uint fw15, const uint fw01r,
-	const uint D1A, const uint C1addK5, const uint B1addK6,
-	const uint W16addK16, const uint W17addK17,
-	const uint PreVal4addT1, const uint Preval0,
+void search(const uint offset,
+	const uint PreVal0, const uint B1, const uint C1, const uint D1,
+	const uint PreVal4, const uint F1, const uint G1, const uint H1,
+	const uint W4, const uint W5, const uint W6, const uint W7, const uint W8,
+	const uint W9, const uint W10, const uint W11, const uint W12,
+	const uint W16, const uint W17, const uint PreW18, const uint PreW19,
+	const uint CA, const uint CB, const uint CC, const uint CD,
+	const uint CE, const uint CF, const uint CG, const uint CH,
+	const uint Prev0, const uint Prev1, const uint Prev2, const uint Prev3,
+	const uint Prev4, const uint Prev5, const uint Prev6, const uint Prev7,
 	__global uint * output)
 {
-	u Vals[24];
-	u *W = &Vals[8];
-
-#ifdef GOFFSET
-	const u nonce = (uint)(get_global_id(0));
-#else
-	const u nonce = base + (uint)(get_global_id(0));
-#endif
-
-Vals[5]=Preval0;
-Vals[5]+=nonce;
-
-Vals[0]=(rotr(Vals[5],6)^rotr(Vals[5],11)^rotr(Vals[5],25));
-Vals[0]+=ch(Vals[5],b1,c1);
-Vals[0]+=D1A;
-
-Vals[2]=Vals[0];
-Vals[2]+=h1;
-
-Vals[1]=PreVal4addT1;
-Vals[1]+=nonce;
-Vals[0]+=(rotr(Vals[1],2)^rotr(Vals[1],13)^rotr(Vals[1],22));
-
-Vals[6]=C1addK5;
-Vals[6]+=(rotr(Vals[2],6)^rotr(Vals[2],11)^rotr(Vals[2],25));
-Vals[6]+=ch(Vals[2],Vals[5],b1);
-
-Vals[3]=Vals[6];
-Vals[3]+=g1;
-Vals[0]+=Ma2(g1,Vals[1],f1);
-Vals[6]+=(rotr(Vals[0],2)^rotr(Vals[0],13)^rotr(Vals[0],22));
-Vals[6]+=Ma2(f1,Vals[0],Vals[1]);
-
-Vals[7]=B1addK6;
-Vals[7]+=(rotr(Vals[3],6)^rotr(Vals[3],11)^rotr(Vals[3],25));
-Vals[7]+=ch(Vals[3],Vals[2],Vals[5]);
-
-Vals[4]=Vals[7];
-Vals[4]+=f1;
-
-Vals[7]+=(rotr(Vals[6],2)^rotr(Vals[6],13)^rotr(Vals[6],22));
-Vals[7]+=Ma(Vals[1],Vals[6],Vals[0]);
-
-Vals[5]+=(rotr(Vals[4],6)^rotr(Vals[4],11)^rotr(Vals[4],25));
-Vals[5]+=ch(Vals[4],Vals[3],Vals[2]);
-Vals[5]+=K[7];
-Vals[1]+=Vals[5];
-Vals[5]+=(rotr(Vals[7],2)^rotr(Vals[7],13)^rotr(Vals[7],22));
-Vals[5]+=Ma(Vals[0],Vals[7],Vals[6]);
-
-Vals[2]+=(rotr(Vals[1],6)^rotr(Vals[1],11)^rotr(Vals[1],25));
-Vals[2]+=ch(Vals[1],Vals[4],Vals[3]);
-Vals[2]+=K[8];
-Vals[0]+=Vals[2];
-Vals[2]+=(rotr(Vals[5],2)^rotr(Vals[5],13)^rotr(Vals[5],22));
-Vals[2]+=Ma(Vals[6],Vals[5],Vals[7]);
-
-Vals[3]+=(rotr(Vals[0],6)^rotr(Vals[0],11)^rotr(Vals[0],25));
-Vals[3]+=ch(Vals[0],Vals[1],Vals[4]);
-Vals[3]+=K[9];
-Vals[6]+=Vals[3];
-Vals[3]+=(rotr(Vals[2],2)^rotr(Vals[2],13)^rotr(Vals[2],22));
-Vals[3]+=Ma(Vals[7],Vals[2],Vals[5]);
-
-Vals[4]+=(rotr(Vals[6],6)^rotr(Vals[6],11)^rotr(Vals[6],25));
-Vals[4]+=ch(Vals[6],Vals[0],Vals[1]);
-Vals[4]+=K[10];
-Vals[7]+=Vals[4];
-Vals[4]+=(rotr(Vals[3],2)^rotr(Vals[3],13)^rotr(Vals[3],22));
-Vals[4]+=Ma(Vals[5],Vals[3],Vals[2]);
-
-Vals[1]+=(rotr(Vals[7],6)^rotr(Vals[7],11)^rotr(Vals[7],25));
-Vals[1]+=ch(Vals[7],Vals[6],Vals[0]);
-Vals[1]+=K[11];
-Vals[5]+=Vals[1];
-Vals[1]+=(rotr(Vals[4],2)^rotr(Vals[4],13)^rotr(Vals[4],22));
-Vals[1]+=Ma(Vals[2],Vals[4],Vals[3]);
-
-Vals[0]+=(rotr(Vals[5],6)^rotr(Vals[5],11)^rotr(Vals[5],25));
-Vals[0]+=ch(Vals[5],Vals[7],Vals[6]);
-Vals[0]+=K[12];
-Vals[2]+=Vals[0];
-Vals[0]+=(rotr(Vals[1],2)^rotr(Vals[1],13)^rotr(Vals[1],22));
-Vals[0]+=Ma(Vals[3],Vals[1],Vals[4]);
-
-Vals[6]+=(rotr(Vals[2],6)^rotr(Vals[2],11)^rotr(Vals[2],25));
-Vals[6]+=ch(Vals[2],Vals[5],Vals[7]);
-Vals[6]+=K[13];
-Vals[3]+=Vals[6];
-Vals[6]+=(rotr(Vals[0],2)^rotr(Vals[0],13)^rotr(Vals[0],22));
-Vals[6]+=Ma(Vals[4],Vals[0],Vals[1]);
-
-Vals[7]+=(rotr(Vals[3],6)^rotr(Vals[3],11)^rotr(Vals[3],25));
-Vals[7]+=ch(Vals[3],Vals[2],Vals[5]);
-Vals[7]+=K[14];
-Vals[4]+=Vals[7];
-Vals[7]+=(rotr(Vals[6],2)^rotr(Vals[6],13)^rotr(Vals[6],22));
-Vals[7]+=Ma(Vals[1],Vals[6],Vals[0]);
-
-Vals[5]+=(rotr(Vals[4],6)^rotr(Vals[4],11)^rotr(Vals[4],25));
-Vals[5]+=ch(Vals[4],Vals[3],Vals[2]);
-Vals[5]+=0xC19BF3F4U;
-Vals[1]+=Vals[5];
-Vals[5]+=(rotr(Vals[7],2)^rotr(Vals[7],13)^rotr(Vals[7],22));
-Vals[5]+=Ma(Vals[0],Vals[7],Vals[6]);
-
-Vals[2]+=(rotr(Vals[1],6)^rotr(Vals[1],11)^rotr(Vals[1],25));
-Vals[2]+=ch(Vals[1],Vals[4],Vals[3]);
-Vals[2]+=W16addK16;
-Vals[0]+=Vals[2];
-Vals[2]+=(rotr(Vals[5],2)^rotr(Vals[5],13)^rotr(Vals[5],22));
-Vals[2]+=Ma(Vals[6],Vals[5],Vals[7]);
-
-Vals[3]+=(rotr(Vals[0],6)^rotr(Vals[0],11)^rotr(Vals[0],25));
-Vals[3]+=ch(Vals[0],Vals[1],Vals[4]);
-Vals[3]+=W17addK17;
-Vals[6]+=Vals[3];
-Vals[3]+=(rotr(Vals[2],2)^rotr(Vals[2],13)^rotr(Vals[2],22));
-Vals[3]+=Ma(Vals[7],Vals[2],Vals[5]);
-
-W[2]=(rotr(nonce,7)^rotr(nonce,18)^(nonce>>3U));
-W[2]+=fw2;
-Vals[4]+=W[2];
-Vals[4]+=(rotr(Vals[6],6)^rotr(Vals[6],11)^rotr(Vals[6],25));
-Vals[4]+=ch(Vals[6],Vals[0],Vals[1]);
-Vals[4]+=K[18];
-Vals[7]+=Vals[4];
-Vals[4]+=(rotr(Vals[3],2)^rotr(Vals[3],13)^rotr(Vals[3],22));
-Vals[4]+=Ma(Vals[5],Vals[3],Vals[2]);
-
-W[3]=nonce;
-W[3]+=fw3;
-Vals[1]+=W[3];
-Vals[1]+=(rotr(Vals[7],6)^rotr(Vals[7],11)^rotr(Vals[7],25));
-Vals[1]+=ch(Vals[7],Vals[6],Vals[0]);
-Vals[1]+=K[19];
-Vals[5]+=Vals[1];
-Vals[1]+=(rotr(Vals[4],2)^rotr(Vals[4],13)^rotr(Vals[4],22));
-Vals[1]+=Ma(Vals[2],Vals[4],Vals[3]);
-
-W[4]=(rotr(W[2],17)^rotr(W[2],19)^(W[2]>>10U));
-W[4]+=0x80000000U;
-Vals[0]+=W[4];
-Vals[0]+=(rotr(Vals[5],6)^rotr(Vals[5],11)^rotr(Vals[5],25));
-Vals[0]+=ch(Vals[5],Vals[7],Vals[6]);
-Vals[0]+=K[20];
-Vals[2]+=Vals[0];
-Vals[0]+=(rotr(Vals[1],2)^rotr(Vals[1],13)^rotr(Vals[1],22));
-Vals[0]+=Ma(Vals[3],Vals[1],Vals[4]);
-
-W[5]=(rotr(W[3],17)^rotr(W[3],19)^(W[3]>>10U));
-Vals[6]+=W[5];
-Vals[6]+=(rotr(Vals[2],6)^rotr(Vals[2],11)^rotr(Vals[2],25));
-Vals[6]+=ch(Vals[2],Vals[5],Vals[7]);
-Vals[6]+=K[21];
-Vals[3]+=Vals[6];
-Vals[6]+=(rotr(Vals[0],2)^rotr(Vals[0],13)^rotr(Vals[0],22));
-Vals[6]+=Ma(Vals[4],Vals[0],Vals[1]);
-
-W[6]=(rotr(W[4],17)^rotr(W[4],19)^(W[4]>>10U));
-W[6]+=0x00000280U;
-Vals[7]+=W[6];
-Vals[7]+=(rotr(Vals[3],6)^rotr(Vals[3],11)^rotr(Vals[3],25));
-Vals[7]+=ch(Vals[3],Vals[2],Vals[5]);
-Vals[7]+=K[22];
-Vals[4]+=Vals[7];
-Vals[7]+=(rotr(Vals[6],2)^rotr(Vals[6],13)^rotr(Vals[6],22));
-Vals[7]+=Ma(Vals[1],Vals[6],Vals[0]);
-
-W[7]=(rotr(W[5],17)^rotr(W[5],19)^(W[5]>>10U));
-W[7]+=fw0;
-Vals[5]+=W[7];
-Vals[5]+=(rotr(Vals[4],6)^rotr(Vals[4],11)^rotr(Vals[4],25));
-Vals[5]+=ch(Vals[4],Vals[3],Vals[2]);
-Vals[5]+=K[23];
-Vals[1]+=Vals[5];
-Vals[5]+=(rotr(Vals[7],2)^rotr(Vals[7],13)^rotr(Vals[7],22));
-Vals[5]+=Ma(Vals[0],Vals[7],Vals[6]);
-
-W[8]=(rotr(W[6],17)^rotr(W[6],19)^(W[6]>>10U));
-W[8]+=fw1;
-Vals[2]+=W[8];
-Vals[2]+=(rotr(Vals[1],6)^rotr(Vals[1],11)^rotr(Vals[1],25));
-Vals[2]+=ch(Vals[1],Vals[4],Vals[3]);
-Vals[2]+=K[24];
-Vals[0]+=Vals[2];
-Vals[2]+=(rotr(Vals[5],2)^rotr(Vals[5],13)^rotr(Vals[5],22));
-Vals[2]+=Ma(Vals[6],Vals[5],Vals[7]);
-
-W[9]=W[2];
-W[9]+=(rotr(W[7],17)^rotr(W[7],19)^(W[7]>>10U));
-Vals[3]+=W[9];
-Vals[3]+=(rotr(Vals[0],6)^rotr(Vals[0],11)^rotr(Vals[0],25));
-Vals[3]+=ch(Vals[0],Vals[1],Vals[4]);
-Vals[3]+=K[25];
-Vals[6]+=Vals[3];
-Vals[3]+=(rotr(Vals[2],2)^rotr(Vals[2],13)^rotr(Vals[2],22));
-Vals[3]+=Ma(Vals[7],Vals[2],Vals[5]);
-
-W[10]=W[3];
-W[10]+=(rotr(W[8],17)^rotr(W[8],19)^(W[8]>>10U));
-Vals[4]+=W[10];
-Vals[4]+=(rotr(Vals[6],6)^rotr(Vals[6],11)^rotr(Vals[6],25));
-Vals[4]+=ch(Vals[6],Vals[0],Vals[1]);
-Vals[4]+=K[26];
-Vals[7]+=Vals[4];
-Vals[4]+=(rotr(Vals[3],2)^rotr(Vals[3],13)^rotr(Vals[3],22));
-Vals[4]+=Ma(Vals[5],Vals[3],Vals[2]);
-
-W[11]=W[4];
-W[11]+=(rotr(W[9],17)^rotr(W[9],19)^(W[9]>>10U));
-Vals[1]+=W[11];
-Vals[1]+=(rotr(Vals[7],6)^rotr(Vals[7],11)^rotr(Vals[7],25));
-Vals[1]+=ch(Vals[7],Vals[6],Vals[0]);
-Vals[1]+=K[27];
-Vals[5]+=Vals[1];
-Vals[1]+=(rotr(Vals[4],2)^rotr(Vals[4],13)^rotr(Vals[4],22));
-Vals[1]+=Ma(Vals[2],Vals[4],Vals[3]);
-
-W[12]=W[5];
-W[12]+=(rotr(W[10],17)^rotr(W[10],19)^(W[10]>>10U));
-Vals[0]+=W[12];
-Vals[0]+=(rotr(Vals[5],6)^rotr(Vals[5],11)^rotr(Vals[5],25));
-Vals[0]+=ch(Vals[5],Vals[7],Vals[6]);
-Vals[0]+=K[28];
-Vals[2]+=Vals[0];
-Vals[0]+=(rotr(Vals[1],2)^rotr(Vals[1],13)^rotr(Vals[1],22));
-Vals[0]+=Ma(Vals[3],Vals[1],Vals[4]);
-
-W[13]=W[6];
-W[13]+=(rotr(W[11],17)^rotr(W[11],19)^(W[11]>>10U));
-Vals[6]+=W[13];
-Vals[6]+=(rotr(Vals[2],6)^rotr(Vals[2],11)^rotr(Vals[2],25));
-Vals[6]+=ch(Vals[2],Vals[5],Vals[7]);
-Vals[6]+=K[29];
-Vals[3]+=Vals[6];
-Vals[6]+=(rotr(Vals[0],2)^rotr(Vals[0],13)^rotr(Vals[0],22));
-Vals[6]+=Ma(Vals[4],Vals[0],Vals[1]);
-
-W[14]=0x00a00055U;
-W[14]+=W[7];
-W[14]+=(rotr(W[12],17)^rotr(W[12],19)^(W[12]>>10U));
-Vals[7]+=W[14];
-Vals[7]+=(rotr(Vals[3],6)^rotr(Vals[3],11)^rotr(Vals[3],25));
-Vals[7]+=ch(Vals[3],Vals[2],Vals[5]);
-Vals[7]+=K[30];
-Vals[4]+=Vals[7];
-Vals[7]+=(rotr(Vals[6],2)^rotr(Vals[6],13)^rotr(Vals[6],22));
-Vals[7]+=Ma(Vals[1],Vals[6],Vals[0]);
-
-W[15]=fw15;
-W[15]+=W[8];
-W[15]+=(rotr(W[13],17)^rotr(W[13],19)^(W[13]>>10U));
-Vals[5]+=W[15];
-Vals[5]+=(rotr(Vals[4],6)^rotr(Vals[4],11)^rotr(Vals[4],25));
-Vals[5]+=ch(Vals[4],Vals[3],Vals[2]);
-Vals[5]+=K[31];
-Vals[1]+=Vals[5];
-Vals[5]+=(rotr(Vals[7],2)^rotr(Vals[7],13)^rotr(Vals[7],22));
-Vals[5]+=Ma(Vals[0],Vals[7],Vals[6]);
-
-W[0]=fw01r;
-W[0]+=W[9];
-W[0]+=(rotr(W[14],17)^rotr(W[14],19)^(W[14]>>10U));
-Vals[2]+=W[0];
-Vals[2]+=(rotr(Vals[1],6)^rotr(Vals[1],11)^rotr(Vals[1],25));
-Vals[2]+=ch(Vals[1],Vals[4],Vals[3]);
-Vals[2]+=K[32];
-Vals[0]+=Vals[2];
-Vals[2]+=(rotr(Vals[5],2)^rotr(Vals[5],13)^rotr(Vals[5],22));
-Vals[2]+=Ma(Vals[6],Vals[5],Vals[7]);
-
-W[1]=fw1;
-W[1]+=(rotr(W[2],7)^rotr(W[2],18)^(W[2]>>3U));
-W[1]+=W[10];
-W[1]+=(rotr(W[15],17)^rotr(W[15],19)^(W[15]>>10U));
-Vals[3]+=W[1];
-Vals[3]+=(rotr(Vals[0],6)^rotr(Vals[0],11)^rotr(Vals[0],25));
-Vals[3]+=ch(Vals[0],Vals[1],Vals[4]);
-Vals[3]+=K[33];
-Vals[6]+=Vals[3];
-Vals[3]+=(rotr(Vals[2],2)^rotr(Vals[2],13)^rotr(Vals[2],22));
-Vals[3]+=Ma(Vals[7],Vals[2],Vals[5]);
-
-W[2]+=(rotr(W[3],7)^rotr(W[3],18)^(W[3]>>3U));
-W[2]+=W[11];
-W[2]+=(rotr(W[0],17)^rotr(W[0],19)^(W[0]>>10U));
-Vals[4]+=W[2];
-Vals[4]+=(rotr(Vals[6],6)^rotr(Vals[6],11)^rotr(Vals[6],25));
-Vals[4]+=ch(Vals[6],Vals[0],Vals[1]);
-Vals[4]+=K[34];
-Vals[7]+=Vals[4];
-Vals[4]+=(rotr(Vals[3],2)^rotr(Vals[3],13)^rotr(Vals[3],22));
-Vals[4]+=Ma(Vals[5],Vals[3],Vals[2]);
-
-W[3]+=(rotr(W[4],7)^rotr(W[4],18)^(W[4]>>3U));
-W[3]+=W[12];
-W[3]+=(rotr(W[1],17)^rotr(W[1],19)^(W[1]>>10U));
-Vals[1]+=W[3];
-Vals[1]+=(rotr(Vals[7],6)^rotr(Vals[7],11)^rotr(Vals[7],25));
-Vals[1]+=ch(Vals[7],Vals[6],Vals[0]);
-Vals[1]+=K[35];
-Vals[5]+=Vals[1];
-Vals[1]+=(rotr(Vals[4],2)^rotr(Vals[4],13)^rotr(Vals[4],22));
-Vals[1]+=Ma(Vals[2],Vals[4],Vals[3]);
-
-W[4]+=(rotr(W[5],7)^rotr(W[5],18)^(W[5]>>3U));
-W[4]+=W[13];
-W[4]+=(rotr(W[2],17)^rotr(W[2],19)^(W[2]>>10U));
-Vals[0]+=W[4];
-Vals[0]+=(rotr(Vals[5],6)^rotr(Vals[5],11)^rotr(Vals[5],25));
-Vals[0]+=ch(Vals[5],Vals[7],Vals[6]);
-Vals[0]+=K[36];
-Vals[2]+=Vals[0];
-Vals[0]+=(rotr(Vals[1],2)^rotr(Vals[1],13)^rotr(Vals[1],22));
-Vals[0]+=Ma(Vals[3],Vals[1],Vals[4]);
-
-W[5]+=(rotr(W[6],7)^rotr(W[6],18)^(W[6]>>3U));
-W[5]+=W[14];
-W[5]+=(rotr(W[3],17)^rotr(W[3],19)^(W[3]>>10U));
-Vals[6]+=W[5];
-Vals[6]+=(rotr(Vals[2],6)^rotr(Vals[2],11)^rotr(Vals[2],25));
-Vals[6]+=ch(Vals[2],Vals[5],Vals[7]);
-Vals[6]+=K[37];
-Vals[3]+=Vals[6];
-Vals[6]+=(rotr(Vals[0],2)^rotr(Vals[0],13)^rotr(Vals[0],22));
-Vals[6]+=Ma(Vals[4],Vals[0],Vals[1]);
-
-W[6]+=(rotr(W[7],7)^rotr(W[7],18)^(W[7]>>3U));
-W[6]+=W[15];
-W[6]+=(rotr(W[4],17)^rotr(W[4],19)^(W[4]>>10U));
-Vals[7]+=W[6];
-Vals[7]+=(rotr(Vals[3],6)^rotr(Vals[3],11)^rotr(Vals[3],25));
-Vals[7]+=ch(Vals[3],Vals[2],Vals[5]);
-Vals[7]+=K[38];
-Vals[4]+=Vals[7];
-Vals[7]+=(rotr(Vals[6],2)^rotr(Vals[6],13)^rotr(Vals[6],22));
-Vals[7]+=Ma(Vals[1],Vals[6],Vals[0]);
-
-W[7]+=(rotr(W[8],7)^rotr(W[8],18)^(W[8]>>3U));
-W[7]+=W[0];
-W[7]+=(rotr(W[5],17)^rotr(W[5],19)^(W[5]>>10U));
-Vals[5]+=W[7];
-Vals[5]+=(rotr(Vals[4],6)^rotr(Vals[4],11)^rotr(Vals[4],25));
-Vals[5]+=ch(Vals[4],Vals[3],Vals[2]);
-Vals[5]+=K[39];
-Vals[1]+=Vals[5];
-Vals[5]+=(rotr(Vals[7],2)^rotr(Vals[7],13)^rotr(Vals[7],22));
-Vals[5]+=Ma(Vals[0],Vals[7],Vals[6]);
-
-W[8]+=(rotr(W[9],7)^rotr(W[9],18)^(W[9]>>3U));
-W[8]+=W[1];
-W[8]+=(rotr(W[6],17)^rotr(W[6],19)^(W[6]>>10U));
-Vals[2]+=W[8];
-Vals[2]+=(rotr(Vals[1],6)^rotr(Vals[1],11)^rotr(Vals[1],25));
-Vals[2]+=ch(Vals[1],Vals[4],Vals[3]);
-Vals[2]+=K[40];
-Vals[0]+=Vals[2];
-Vals[2]+=(rotr(Vals[5],2)^rotr(Vals[5],13)^rotr(Vals[5],22));
-Vals[2]+=Ma(Vals[6],Vals[5],Vals[7]);
-
-W[9]+=(rotr(W[10],7)^rotr(W[10],18)^(W[10]>>3U));
-W[9]+=W[2];
-W[9]+=(rotr(W[7],17)^rotr(W[7],19)^(W[7]>>10U));
-Vals[3]+=W[9];
-Vals[3]+=(rotr(Vals[0],6)^rotr(Vals[0],11)^rotr(Vals[0],25));
-Vals[3]+=ch(Vals[0],Vals[1],Vals[4]);
-Vals[3]+=K[41];
-Vals[6]+=Vals[3];
-Vals[3]+=(rotr(Vals[2],2)^rotr(Vals[2],13)^rotr(Vals[2],22));
-Vals[3]+=Ma(Vals[7],Vals[2],Vals[5]);
-
-W[10]+=(rotr(W[11],7)^rotr(W[11],18)^(W[11]>>3U));
-W[10]+=W[3];
-W[10]+=(rotr(W[8],17)^rotr(W[8],19)^(W[8]>>10U));
-Vals[4]+=W[10];
-Vals[4]+=(rotr(Vals[6],6)^rotr(Vals[6],11)^rotr(Vals[6],25));
-Vals[4]+=ch(Vals[6],Vals[0],Vals[1]);
-Vals[4]+=K[42];
-Vals[7]+=Vals[4];
-Vals[4]+=(rotr(Vals[3],2)^rotr(Vals[3],13)^rotr(Vals[3],22));
-Vals[4]+=Ma(Vals[5],Vals[3],Vals[2]);
-
-W[11]+=(rotr(W[12],7)^rotr(W[12],18)^(W[12]>>3U));
-W[11]+=W[4];
-W[11]+=(rotr(W[9],17)^rotr(W[9],19)^(W[9]>>10U));
-Vals[1]+=W[11];
-Vals[1]+=(rotr(Vals[7],6)^rotr(Vals[7],11)^rotr(Vals[7],25));
-Vals[1]+=ch(Vals[7],Vals[6],Vals[0]);
-Vals[1]+=K[43];
-Vals[5]+=Vals[1];
-Vals[1]+=(rotr(Vals[4],2)^rotr(Vals[4],13)^rotr(Vals[4],22));
-Vals[1]+=Ma(Vals[2],Vals[4],Vals[3]);
-
-W[12]+=(rotr(W[13],7)^rotr(W[13],18)^(W[13]>>3U));
-W[12]+=W[5];
-W[12]+=(rotr(W[10],17)^rotr(W[10],19)^(W[10]>>10U));
-Vals[0]+=W[12];
-Vals[0]+=(rotr(Vals[5],6)^rotr(Vals[5],11)^rotr(Vals[5],25));
-Vals[0]+=ch(Vals[5],Vals[7],Vals[6]);
-Vals[0]+=K[44];
-Vals[2]+=Vals[0];
-Vals[0]+=(rotr(Vals[1],2)^rotr(Vals[1],13)^rotr(Vals[1],22));
-Vals[0]+=Ma(Vals[3],Vals[1],Vals[4]);
-
-W[13]+=(rotr(W[14],7)^rotr(W[14],18)^(W[14]>>3U));
-W[13]+=W[6];
-W[13]+=(rotr(W[11],17)^rotr(W[11],19)^(W[11]>>10U));
-Vals[6]+=W[13];
-Vals[6]+=(rotr(Vals[2],6)^rotr(Vals[2],11)^rotr(Vals[2],25));
-Vals[6]+=ch(Vals[2],Vals[5],Vals[7]);
-Vals[6]+=K[45];
-Vals[3]+=Vals[6];
-Vals[6]+=(rotr(Vals[0],2)^rotr(Vals[0],13)^rotr(Vals[0],22));
-Vals[6]+=Ma(Vals[4],Vals[0],Vals[1]);
-
-W[14]+=(rotr(W[15],7)^rotr(W[15],18)^(W[15]>>3U));
-W[14]+=W[7];
-W[14]+=(rotr(W[12],17)^rotr(W[12],19)^(W[12]>>10U));
-Vals[7]+=W[14];
-Vals[7]+=(rotr(Vals[3],6)^rotr(Vals[3],11)^rotr(Vals[3],25));
-Vals[7]+=ch(Vals[3],Vals[2],Vals[5]);
-Vals[7]+=K[46];
-Vals[4]+=Vals[7];
-Vals[7]+=(rotr(Vals[6],2)^rotr(Vals[6],13)^rotr(Vals[6],22));
-Vals[7]+=Ma(Vals[1],Vals[6],Vals[0]);
-
-W[15]+=(rotr(W[0],7)^rotr(W[0],18)^(W[0]>>3U));
-W[15]+=W[8];
-W[15]+=(rotr(W[13],17)^rotr(W[13],19)^(W[13]>>10U));
-Vals[5]+=W[15];
-Vals[5]+=(rotr(Vals[4],6)^rotr(Vals[4],11)^rotr(Vals[4],25));
-Vals[5]+=ch(Vals[4],Vals[3],Vals[2]);
-Vals[5]+=K[47];
-Vals[1]+=Vals[5];
-Vals[5]+=(rotr(Vals[7],2)^rotr(Vals[7],13)^rotr(Vals[7],22));
-Vals[5]+=Ma(Vals[0],Vals[7],Vals[6]);
-
-W[0]+=(rotr(W[1],7)^rotr(W[1],18)^(W[1]>>3U));
-W[0]+=W[9];
-W[0]+=(rotr(W[14],17)^rotr(W[14],19)^(W[14]>>10U));
-Vals[2]+=W[0];
-Vals[2]+=(rotr(Vals[1],6)^rotr(Vals[1],11)^rotr(Vals[1],25));
-Vals[2]+=ch(Vals[1],Vals[4],Vals[3]);
-Vals[2]+=K[48];
-Vals[0]+=Vals[2];
-Vals[2]+=(rotr(Vals[5],2)^rotr(Vals[5],13)^rotr(Vals[5],22));
-Vals[2]+=Ma(Vals[6],Vals[5],Vals[7]);
-
-W[1]+=(rotr(W[2],7)^rotr(W[2],18)^(W[2]>>3U));
-W[1]+=W[10];
-W[1]+=(rotr(W[15],17)^rotr(W[15],19)^(W[15]>>10U));
-Vals[3]+=W[1];
-Vals[3]+=(rotr(Vals[0],6)^rotr(Vals[0],11)^rotr(Vals[0],25));
-Vals[3]+=ch(Vals[0],Vals[1],Vals[4]);
-Vals[3]+=K[49];
-Vals[6]+=Vals[3];
-Vals[3]+=(rotr(Vals[2],2)^rotr(Vals[2],13)^rotr(Vals[2],22));
-Vals[3]+=Ma(Vals[7],Vals[2],Vals[5]);
-
-W[2]+=(rotr(W[3],7)^rotr(W[3],18)^(W[3]>>3U));
-W[2]+=W[11];
-W[2]+=(rotr(W[0],17)^rotr(W[0],19)^(W[0]>>10U));
-Vals[4]+=W[2];
-Vals[4]+=(rotr(Vals[6],6)^rotr(Vals[6],11)^rotr(Vals[6],25));
-Vals[4]+=ch(Vals[6],Vals[0],Vals[1]);
-Vals[4]+=K[50];
-Vals[7]+=Vals[4];
-Vals[4]+=(rotr(Vals[3],2)^rotr(Vals[3],13)^rotr(Vals[3],22));
-Vals[4]+=Ma(Vals[5],Vals[3],Vals[2]);
-
-W[3]+=(rotr(W[4],7)^rotr(W[4],18)^(W[4]>>3U));
-W[3]+=W[12];
-W[3]+=(rotr(W[1],17)^rotr(W[1],19)^(W[1]>>10U));
-Vals[1]+=W[3];
-Vals[1]+=(rotr(Vals[7],6)^rotr(Vals[7],11)^rotr(Vals[7],25));
-Vals[1]+=ch(Vals[7],Vals[6],Vals[0]);
-Vals[1]+=K[51];
-Vals[5]+=Vals[1];
-Vals[1]+=(rotr(Vals[4],2)^rotr(Vals[4],13)^rotr(Vals[4],22));
-Vals[1]+=Ma(Vals[2],Vals[4],Vals[3]);
-
-W[4]+=(rotr(W[5],7)^rotr(W[5],18)^(W[5]>>3U));
-W[4]+=W[13];
-W[4]+=(rotr(W[2],17)^rotr(W[2],19)^(W[2]>>10U));
-Vals[0]+=W[4];
-Vals[0]+=(rotr(Vals[5],6)^rotr(Vals[5],11)^rotr(Vals[5],25));
-Vals[0]+=ch(Vals[5],Vals[7],Vals[6]);
-Vals[0]+=K[52];
-Vals[2]+=Vals[0];
-Vals[0]+=(rotr(Vals[1],2)^rotr(Vals[1],13)^rotr(Vals[1],22));
-Vals[0]+=Ma(Vals[3],Vals[1],Vals[4]);
-
-W[5]+=(rotr(W[6],7)^rotr(W[6],18)^(W[6]>>3U));
-W[5]+=W[14];
-W[5]+=(rotr(W[3],17)^rotr(W[3],19)^(W[3]>>10U));
-Vals[6]+=W[5];
-Vals[6]+=(rotr(Vals[2],6)^rotr(Vals[2],11)^rotr(Vals[2],25));
-Vals[6]+=ch(Vals[2],Vals[5],Vals[7]);
-Vals[6]+=K[53];
-Vals[3]+=Vals[6];
-Vals[6]+=(rotr(Vals[0],2)^rotr(Vals[0],13)^rotr(Vals[0],22));
-Vals[6]+=Ma(Vals[4],Vals[0],Vals[1]);
-
-W[6]+=(rotr(W[7],7)^rotr(W[7],18)^(W[7]>>3U));
-W[6]+=W[15];
-W[6]+=(rotr(W[4],17)^rotr(W[4],19)^(W[4]>>10U));
-Vals[7]+=W[6];
-Vals[7]+=(rotr(Vals[3],6)^rotr(Vals[3],11)^rotr(Vals[3],25));
-Vals[7]+=ch(Vals[3],Vals[2],Vals[5]);
-Vals[7]+=K[54];
-Vals[4]+=Vals[7];
-Vals[7]+=(rotr(Vals[6],2)^rotr(Vals[6],13)^rotr(Vals[6],22));
-Vals[7]+=Ma(Vals[1],Vals[6],Vals[0]);
-
-W[7]+=(rotr(W[8],7)^rotr(W[8],18)^(W[8]>>3U));
-W[7]+=W[0];
-W[7]+=(rotr(W[5],17)^rotr(W[5],19)^(W[5]>>10U));
-Vals[5]+=W[7];
-Vals[5]+=(rotr(Vals[4],6)^rotr(Vals[4],11)^rotr(Vals[4],25));
-Vals[5]+=ch(Vals[4],Vals[3],Vals[2]);
-Vals[5]+=K[55];
-Vals[1]+=Vals[5];
-Vals[5]+=(rotr(Vals[7],2)^rotr(Vals[7],13)^rotr(Vals[7],22));
-Vals[5]+=Ma(Vals[0],Vals[7],Vals[6]);
-
-W[8]+=(rotr(W[9],7)^rotr(W[9],18)^(W[9]>>3U));
-W[8]+=W[1];
-W[8]+=(rotr(W[6],17)^rotr(W[6],19)^(W[6]>>10U));
-Vals[2]+=W[8];
-Vals[2]+=(rotr(Vals[1],6)^rotr(Vals[1],11)^rotr(Vals[1],25));
-Vals[2]+=ch(Vals[1],Vals[4],Vals[3]);
-Vals[2]+=K[56];
-Vals[0]+=Vals[2];
-Vals[2]+=(rotr(Vals[5],2)^rotr(Vals[5],13)^rotr(Vals[5],22));
-Vals[2]+=Ma(Vals[6],Vals[5],Vals[7]);
-
-W[9]+=(rotr(W[10],7)^rotr(W[10],18)^(W[10]>>3U));
-W[9]+=W[2];
-W[9]+=(rotr(W[7],17)^rotr(W[7],19)^(W[7]>>10U));
-Vals[3]+=W[9];
-Vals[3]+=(rotr(Vals[0],6)^rotr(Vals[0],11)^rotr(Vals[0],25));
-Vals[3]+=ch(Vals[0],Vals[1],Vals[4]);
-Vals[3]+=K[57];
-Vals[6]+=Vals[3];
-Vals[3]+=(rotr(Vals[2],2)^rotr(Vals[2],13)^rotr(Vals[2],22));
-Vals[3]+=Ma(Vals[7],Vals[2],Vals[5]);
-
-W[10]+=(rotr(W[11],7)^rotr(W[11],18)^(W[11]>>3U));
-W[10]+=W[3];
-W[10]+=(rotr(W[8],17)^rotr(W[8],19)^(W[8]>>10U));
-Vals[4]+=W[10];
-Vals[4]+=(rotr(Vals[6],6)^rotr(Vals[6],11)^rotr(Vals[6],25));
-Vals[4]+=ch(Vals[6],Vals[0],Vals[1]);
-Vals[4]+=K[58];
-Vals[7]+=Vals[4];
-Vals[4]+=(rotr(Vals[3],2)^rotr(Vals[3],13)^rotr(Vals[3],22));
-Vals[4]+=Ma(Vals[5],Vals[3],Vals[2]);
-
-W[11]+=(rotr(W[12],7)^rotr(W[12],18)^(W[12]>>3U));
-W[11]+=W[4];
-W[11]+=(rotr(W[9],17)^rotr(W[9],19)^(W[9]>>10U));
-Vals[1]+=W[11];
-Vals[1]+=(rotr(Vals[7],6)^rotr(Vals[7],11)^rotr(Vals[7],25));
-Vals[1]+=ch(Vals[7],Vals[6],Vals[0]);
-Vals[1]+=K[59];
-Vals[5]+=Vals[1];
-Vals[1]+=(rotr(Vals[4],2)^rotr(Vals[4],13)^rotr(Vals[4],22));
-Vals[1]+=Ma(Vals[2],Vals[4],Vals[3]);
-
-W[12]+=(rotr(W[13],7)^rotr(W[13],18)^(W[13]>>3U));
-W[12]+=W[5];
-W[12]+=(rotr(W[10],17)^rotr(W[10],19)^(W[10]>>10U));
-Vals[0]+=W[12];
-Vals[0]+=(rotr(Vals[5],6)^rotr(Vals[5],11)^rotr(Vals[5],25));
-Vals[0]+=ch(Vals[5],Vals[7],Vals[6]);
-Vals[0]+=K[60];
-Vals[2]+=Vals[0];
-Vals[0]+=(rotr(Vals[1],2)^rotr(Vals[1],13)^rotr(Vals[1],22));
-Vals[0]+=Ma(Vals[3],Vals[1],Vals[4]);
-
-W[13]+=(rotr(W[14],7)^rotr(W[14],18)^(W[14]>>3U));
-W[13]+=W[6];
-W[13]+=(rotr(W[11],17)^rotr(W[11],19)^(W[11]>>10U));
-Vals[6]+=W[13];
-Vals[6]+=(rotr(Vals[2],6)^rotr(Vals[2],11)^rotr(Vals[2],25));
-Vals[6]+=ch(Vals[2],Vals[5],Vals[7]);
-Vals[6]+=K[61];
-Vals[3]+=Vals[6];
-Vals[6]+=(rotr(Vals[0],2)^rotr(Vals[0],13)^rotr(Vals[0],22));
-Vals[6]+=Ma(Vals[4],Vals[0],Vals[1]);
-
-Vals[7]+=W[14];
-Vals[7]+=(rotr(W[15],7)^rotr(W[15],18)^(W[15]>>3U));
-Vals[7]+=W[7];
-Vals[7]+=(rotr(W[12],17)^rotr(W[12],19)^(W[12]>>10U));
-Vals[7]+=(rotr(Vals[3],6)^rotr(Vals[3],11)^rotr(Vals[3],25));
-Vals[7]+=ch(Vals[3],Vals[2],Vals[5]);
-Vals[7]+=K[62];
-Vals[4]+=Vals[7];
-Vals[7]+=(rotr(Vals[6],2)^rotr(Vals[6],13)^rotr(Vals[6],22));
-Vals[7]+=Ma(Vals[1],Vals[6],Vals[0]);
-
-Vals[5]+=W[15];
-Vals[5]+=(rotr(W[0],7)^rotr(W[0],18)^(W[0]>>3U));
-Vals[5]+=W[8];
-Vals[5]+=(rotr(W[13],17)^rotr(W[13],19)^(W[13]>>10U));
-Vals[5]+=(rotr(Vals[4],6)^rotr(Vals[4],11)^rotr(Vals[4],25));
-Vals[5]+=ch(Vals[4],Vals[3],Vals[2]);
-Vals[5]+=K[63];
-Vals[1]+=Vals[5];
-Vals[5]+=(rotr(Vals[7],2)^rotr(Vals[7],13)^rotr(Vals[7],22));
-Vals[5]+=Ma(Vals[0],Vals[7],Vals[6]);
-
-Vals[5]+=state0;
-
-W[7]=state7;
-W[7]+=Vals[2];
-
-Vals[2]=0xF377ED68U;
-Vals[2]+=Vals[5];
-
-W[3]=state3;
-W[3]+=Vals[0];
-
-Vals[0]=0xa54ff53aU;
-Vals[0]+=Vals[2];
-Vals[2]+=0x08909ae5U;
-
-W[6]=state6;
-W[6]+=Vals[3];
-
-Vals[3]=0x90BB1E3CU;
-Vals[3]+=(rotr(Vals[0],6)^rotr(Vals[0],11)^rotr(Vals[0],25));
-Vals[3]+=(0x9b05688cU^(Vals[0]&0xca0b3af3U));
-
-Vals[7]+=state1;
-Vals[3]+=Vals[7];
-
-W[2]=state2;
-W[2]+=Vals[6];
-
-Vals[6]=0x3c6ef372U;
-Vals[6]+=Vals[3];
-Vals[3]+=(rotr(Vals[2],2)^rotr(Vals[2],13)^rotr(Vals[2],22));
-Vals[3]+=Ma2(0xbb67ae85U,Vals[2],0x6a09e667U);
-
-W[5]=state5;
-W[5]+=Vals[4];
-
-Vals[4]=0x50C6645BU;
-Vals[4]+=(rotr(Vals[6],6)^rotr(Vals[6],11)^rotr(Vals[6],25));
-Vals[4]+=ch(Vals[6],Vals[0],0x510e527fU);
-Vals[4]+=W[2];
-
-W[1]=Vals[7];
-Vals[7]=0xbb67ae85U;
-Vals[7]+=Vals[4];
-Vals[4]+=(rotr(Vals[3],2)^rotr(Vals[3],13)^rotr(Vals[3],22));
-Vals[4]+=Ma2(0x6a09e667U,Vals[3],Vals[2]);
-
-W[4]=state4;
-W[4]+=Vals[1];
-
-Vals[1]=0x3AC42E24U;
-Vals[1]+=(rotr(Vals[7],6)^rotr(Vals[7],11)^rotr(Vals[7],25));
-Vals[1]+=ch(Vals[7],Vals[6],Vals[0]);
-Vals[1]+=W[3];
-
-W[0]=Vals[5];
-
-Vals[5]=Vals[1];
-Vals[5]+=0x6a09e667U;
-
-Vals[1]+=(rotr(Vals[4],2)^rotr(Vals[4],13)^rotr(Vals[4],22));
-Vals[1]+=Ma(Vals[2],Vals[4],Vals[3]);
-
-Vals[0]+=(rotr(Vals[5],6)^rotr(Vals[5],11)^rotr(Vals[5],25));
-Vals[0]+=ch(Vals[5],Vals[7],Vals[6]);
-Vals[0]+=K[4];
-Vals[0]+=W[4];
-Vals[2]+=Vals[0];
-Vals[0]+=(rotr(Vals[1],2)^rotr(Vals[1],13)^rotr(Vals[1],22));
-Vals[0]+=Ma(Vals[3],Vals[1],Vals[4]);
-
-Vals[6]+=(rotr(Vals[2],6)^rotr(Vals[2],11)^rotr(Vals[2],25));
-Vals[6]+=ch(Vals[2],Vals[5],Vals[7]);
-Vals[6]+=K[5];
-Vals[6]+=W[5];
-Vals[3]+=Vals[6];
-Vals[6]+=(rotr(Vals[0],2)^rotr(Vals[0],13)^rotr(Vals[0],22));
-Vals[6]+=Ma(Vals[4],Vals[0],Vals[1]);
-
-Vals[7]+=(rotr(Vals[3],6)^rotr(Vals[3],11)^rotr(Vals[3],25));
-Vals[7]+=ch(Vals[3],Vals[2],Vals[5]);
-Vals[7]+=K[6];
-Vals[7]+=W[6];
-Vals[4]+=Vals[7];
-Vals[7]+=(rotr(Vals[6],2)^rotr(Vals[6],13)^rotr(Vals[6],22));
-Vals[7]+=Ma(Vals[1],Vals[6],Vals[0]);
-
-Vals[5]+=(rotr(Vals[4],6)^rotr(Vals[4],11)^rotr(Vals[4],25));
-Vals[5]+=ch(Vals[4],Vals[3],Vals[2]);
-Vals[5]+=K[7];
-Vals[5]+=W[7];
-Vals[1]+=Vals[5];
-Vals[5]+=(rotr(Vals[7],2)^rotr(Vals[7],13)^rotr(Vals[7],22));
-Vals[5]+=Ma(Vals[0],Vals[7],Vals[6]);
-
-Vals[2]+=(rotr(Vals[1],6)^rotr(Vals[1],11)^rotr(Vals[1],25));
-Vals[2]+=ch(Vals[1],Vals[4],Vals[3]);
-Vals[2]+=0x5807AA98U;
-Vals[0]+=Vals[2];
-Vals[2]+=(rotr(Vals[5],2)^rotr(Vals[5],13)^rotr(Vals[5],22));
-Vals[2]+=Ma(Vals[6],Vals[5],Vals[7]);
-
-Vals[3]+=(rotr(Vals[0],6)^rotr(Vals[0],11)^rotr(Vals[0],25));
-Vals[3]+=ch(Vals[0],Vals[1],Vals[4]);
-Vals[3]+=K[9];
-Vals[6]+=Vals[3];
-Vals[3]+=(rotr(Vals[2],2)^rotr(Vals[2],13)^rotr(Vals[2],22));
-Vals[3]+=Ma(Vals[7],Vals[2],Vals[5]);
-
-Vals[4]+=(rotr(Vals[6],6)^rotr(Vals[6],11)^rotr(Vals[6],25));
-Vals[4]+=ch(Vals[6],Vals[0],Vals[1]);
-Vals[4]+=K[10];
-Vals[7]+=Vals[4];
-Vals[4]+=(rotr(Vals[3],2)^rotr(Vals[3],13)^rotr(Vals[3],22));
-Vals[4]+=Ma(Vals[5],Vals[3],Vals[2]);
-
-Vals[1]+=(rotr(Vals[7],6)^rotr(Vals[7],11)^rotr(Vals[7],25));
-Vals[1]+=ch(Vals[7],Vals[6],Vals[0]);
-Vals[1]+=K[11];
-Vals[5]+=Vals[1];
-Vals[1]+=(rotr(Vals[4],2)^rotr(Vals[4],13)^rotr(Vals[4],22));
-Vals[1]+=Ma(Vals[2],Vals[4],Vals[3]);
-
-Vals[0]+=(rotr(Vals[5],6)^rotr(Vals[5],11)^rotr(Vals[5],25));
-Vals[0]+=ch(Vals[5],Vals[7],Vals[6]);
-Vals[0]+=K[12];
-Vals[2]+=Vals[0];
-Vals[0]+=(rotr(Vals[1],2)^rotr(Vals[1],13)^rotr(Vals[1],22));
-Vals[0]+=Ma(Vals[3],Vals[1],Vals[4]);
-
-Vals[6]+=(rotr(Vals[2],6)^rotr(Vals[2],11)^rotr(Vals[2],25));
-Vals[6]+=ch(Vals[2],Vals[5],Vals[7]);
-Vals[6]+=K[13];
-Vals[3]+=Vals[6];
-Vals[6]+=(rotr(Vals[0],2)^rotr(Vals[0],13)^rotr(Vals[0],22));
-Vals[6]+=Ma(Vals[4],Vals[0],Vals[1]);
-
-Vals[7]+=(rotr(Vals[3],6)^rotr(Vals[3],11)^rotr(Vals[3],25));
-Vals[7]+=ch(Vals[3],Vals[2],Vals[5]);
-Vals[7]+=K[14];
-Vals[4]+=Vals[7];
-Vals[7]+=(rotr(Vals[6],2)^rotr(Vals[6],13)^rotr(Vals[6],22));
-Vals[7]+=Ma(Vals[1],Vals[6],Vals[0]);
-
-Vals[5]+=(rotr(Vals[4],6)^rotr(Vals[4],11)^rotr(Vals[4],25));
-Vals[5]+=ch(Vals[4],Vals[3],Vals[2]);
-Vals[5]+=0xC19BF274U;
-Vals[1]+=Vals[5];
-Vals[5]+=(rotr(Vals[7],2)^rotr(Vals[7],13)^rotr(Vals[7],22));
-Vals[5]+=Ma(Vals[0],Vals[7],Vals[6]);
-
-W[0]+=(rotr(W[1],7)^rotr(W[1],18)^(W[1]>>3U));
-Vals[2]+=W[0];
-Vals[2]+=(rotr(Vals[1],6)^rotr(Vals[1],11)^rotr(Vals[1],25));
-Vals[2]+=ch(Vals[1],Vals[4],Vals[3]);
-Vals[2]+=K[16];
-Vals[0]+=Vals[2];
-Vals[2]+=(rotr(Vals[5],2)^rotr(Vals[5],13)^rotr(Vals[5],22));
-Vals[2]+=Ma(Vals[6],Vals[5],Vals[7]);
-
-W[1]+=(rotr(W[2],7)^rotr(W[2],18)^(W[2]>>3U));
-W[1]+=0x00a00000U;
-Vals[3]+=W[1];
-Vals[3]+=(rotr(Vals[0],6)^rotr(Vals[0],11)^rotr(Vals[0],25));
-Vals[3]+=ch(Vals[0],Vals[1],Vals[4]);
-Vals[3]+=K[17];
-Vals[6]+=Vals[3];
-Vals[3]+=(rotr(Vals[2],2)^rotr(Vals[2],13)^rotr(Vals[2],22));
-Vals[3]+=Ma(Vals[7],Vals[2],Vals[5]);
-
-W[2]+=(rotr(W[3],7)^rotr(W[3],18)^(W[3]>>3U));
-W[2]+=(rotr(W[0],17)^rotr(W[0],19)^(W[0]>>10U));
-Vals[4]+=W[2];
-Vals[4]+=(rotr(Vals[6],6)^rotr(Vals[6],11)^rotr(Vals[6],25));
-Vals[4]+=ch(Vals[6],Vals[0],Vals[1]);
-Vals[4]+=K[18];
-Vals[7]+=Vals[4];
-Vals[4]+=(rotr(Vals[3],2)^rotr(Vals[3],13)^rotr(Vals[3],22));
-Vals[4]+=Ma(Vals[5],Vals[3],Vals[2]);
-
-W[3]+=(rotr(W[4],7)^rotr(W[4],18)^(W[4]>>3U));
-W[3]+=(rotr(W[1],17)^rotr(W[1],19)^(W[1]>>10U));
-Vals[1]+=W[3];
-Vals[1]+=(rotr(Vals[7],6)^rotr(Vals[7],11)^rotr(Vals[7],25));
-Vals[1]+=ch(Vals[7],Vals[6],Vals[0]);
-Vals[1]+=K[19];
-Vals[5]+=Vals[1];
-Vals[1]+=(rotr(Vals[4],2)^rotr(Vals[4],13)^rotr(Vals[4],22));
-Vals[1]+=Ma(Vals[2],Vals[4],Vals[3]);
-
-W[4]+=(rotr(W[5],7)^rotr(W[5],18)^(W[5]>>3U));
-W[4]+=(rotr(W[2],17)^rotr(W[2],19)^(W[2]>>10U));
-Vals[0]+=W[4];
-Vals[0]+=(rotr(Vals[5],6)^rotr(Vals[5],11)^rotr(Vals[5],25));
-Vals[0]+=ch(Vals[5],Vals[7],Vals[6]);
-Vals[0]+=K[20];
-Vals[2]+=Vals[0];
-Vals[0]+=(rotr(Vals[1],2)^rotr(Vals[1],13)^rotr(Vals[1],22));
-Vals[0]+=Ma(Vals[3],Vals[1],Vals[4]);
-
-W[5]+=(rotr(W[6],7)^rotr(W[6],18)^(W[6]>>3U));
-W[5]+=(rotr(W[3],17)^rotr(W[3],19)^(W[3]>>10U));
-Vals[6]+=W[5];
-Vals[6]+=(rotr(Vals[2],6)^rotr(Vals[2],11)^rotr(Vals[2],25));
-Vals[6]+=ch(Vals[2],Vals[5],Vals[7]);
-Vals[6]+=K[21];
-Vals[3]+=Vals[6];
-Vals[6]+=(rotr(Vals[0],2)^rotr(Vals[0],13)^rotr(Vals[0],22));
-Vals[6]+=Ma(Vals[4],Vals[0],Vals[1]);
-
-W[6]+=(rotr(W[7],7)^rotr(W[7],18)^(W[7]>>3U));
-W[6]+=0x00000100U;
-W[6]+=(rotr(W[4],17)^rotr(W[4],19)^(W[4]>>10U));
-Vals[7]+=W[6];
-Vals[7]+=(rotr(Vals[3],6)^rotr(Vals[3],11)^rotr(Vals[3],25));
-Vals[7]+=ch(Vals[3],Vals[2],Vals[5]);
-Vals[7]+=K[22];
-Vals[4]+=Vals[7];
-Vals[7]+=(rotr(Vals[6],2)^rotr(Vals[6],13)^rotr(Vals[6],22));
-Vals[7]+=Ma(Vals[1],Vals[6],Vals[0]);
-
-W[7]+=0x11002000U;
-W[7]+=W[0];
-W[7]+=(rotr(W[5],17)^rotr(W[5],19)^(W[5]>>10U));
-Vals[5]+=W[7];
-Vals[5]+=(rotr(Vals[4],6)^rotr(Vals[4],11)^rotr(Vals[4],25));
-Vals[5]+=ch(Vals[4],Vals[3],Vals[2]);
-Vals[5]+=K[23];
-Vals[1]+=Vals[5];
-Vals[5]+=(rotr(Vals[7],2)^rotr(Vals[7],13)^rotr(Vals[7],22));
-Vals[5]+=Ma(Vals[0],Vals[7],Vals[6]);
-
-W[8]=0x80000000U;
-W[8]+=W[1];
-W[8]+=(rotr(W[6],17)^rotr(W[6],19)^(W[6]>>10U));
-Vals[2]+=W[8];
-Vals[2]+=(rotr(Vals[1],6)^rotr(Vals[1],11)^rotr(Vals[1],25));
-Vals[2]+=ch(Vals[1],Vals[4],Vals[3]);
-Vals[2]+=K[24];
-Vals[0]+=Vals[2];
-Vals[2]+=(rotr(Vals[5],2)^rotr(Vals[5],13)^rotr(Vals[5],22));
-Vals[2]+=Ma(Vals[6],Vals[5],Vals[7]);
-
-W[9]=W[2];
-W[9]+=(rotr(W[7],17)^rotr(W[7],19)^(W[7]>>10U));
-Vals[3]+=W[9];
-Vals[3]+=(rotr(Vals[0],6)^rotr(Vals[0],11)^rotr(Vals[0],25));
-Vals[3]+=ch(Vals[0],Vals[1],Vals[4]);
-Vals[3]+=K[25];
-Vals[6]+=Vals[3];
-Vals[3]+=(rotr(Vals[2],2)^rotr(Vals[2],13)^rotr(Vals[2],22));
-Vals[3]+=Ma(Vals[7],Vals[2],Vals[5]);
-
-W[10]=W[3];
-W[10]+=(rotr(W[8],17)^rotr(W[8],19)^(W[8]>>10U));
-Vals[4]+=W[10];
-Vals[4]+=(rotr(Vals[6],6)^rotr(Vals[6],11)^rotr(Vals[6],25));
-Vals[4]+=ch(Vals[6],Vals[0],Vals[1]);
-Vals[4]+=K[26];
-Vals[7]+=Vals[4];
-Vals[4]+=(rotr(Vals[3],2)^rotr(Vals[3],13)^rotr(Vals[3],22));
-Vals[4]+=Ma(Vals[5],Vals[3],Vals[2]);
-
-W[11]=W[4];
-W[11]+=(rotr(W[9],17)^rotr(W[9],19)^(W[9]>>10U));
-Vals[1]+=W[11];
-Vals[1]+=(rotr(Vals[7],6)^rotr(Vals[7],11)^rotr(Vals[7],25));
-Vals[1]+=ch(Vals[7],Vals[6],Vals[0]);
-Vals[1]+=K[27];
-Vals[5]+=Vals[1];
-Vals[1]+=(rotr(Vals[4],2)^rotr(Vals[4],13)^rotr(Vals[4],22));
-Vals[1]+=Ma(Vals[2],Vals[4],Vals[3]);
-
-W[12]=W[5];
-W[12]+=(rotr(W[10],17)^rotr(W[10],19)^(W[10]>>10U));
-Vals[0]+=W[12];
-Vals[0]+=(rotr(Vals[5],6)^rotr(Vals[5],11)^rotr(Vals[5],25));
-Vals[0]+=ch(Vals[5],Vals[7],Vals[6]);
-Vals[0]+=K[28];
-Vals[2]+=Vals[0];
-Vals[0]+=(rotr(Vals[1],2)^rotr(Vals[1],13)^rotr(Vals[1],22));
-Vals[0]+=Ma(Vals[3],Vals[1],Vals[4]);
-
-W[13]=W[6];
-W[13]+=(rotr(W[11],17)^rotr(W[11],19)^(W[11]>>10U));
-Vals[6]+=W[13];
-Vals[6]+=(rotr(Vals[2],6)^rotr(Vals[2],11)^rotr(Vals[2],25));
-Vals[6]+=ch(Vals[2],Vals[5],Vals[7]);
-Vals[6]+=K[29];
-Vals[3]+=Vals[6];
-Vals[6]+=(rotr(Vals[0],2)^rotr(Vals[0],13)^rotr(Vals[0],22));
-Vals[6]+=Ma(Vals[4],Vals[0],Vals[1]);
-
-W[14]=0x00400022U;
-W[14]+=W[7];
-W[14]+=(rotr(W[12],17)^rotr(W[12],19)^(W[12]>>10U));
-Vals[7]+=W[14];
-Vals[7]+=(rotr(Vals[3],6)^rotr(Vals[3],11)^rotr(Vals[3],25));
-Vals[7]+=ch(Vals[3],Vals[2],Vals[5]);
-Vals[7]+=K[30];
-Vals[4]+=Vals[7];
-Vals[7]+=(rotr(Vals[6],2)^rotr(Vals[6],13)^rotr(Vals[6],22));
-Vals[7]+=Ma(Vals[1],Vals[6],Vals[0]);
-
-W[15]=0x00000100U;
-W[15]+=(rotr(W[0],7)^rotr(W[0],18)^(W[0]>>3U));
-W[15]+=W[8];
-W[15]+=(rotr(W[13],17)^rotr(W[13],19)^(W[13]>>10U));
-Vals[5]+=W[15];
-Vals[5]+=(rotr(Vals[4],6)^rotr(Vals[4],11)^rotr(Vals[4],25));
-Vals[5]+=ch(Vals[4],Vals[3],Vals[2]);
-Vals[5]+=K[31];
-Vals[1]+=Vals[5];
-Vals[5]+=(rotr(Vals[7],2)^rotr(Vals[7],13)^rotr(Vals[7],22));
-Vals[5]+=Ma(Vals[0],Vals[7],Vals[6]);
-
-W[0]+=(rotr(W[1],7)^rotr(W[1],18)^(W[1]>>3U));
-W[0]+=W[9];
-W[0]+=(rotr(W[14],17)^rotr(W[14],19)^(W[14]>>10U));
-Vals[2]+=W[0];
-Vals[2]+=(rotr(Vals[1],6)^rotr(Vals[1],11)^rotr(Vals[1],25));
-Vals[2]+=ch(Vals[1],Vals[4],Vals[3]);
-Vals[2]+=K[32];
-Vals[0]+=Vals[2];
-Vals[2]+=(rotr(Vals[5],2)^rotr(Vals[5],13)^rotr(Vals[5],22));
-Vals[2]+=Ma(Vals[6],Vals[5],Vals[7]);
-
-W[1]+=(rotr(W[2],7)^rotr(W[2],18)^(W[2]>>3U));
-W[1]+=W[10];
-W[1]+=(rotr(W[15],17)^rotr(W[15],19)^(W[15]>>10U));
-Vals[3]+=W[1];
-Vals[3]+=(rotr(Vals[0],6)^rotr(Vals[0],11)^rotr(Vals[0],25));
-Vals[3]+=ch(Vals[0],Vals[1],Vals[4]);
-Vals[3]+=K[33];
-Vals[6]+=Vals[3];
-Vals[3]+=(rotr(Vals[2],2)^rotr(Vals[2],13)^rotr(Vals[2],22));
-Vals[3]+=Ma(Vals[7],Vals[2],Vals[5]);
-
-W[2]+=(rotr(W[3],7)^rotr(W[3],18)^(W[3]>>3U));
-W[2]+=W[11];
-W[2]+=(rotr(W[0],17)^rotr(W[0],19)^(W[0]>>10U));
-Vals[4]+=W[2];
-Vals[4]+=(rotr(Vals[6],6)^rotr(Vals[6],11)^rotr(Vals[6],25));
-Vals[4]+=ch(Vals[6],Vals[0],Vals[1]);
-Vals[4]+=K[34];
-Vals[7]+=Vals[4];
-Vals[4]+=(rotr(Vals[3],2)^rotr(Vals[3],13)^rotr(Vals[3],22));
-Vals[4]+=Ma(Vals[5],Vals[3],Vals[2]);
-
-W[3]+=(rotr(W[4],7)^rotr(W[4],18)^(W[4]>>3U));
-W[3]+=W[12];
-W[3]+=(rotr(W[1],17)^rotr(W[1],19)^(W[1]>>10U));
-Vals[1]+=W[3];
-Vals[1]+=(rotr(Vals[7],6)^rotr(Vals[7],11)^rotr(Vals[7],25));
-Vals[1]+=ch(Vals[7],Vals[6],Vals[0]);
-Vals[1]+=K[35];
-Vals[5]+=Vals[1];
-Vals[1]+=(rotr(Vals[4],2)^rotr(Vals[4],13)^rotr(Vals[4],22));
-Vals[1]+=Ma(Vals[2],Vals[4],Vals[3]);
-
-W[4]+=(rotr(W[5],7)^rotr(W[5],18)^(W[5]>>3U));
-W[4]+=W[13];
-W[4]+=(rotr(W[2],17)^rotr(W[2],19)^(W[2]>>10U));
-Vals[0]+=W[4];
-Vals[0]+=(rotr(Vals[5],6)^rotr(Vals[5],11)^rotr(Vals[5],25));
-Vals[0]+=ch(Vals[5],Vals[7],Vals[6]);
-Vals[0]+=K[36];
-Vals[2]+=Vals[0];
-Vals[0]+=(rotr(Vals[1],2)^rotr(Vals[1],13)^rotr(Vals[1],22));
-Vals[0]+=Ma(Vals[3],Vals[1],Vals[4]);
-
-W[5]+=(rotr(W[6],7)^rotr(W[6],18)^(W[6]>>3U));
-W[5]+=W[14];
-W[5]+=(rotr(W[3],17)^rotr(W[3],19)^(W[3]>>10U));
-Vals[6]+=W[5];
-Vals[6]+=(rotr(Vals[2],6)^rotr(Vals[2],11)^rotr(Vals[2],25));
-Vals[6]+=ch(Vals[2],Vals[5],Vals[7]);
-Vals[6]+=K[37];
-Vals[3]+=Vals[6];
-Vals[6]+=(rotr(Vals[0],2)^rotr(Vals[0],13)^rotr(Vals[0],22));
-Vals[6]+=Ma(Vals[4],Vals[0],Vals[1]);
-
-W[6]+=(rotr(W[7],7)^rotr(W[7],18)^(W[7]>>3U));
-W[6]+=W[15];
-W[6]+=(rotr(W[4],17)^rotr(W[4],19)^(W[4]>>10U));
-Vals[7]+=W[6];
-Vals[7]+=(rotr(Vals[3],6)^rotr(Vals[3],11)^rotr(Vals[3],25));
-Vals[7]+=ch(Vals[3],Vals[2],Vals[5]);
-Vals[7]+=K[38];
-Vals[4]+=Vals[7];
-Vals[7]+=(rotr(Vals[6],2)^rotr(Vals[6],13)^rotr(Vals[6],22));
-Vals[7]+=Ma(Vals[1],Vals[6],Vals[0]);
-
-W[7]+=(rotr(W[8],7)^rotr(W[8],18)^(W[8]>>3U));
-W[7]+=W[0];
-W[7]+=(rotr(W[5],17)^rotr(W[5],19)^(W[5]>>10U));
-Vals[5]+=W[7];
-Vals[5]+=(rotr(Vals[4],6)^rotr(Vals[4],11)^rotr(Vals[4],25));
-Vals[5]+=ch(Vals[4],Vals[3],Vals[2]);
-Vals[5]+=K[39];
-Vals[1]+=Vals[5];
-Vals[5]+=(rotr(Vals[7],2)^rotr(Vals[7],13)^rotr(Vals[7],22));
-Vals[5]+=Ma(Vals[0],Vals[7],Vals[6]);
-
-W[8]+=(rotr(W[9],7)^rotr(W[9],18)^(W[9]>>3U));
-W[8]+=W[1];
-W[8]+=(rotr(W[6],17)^rotr(W[6],19)^(W[6]>>10U));
-Vals[2]+=W[8];
-Vals[2]+=(rotr(Vals[1],6)^rotr(Vals[1],11)^rotr(Vals[1],25));
-Vals[2]+=ch(Vals[1],Vals[4],Vals[3]);
-Vals[2]+=K[40];
-Vals[0]+=Vals[2];
-Vals[2]+=(rotr(Vals[5],2)^rotr(Vals[5],13)^rotr(Vals[5],22));
-Vals[2]+=Ma(Vals[6],Vals[5],Vals[7]);
-
-W[9]+=(rotr(W[10],7)^rotr(W[10],18)^(W[10]>>3U));
-W[9]+=W[2];
-W[9]+=(rotr(W[7],17)^rotr(W[7],19)^(W[7]>>10U));
-Vals[3]+=W[9];
-Vals[3]+=(rotr(Vals[0],6)^rotr(Vals[0],11)^rotr(Vals[0],25));
-Vals[3]+=ch(Vals[0],Vals[1],Vals[4]);
-Vals[3]+=K[41];
-Vals[6]+=Vals[3];
-Vals[3]+=(rotr(Vals[2],2)^rotr(Vals[2],13)^rotr(Vals[2],22));
-Vals[3]+=Ma(Vals[7],Vals[2],Vals[5]);
-
-W[10]+=(rotr(W[11],7)^rotr(W[11],18)^(W[11]>>3U));
-W[10]+=W[3];
-W[10]+=(rotr(W[8],17)^rotr(W[8],19)^(W[8]>>10U));
-Vals[4]+=W[10];
-Vals[4]+=(rotr(Vals[6],6)^rotr(Vals[6],11)^rotr(Vals[6],25));
-Vals[4]+=ch(Vals[6],Vals[0],Vals[1]);
-Vals[4]+=K[42];
-Vals[7]+=Vals[4];
-Vals[4]+=(rotr(Vals[3],2)^rotr(Vals[3],13)^rotr(Vals[3],22));
-Vals[4]+=Ma(Vals[5],Vals[3],Vals[2]);
-
-W[11]+=(rotr(W[12],7)^rotr(W[12],18)^(W[12]>>3U));
-W[11]+=W[4];
-W[11]+=(rotr(W[9],17)^rotr(W[9],19)^(W[9]>>10U));
-Vals[1]+=W[11];
-Vals[1]+=(rotr(Vals[7],6)^rotr(Vals[7],11)^rotr(Vals[7],25));
-Vals[1]+=ch(Vals[7],Vals[6],Vals[0]);
-Vals[1]+=K[43];
-Vals[5]+=Vals[1];
-Vals[1]+=(rotr(Vals[4],2)^rotr(Vals[4],13)^rotr(Vals[4],22));
-Vals[1]+=Ma(Vals[2],Vals[4],Vals[3]);
-
-W[12]+=(rotr(W[13],7)^rotr(W[13],18)^(W[13]>>3U));
-W[12]+=W[5];
-W[12]+=(rotr(W[10],17)^rotr(W[10],19)^(W[10]>>10U));
-Vals[0]+=W[12];
-Vals[0]+=(rotr(Vals[5],6)^rotr(Vals[5],11)^rotr(Vals[5],25));
-Vals[0]+=ch(Vals[5],Vals[7],Vals[6]);
-Vals[0]+=K[44];
-Vals[2]+=Vals[0];
-Vals[0]+=(rotr(Vals[1],2)^rotr(Vals[1],13)^rotr(Vals[1],22));
-Vals[0]+=Ma(Vals[3],Vals[1],Vals[4]);
-
-W[13]+=(rotr(W[14],7)^rotr(W[14],18)^(W[14]>>3U));
-W[13]+=W[6];
-W[13]+=(rotr(W[11],17)^rotr(W[11],19)^(W[11]>>10U));
-Vals[6]+=W[13];
-Vals[6]+=(rotr(Vals[2],6)^rotr(Vals[2],11)^rotr(Vals[2],25));
-Vals[6]+=ch(Vals[2],Vals[5],Vals[7]);
-Vals[6]+=K[45];
-Vals[3]+=Vals[6];
-Vals[6]+=(rotr(Vals[0],2)^rotr(Vals[0],13)^rotr(Vals[0],22));
-Vals[6]+=Ma(Vals[4],Vals[0],Vals[1]);
-
-W[14]+=(rotr(W[15],7)^rotr(W[15],18)^(W[15]>>3U));
-W[14]+=W[7];
-W[14]+=(rotr(W[12],17)^rotr(W[12],19)^(W[12]>>10U));
-Vals[7]+=W[14];
-Vals[7]+=(rotr(Vals[3],6)^rotr(Vals[3],11)^rotr(Vals[3],25));
-Vals[7]+=ch(Vals[3],Vals[2],Vals[5]);
-Vals[7]+=K[46];
-Vals[4]+=Vals[7];
-Vals[7]+=(rotr(Vals[6],2)^rotr(Vals[6],13)^rotr(Vals[6],22));
-Vals[7]+=Ma(Vals[1],Vals[6],Vals[0]);
-
-W[15]+=(rotr(W[0],7)^rotr(W[0],18)^(W[0]>>3U));
-W[15]+=W[8];
-W[15]+=(rotr(W[13],17)^rotr(W[13],19)^(W[13]>>10U));
-Vals[5]+=W[15];
-Vals[5]+=(rotr(Vals[4],6)^rotr(Vals[4],11)^rotr(Vals[4],25));
-Vals[5]+=ch(Vals[4],Vals[3],Vals[2]);
-Vals[5]+=K[47];
-Vals[1]+=Vals[5];
-Vals[5]+=(rotr(Vals[7],2)^rotr(Vals[7],13)^rotr(Vals[7],22));
-Vals[5]+=Ma(Vals[0],Vals[7],Vals[6]);
-
-W[0]+=(rotr(W[1],7)^rotr(W[1],18)^(W[1]>>3U));
-W[0]+=W[9];
-W[0]+=(rotr(W[14],17)^rotr(W[14],19)^(W[14]>>10U));
-Vals[2]+=W[0];
-Vals[2]+=(rotr(Vals[1],6)^rotr(Vals[1],11)^rotr(Vals[1],25));
-Vals[2]+=ch(Vals[1],Vals[4],Vals[3]);
-Vals[2]+=K[48];
-Vals[0]+=Vals[2];
-Vals[2]+=(rotr(Vals[5],2)^rotr(Vals[5],13)^rotr(Vals[5],22));
-Vals[2]+=Ma(Vals[6],Vals[5],Vals[7]);
-
-W[1]+=(rotr(W[2],7)^rotr(W[2],18)^(W[2]>>3U));
-W[1]+=W[10];
-W[1]+=(rotr(W[15],17)^rotr(W[15],19)^(W[15]>>10U));
-Vals[3]+=W[1];
-Vals[3]+=(rotr(Vals[0],6)^rotr(Vals[0],11)^rotr(Vals[0],25));
-Vals[3]+=ch(Vals[0],Vals[1],Vals[4]);
-Vals[3]+=K[49];
-Vals[6]+=Vals[3];
-Vals[3]+=(rotr(Vals[2],2)^rotr(Vals[2],13)^rotr(Vals[2],22));
-Vals[3]+=Ma(Vals[7],Vals[2],Vals[5]);
-
-W[2]+=(rotr(W[3],7)^rotr(W[3],18)^(W[3]>>3U));
-W[2]+=W[11];
-W[2]+=(rotr(W[0],17)^rotr(W[0],19)^(W[0]>>10U));
-Vals[4]+=W[2];
-Vals[4]+=(rotr(Vals[6],6)^rotr(Vals[6],11)^rotr(Vals[6],25));
-Vals[4]+=ch(Vals[6],Vals[0],Vals[1]);
-Vals[4]+=K[50];
-Vals[7]+=Vals[4];
-Vals[4]+=(rotr(Vals[3],2)^rotr(Vals[3],13)^rotr(Vals[3],22));
-Vals[4]+=Ma(Vals[5],Vals[3],Vals[2]);
-
-W[3]+=(rotr(W[4],7)^rotr(W[4],18)^(W[4]>>3U));
-W[3]+=W[12];
-W[3]+=(rotr(W[1],17)^rotr(W[1],19)^(W[1]>>10U));
-Vals[1]+=W[3];
-Vals[1]+=(rotr(Vals[7],6)^rotr(Vals[7],11)^rotr(Vals[7],25));
-Vals[1]+=ch(Vals[7],Vals[6],Vals[0]);
-Vals[1]+=K[51];
-Vals[5]+=Vals[1];
-Vals[1]+=(rotr(Vals[4],2)^rotr(Vals[4],13)^rotr(Vals[4],22));
-Vals[1]+=Ma(Vals[2],Vals[4],Vals[3]);
-
-W[4]+=(rotr(W[5],7)^rotr(W[5],18)^(W[5]>>3U));
-W[4]+=W[13];
-W[4]+=(rotr(W[2],17)^rotr(W[2],19)^(W[2]>>10U));
-Vals[0]+=W[4];
-Vals[0]+=(rotr(Vals[5],6)^rotr(Vals[5],11)^rotr(Vals[5],25));
-Vals[0]+=ch(Vals[5],Vals[7],Vals[6]);
-Vals[0]+=K[52];
-Vals[2]+=Vals[0];
-Vals[0]+=(rotr(Vals[1],2)^rotr(Vals[1],13)^rotr(Vals[1],22));
-Vals[0]+=Ma(Vals[3],Vals[1],Vals[4]);
-
-W[5]+=(rotr(W[6],7)^rotr(W[6],18)^(W[6]>>3U));
-W[5]+=W[14];
-W[5]+=(rotr(W[3],17)^rotr(W[3],19)^(W[3]>>10U));
-Vals[6]+=W[5];
-Vals[6]+=(rotr(Vals[2],6)^rotr(Vals[2],11)^rotr(Vals[2],25));
-Vals[6]+=ch(Vals[2],Vals[5],Vals[7]);
-Vals[6]+=K[53];
-Vals[3]+=Vals[6];
-Vals[6]+=(rotr(Vals[0],2)^rotr(Vals[0],13)^rotr(Vals[0],22));
-Vals[6]+=Ma(Vals[4],Vals[0],Vals[1]);
-
-W[6]+=(rotr(W[7],7)^rotr(W[7],18)^(W[7]>>3U));
-W[6]+=W[15];
-W[6]+=(rotr(W[4],17)^rotr(W[4],19)^(W[4]>>10U));
-Vals[7]+=W[6];
-Vals[7]+=(rotr(Vals[3],6)^rotr(Vals[3],11)^rotr(Vals[3],25));
-Vals[7]+=ch(Vals[3],Vals[2],Vals[5]);
-Vals[7]+=K[54];
-Vals[4]+=Vals[7];
-Vals[7]+=(rotr(Vals[6],2)^rotr(Vals[6],13)^rotr(Vals[6],22));
-Vals[7]+=Ma(Vals[1],Vals[6],Vals[0]);
-
-W[7]+=(rotr(W[8],7)^rotr(W[8],18)^(W[8]>>3U));
-W[7]+=W[0];
-W[7]+=(rotr(W[5],17)^rotr(W[5],19)^(W[5]>>10U));
-Vals[5]+=W[7];
-Vals[5]+=(rotr(Vals[4],6)^rotr(Vals[4],11)^rotr(Vals[4],25));
-Vals[5]+=ch(Vals[4],Vals[3],Vals[2]);
-Vals[5]+=K[55];
-Vals[1]+=Vals[5];
-Vals[5]+=(rotr(Vals[7],2)^rotr(Vals[7],13)^rotr(Vals[7],22));
-Vals[5]+=Ma(Vals[0],Vals[7],Vals[6]);
-
-W[8]+=(rotr(W[9],7)^rotr(W[9],18)^(W[9]>>3U));
-W[8]+=W[1];
-W[8]+=(rotr(W[6],17)^rotr(W[6],19)^(W[6]>>10U));
-Vals[2]+=W[8];
-Vals[2]+=(rotr(Vals[1],6)^rotr(Vals[1],11)^rotr(Vals[1],25));
-Vals[2]+=ch(Vals[1],Vals[4],Vals[3]);
-Vals[2]+=K[56];
-Vals[0]+=Vals[2];
-
-W[9]+=(rotr(W[10],7)^rotr(W[10],18)^(W[10]>>3U));
-W[9]+=W[2];
-W[9]+=(rotr(W[7],17)^rotr(W[7],19)^(W[7]>>10U));
-Vals[3]+=W[9];
-Vals[3]+=(rotr(Vals[0],6)^rotr(Vals[0],11)^rotr(Vals[0],25));
-Vals[3]+=ch(Vals[0],Vals[1],Vals[4]);
-Vals[3]+=K[57];
-Vals[3]+=Vals[6];
-
-W[10]+=(rotr(W[11],7)^rotr(W[11],18)^(W[11]>>3U));
-W[10]+=W[3];
-W[10]+=(rotr(W[8],17)^rotr(W[8],19)^(W[8]>>10U));
-Vals[4]+=W[10];
-Vals[4]+=(rotr(Vals[3],6)^rotr(Vals[3],11)^rotr(Vals[3],25));
-Vals[4]+=ch(Vals[3],Vals[0],Vals[1]);
-Vals[4]+=K[58];
-Vals[4]+=Vals[7];
-Vals[1]+=(rotr(Vals[4],6)^rotr(Vals[4],11)^rotr(Vals[4],25));
-Vals[1]+=ch(Vals[4],Vals[3],Vals[0]);
-Vals[1]+=W[11];
-Vals[1]+=(rotr(W[12],7)^rotr(W[12],18)^(W[12]>>3U));
-Vals[1]+=W[4];
-Vals[1]+=(rotr(W[9],17)^rotr(W[9],19)^(W[9]>>10U));
-Vals[1]+=K[59];
-Vals[1]+=Vals[5];
-
-#define FOUND (0x80)
-#define NFLAG (0x7F)
+	u W[64];
+	u CW[64];
+	u A, B, C, D, E, F, G, H;
+
+	for (uint iteration = 0; iteration < ITERATIONS; iteration += VECTOR_WIDTH) {
+		const u nonce = offset + get_global_id(0) * ITERATIONS + iteration + LANES;
+
+		// pow layer, from round 4 on; the host ran rounds 0..2, and round 3
+		// only adds the nonce to a and e. Round 4 is the fifth round of an
+		// R8, so the state starts rotated by four registers.
+		E = PreVal0 + nonce; F = B1; G = C1; H = D1;
+		A = PreVal4 + nonce; B = F1; C = G1; D = H1;
+		W[3] = nonce;
+		W[4] = W4; W[5] = W5; W[6] = W6; W[7] = W7; W[8] = W8;
+		W[9] = W9; W[10] = W10; W[11] = W11; W[12] = W12;
+		W[13] = 0x80000000U; W[14] = 0; W[15] = 0x1a0U;
+		W[16] = W16; W[17] = W17;
+		W[18] = PreW18 + sigma0(nonce);
+		W[19] = PreW19 + nonce;
+		#pragma unroll
+		for (uint i = 20; i < 64; ++i) {
+			EXTEND(W, i);
+		}
+		R(E, F, G, H, A, B, C, D, POW_KW(4));
+		R(D, E, F, G, H, A, B, C, POW_KW(5));
+		R(C, D, E, F, G, H, A, B, POW_KW(6));
+		R(B, C, D, E, F, G, H, A, POW_KW(7));
+		R8(8, POW_KW); R8(16, POW_KW); R8(24, POW_KW); R8(32, POW_KW);
+		R8(40, POW_KW); R8(48, POW_KW); R8(56, POW_KW);
+
+		// chain layer, from round 8 on; rounds 0..7 only depend on the
+		// previous block hash
+		CW[0] = Prev0; CW[1] = Prev1; CW[2] = Prev2; CW[3] = Prev3;
+		CW[4] = Prev4; CW[5] = Prev5; CW[6] = Prev6; CW[7] = Prev7;
+		CW[8] = A + IV[0]; CW[9] = B + IV[1]; CW[10] = C + IV[2]; CW[11] = D + IV[3];
+		CW[12] = E + IV[4]; CW[13] = F + IV[5]; CW[14] = G + IV[6]; CW[15] = H + IV[7];
+		#pragma unroll
+		for (uint i = 16; i < 64; ++i) {
+			EXTEND(CW, i);
+		}
+		A = CA; B = CB; C = CC; D = CD; E = CE; F = CF; G = CG; H = CH;
+		R8(8, CHAIN_KW); R8(16, CHAIN_KW); R8(24, CHAIN_KW); R8(32, CHAIN_KW);
+		R8(40, CHAIN_KW); R8(48, CHAIN_KW); R8(56, CHAIN_KW);
+		A += IV[0]; B += IV[1]; C += IV[2]; D += IV[3];
+		E += IV[4]; F += IV[5]; G += IV[6]; H += IV[7];
+
+		// padding block; the last hash word is e after round 60 plus the
+		// input h, so only T1 of round 60 is computed
+		const u chain_h = H;
+		R8(0, PAD_KW); R8(8, PAD_KW); R8(16, PAD_KW); R8(24, PAD_KW);
+		R8(32, PAD_KW); R8(40, PAD_KW); R8(48, PAD_KW);
+		R(A, B, C, D, E, F, G, H, PAD_KW(56));
+		R(H, A, B, C, D, E, F, G, PAD_KW(57));
+		R(G, H, A, B, C, D, E, F, PAD_KW(58));
+		R(F, G, H, A, B, C, D, E, PAD_KW(59));
+		const u last_word = chain_h + H + D + Sigma1(A) + ch(A, B, C) + PAD_KW(60);
 
 #if defined(VECTORS2) || defined(VECTORS4)
-	Vals[2]+=Ma(Vals[6],Vals[5],Vals[7]);
-	Vals[2]+=(rotr(Vals[5],2)^rotr(Vals[5],13)^rotr(Vals[5],22));
-	Vals[2]+=W[12];
-	Vals[2]+=(rotr(W[13],7)^rotr(W[13],18)^(W[13]>>3U));
-	Vals[2]+=W[5];
-	Vals[2]+=(rotr(W[10],17)^rotr(W[10],19)^(W[10]>>10U));
-	Vals[2]+=Vals[0];
-	Vals[2]+=(rotr(Vals[1],6)^rotr(Vals[1],11)^rotr(Vals[1],25));
-	Vals[2]+=ch(Vals[1],Vals[4],Vals[3]);
-
-	if (any(Vals[2] == 0x136032edU)) {
-		if (Vals[2].x == 0x136032edU)
-			output[FOUND] = output[NFLAG & nonce.x] = nonce.x;
-		if (Vals[2].y == 0x136032edU)
-			output[FOUND] = output[NFLAG & nonce.y] = nonce.y;
+		if (any(last_word == 0)) {
+			if (last_word.x == 0) {
+				report_nonce(output, nonce.x);
+			}
+			if (last_word.y == 0) {
+				report_nonce(output, nonce.y);
+			}
 #if defined(VECTORS4)
-		if (Vals[2].z == 0x136032edU)
-			output[FOUND] = output[NFLAG & nonce.z] = nonce.z;
-		if (Vals[2].w == 0x136032edU)
-			output[FOUND] = output[NFLAG & nonce.w] = nonce.w;
+			if (last_word.z == 0) {
+				report_nonce(output, nonce.z);
+			}
+			if (last_word.w == 0) {
+				report_nonce(output, nonce.w);
+			}
 #endif
-	}
+		}
 #else
-	if ((Vals[2]+
-		Ma(Vals[6],Vals[5],Vals[7])+
-		(rotr(Vals[5],2)^rotr(Vals[5],13)^rotr(Vals[5],22))+
-		W[12]+
-		(rotr(W[13],7)^rotr(W[13],18)^(W[13]>>3U))+
-		W[5]+
-		(rotr(W[10],17)^rotr(W[10],19)^(W[10]>>10U))+
-		Vals[0]+
-		(rotr(Vals[1],6)^rotr(Vals[1],11)^rotr(Vals[1],25))+
-		ch(Vals[1],Vals[4],Vals[3])) == 0x136032edU)
-			output[FOUND] = output[NFLAG & nonce] =  nonce;
+		if (last_word == 0) {
+			report_nonce(output, nonce);
+		}
 #endif
+	}
 }
@@ -1,12 +1,36 @@
 use std::{sync::Arc, time::Duration};
 
-use lotus_miner_lib::{ConfigSettings, Server};
+use lotus_miner_lib::{
+    check_found_blocks, format_bench_json, format_bench_table, format_found_blocks_table,
+    run_bench, tune_devices, Command, ConfigSettings, Log, Server,
+};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let config: ConfigSettings = ConfigSettings::load(true)?;
+    let (config, command) = ConfigSettings::load_command(true)?;
+    match command {
+        Command::Mine => {}
+        Command::Bench(bench) => {
+            let results = run_bench(&config, &bench)?;
+            if bench.json {
+                println!("{}", format_bench_json(&results));
+            } else {
+                print!("{}", format_bench_table(&results));
+            }
+            return Ok(());
+        }
+        Command::Tune => {
+            tune_devices(&config, &Log::new())?;
+            return Ok(());
+        }
+        Command::Blocks => {
+            let blocks = check_found_blocks(config).await?;
+            print!("{}", format_found_blocks_table(&blocks));
+            return Ok(());
+        }
+    }
     let report_hashrate_interval = Duration::from_secs(10);
-    let server = Arc::new(Server::from_config(config, report_hashrate_interval));
+    let server = Arc::new(Server::from_config(config, report_hashrate_interval)?);
     tokio::spawn({
         let server = Arc::clone(&server);
         async move {
@@ -15,6 +39,12 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 if let Some(hashrate) = server.log().hashrates().last() {
                     println!("{}", hashrate);
                 }
+                let device_hashrates = server.log().device_hashrates();
+                if device_hashrates.len() > 1 {
+                    for device_hashrate in device_hashrates.values() {
+                        println!("{}", device_hashrate);
+                    }
+                }
             }
         }
     });
@@ -8,7 +8,9 @@ use eframe::{
     },
     epi,
 };
-use lotus_miner_lib::{settings, ConfigSettings, LogEntry, Miner, Server, ServerRef};
+use lotus_miner_lib::{
+    difficulty, settings, ConfigSettings, LogEntry, Miner, NodeConfig, Server, ServerRef,
+};
 use serde::{Deserialize, Serialize};
 use tokio::runtime::Runtime;
 
@@ -20,7 +22,48 @@ pub struct UserSettings {
     bitcoind_user: String,
     bitcoind_password: String,
     rpc_poll_interval: u64,
-    gpu_index: i64,
+    #[serde(default)]
+    gpu_indices: Vec<i64>,
+    #[serde(default = "default_backend")]
+    backend: String,
+    #[serde(default)]
+    cpu_threads: i64,
+    #[serde(default = "default_work_source")]
+    work_source: String,
+    #[serde(default)]
+    pool_url: String,
+    #[serde(default)]
+    pool_user: String,
+    #[serde(default = "default_pool_password")]
+    pool_password: String,
+    #[serde(default)]
+    backup_nodes: Vec<NodeConfig>,
+    #[serde(default)]
+    rpc_cookie_file: String,
+    #[serde(default)]
+    zmq_hashblock: String,
+    #[serde(default)]
+    autotune: bool,
+    #[serde(default)]
+    kernel_path: String,
+    #[serde(default = "default_kernel")]
+    kernel: String,
+}
+
+fn default_backend() -> String {
+    settings::DEFAULT_BACKEND.to_string()
+}
+
+fn default_work_source() -> String {
+    settings::DEFAULT_WORK_SOURCE.to_string()
+}
+
+fn default_pool_password() -> String {
+    settings::DEFAULT_POOL_PASSWORD.to_string()
+}
+
+fn default_kernel() -> String {
+    settings::DEFAULT_KERNEL.to_string()
 }
 
 pub struct MinerApp {
@@ -41,17 +84,39 @@ pub enum HashrateZoom {
 }
 
 impl MinerApp {
-    pub fn load() -> Self {
+    pub fn load() -> Result<Self, Box<dyn std::error::Error>> {
         let user_settings = match ConfigSettings::load(false) {
-            Ok(config_settings) => UserSettings {
-                mine_to_address: config_settings.mine_to_address,
-                intensity: config_settings.kernel_size.try_into().unwrap(),
-                bitcoind_url: config_settings.rpc_url,
-                bitcoind_user: config_settings.rpc_user,
-                bitcoind_password: config_settings.rpc_password,
-                rpc_poll_interval: config_settings.rpc_poll_interval.try_into().unwrap(),
-                gpu_index: config_settings.gpu_index,
-            },
+            Ok(config_settings) => {
+                // Derive values through `ConfigSettings` methods before the
+                // literal below moves fields out of it.
+                let gpu_indices = config_settings
+                    .selected_gpu_indices()
+                    .into_iter()
+                    .map(|gpu_index| gpu_index as i64)
+                    .collect();
+                let rpc_cookie_file = config_settings.cookie_file().unwrap_or_default();
+                UserSettings {
+                    mine_to_address: config_settings.mine_to_address,
+                    intensity: config_settings.kernel_size.try_into().unwrap(),
+                    bitcoind_url: config_settings.rpc_url,
+                    bitcoind_user: config_settings.rpc_user,
+                    bitcoind_password: config_settings.rpc_password,
+                    rpc_poll_interval: config_settings.rpc_poll_interval.try_into().unwrap(),
+                    gpu_indices,
+                    backend: config_settings.backend,
+                    cpu_threads: config_settings.cpu_threads,
+                    work_source: config_settings.work_source,
+                    pool_url: config_settings.pool_url,
+                    pool_user: config_settings.pool_user,
+                    pool_password: config_settings.pool_password,
+                    rpc_cookie_file,
+                    backup_nodes: config_settings.backup_nodes,
+                    zmq_hashblock: config_settings.zmq_hashblock,
+                    autotune: config_settings.autotune,
+                    kernel_path: config_settings.kernel_path,
+                    kernel: config_settings.kernel,
+                }
+            }
             Err(err) => {
                 eprintln!("Failed to load config, falling back to defaults: {}", err);
                 UserSettings {
@@ -61,7 +126,19 @@ impl MinerApp {
                     bitcoind_user: settings::DEFAULT_USER.to_string(),
                     bitcoind_password: settings::DEFAULT_PASSWORD.to_string(),
                     rpc_poll_interval: settings::DEFAULT_RPC_POLL_INTERVAL.try_into().unwrap(),
-                    gpu_index: settings::DEFAULT_GPU_INDEX,
+                    gpu_indices: vec![settings::DEFAULT_GPU_INDEX],
+                    backend: settings::DEFAULT_BACKEND.to_string(),
+                    cpu_threads: settings::DEFAULT_CPU_THREADS,
+                    work_source: default_work_source(),
+                    pool_url: String::new(),
+                    pool_user: String::new(),
+                    pool_password: default_pool_password(),
+                    backup_nodes: Vec::new(),
+                    rpc_cookie_file: String::new(),
+                    zmq_hashblock: String::new(),
+                    autotune: false,
+                    kernel_path: String::new(),
+                    kernel: default_kernel(),
                 }
             }
         };
@@ -72,16 +149,34 @@ impl MinerApp {
             rpc_poll_interval: user_settings.rpc_poll_interval.try_into().unwrap(),
             mine_to_address: user_settings.mine_to_address.clone(),
             kernel_size: user_settings.intensity.into(),
-            gpu_index: user_settings.gpu_index,
+            gpu_index: settings::DEFAULT_GPU_INDEX,
+            gpu_indices: user_settings.gpu_indices.clone(),
+            backend: user_settings.backend.clone(),
+            cpu_threads: user_settings.cpu_threads,
+            work_source: user_settings.work_source.clone(),
+            pool_url: user_settings.pool_url.clone(),
+            pool_user: user_settings.pool_user.clone(),
+            pool_password: user_settings.pool_password.clone(),
+            proxy_bind: settings::DEFAULT_PROXY_BIND.to_string(),
+            proxy_difficulty: settings::DEFAULT_PROXY_DIFFICULTY,
+            backup_nodes: user_settings.backup_nodes.clone(),
+            rpc_cookie_file: user_settings.rpc_cookie_file.clone(),
+            datadir: String::new(),
+            zmq_hashblock: user_settings.zmq_hashblock.clone(),
+            autotune: user_settings.autotune,
+            autotune_latency_ms: settings::DEFAULT_AUTOTUNE_LATENCY_MS,
+            kernel_path: user_settings.kernel_path.clone(),
+            kernel: user_settings.kernel.clone(),
+            blocks_file: String::new(),
         };
-        MinerApp {
+        Ok(MinerApp {
             user_settings,
-            server: Arc::new(Server::from_config(config, Duration::from_millis(300))),
+            server: Arc::new(Server::from_config(config, Duration::from_millis(300))?),
             device_names: Miner::list_device_names(),
-            rt: tokio::runtime::Runtime::new().unwrap(),
+            rt: tokio::runtime::Runtime::new()?,
             logs: Vec::new(),
             hashrate_zoom: HashrateZoom::T10m,
-        }
+        })
     }
 }
 
@@ -141,6 +236,13 @@ impl epi::App for MinerApp {
                     ));
                     ui.end_row();
 
+                    ui.label("Autotune: ");
+                    ui.checkbox(
+                        &mut self.user_settings.autotune,
+                        "Tune intensity per GPU on start (overrides intensity)",
+                    );
+                    ui.end_row();
+
                     ui.label("RPC URL: ");
                     ui.text_edit_singleline(&mut self.user_settings.bitcoind_url);
                     ui.end_row();
@@ -156,6 +258,13 @@ impl epi::App for MinerApp {
                     );
                     ui.end_row();
 
+                    ui.label("RPC Cookie File: ");
+                    ui.add(
+                        TextEdit::singleline(&mut self.user_settings.rpc_cookie_file)
+                            .hint_text("Optional, replaces user/password"),
+                    );
+                    ui.end_row();
+
                     ui.label("RPC Poll Interval: ");
                     ui.add(egui::Slider::new(
                         &mut self.user_settings.rpc_poll_interval,
@@ -163,23 +272,78 @@ impl epi::App for MinerApp {
                     ));
                     ui.end_row();
 
-                    ui.label("GPU: ");
-                    egui::ComboBox::from_id_source("gpu")
-                        .selected_text(
-                            self.device_names
-                                .get(self.user_settings.gpu_index as usize)
-                                .map(String::as_str)
-                                .unwrap_or(""),
-                        )
-                        .show_ui(ui, |ui| {
-                            for (device_idx, device_name) in self.device_names.iter().enumerate() {
-                                ui.selectable_value(
-                                    &mut self.user_settings.gpu_index,
-                                    device_idx as i64,
-                                    device_name,
+                    ui.label("ZMQ Block Notify: ");
+                    ui.add(
+                        TextEdit::singleline(&mut self.user_settings.zmq_hashblock)
+                            .hint_text("Optional, e.g. tcp://127.0.0.1:28332"),
+                    );
+                    ui.end_row();
+
+                    ui.label("Backup Nodes: ");
+                    ui.vertical(|ui| {
+                        let backup_nodes = &mut self.user_settings.backup_nodes;
+                        let mut removed_node = None;
+                        for (node_idx, node) in backup_nodes.iter_mut().enumerate() {
+                            ui.horizontal(|ui| {
+                                ui.add(TextEdit::singleline(&mut node.url).hint_text("URL"));
+                                if ui.button("Remove").clicked() {
+                                    removed_node = Some(node_idx);
+                                }
+                            });
+                            ui.horizontal(|ui| {
+                                ui.add(TextEdit::singleline(&mut node.user).hint_text("User"));
+                                ui.add(
+                                    TextEdit::singleline(&mut node.password)
+                                        .hint_text("Password")
+                                        .password(true),
                                 );
+                            });
+                        }
+                        if let Some(node_idx) = removed_node {
+                            backup_nodes.remove(node_idx);
+                        }
+                        if ui.button("Add Backup Node").clicked() {
+                            backup_nodes.push(NodeConfig {
+                                url: String::new(),
+                                user: settings::DEFAULT_USER.to_string(),
+                                password: settings::DEFAULT_PASSWORD.to_string(),
+                                cookie_file: None,
+                            });
+                        }
+                    });
+                    ui.end_row();
+
+                    ui.label("Pool URL: ");
+                    ui.text_edit_singleline(&mut self.user_settings.pool_url);
+                    ui.end_row();
+
+                    ui.label("Pool User: ");
+                    ui.text_edit_singleline(&mut self.user_settings.pool_user);
+                    ui.end_row();
+
+                    ui.label("Pool Password: ");
+                    ui.add(
+                        TextEdit::singleline(&mut self.user_settings.pool_password)
+                            .password(true),
+                    );
+                    ui.end_row();
+
+                    ui.label("GPUs: ");
+                    ui.vertical(|ui| {
+                        let gpu_indices = &mut self.user_settings.gpu_indices;
+                        for (device_idx, device_name) in self.device_names.iter().enumerate() {
+                            let device_idx = device_idx as i64;
+                            let mut selected = gpu_indices.contains(&device_idx);
+                            if ui.checkbox(&mut selected, device_name).changed() {
+                                if selected {
+                                    gpu_indices.push(device_idx);
+                                    gpu_indices.sort_unstable();
+                                } else {
+                                    gpu_indices.retain(|&gpu_index| gpu_index != device_idx);
+                                }
                             }
-                        });
+                        }
+                    });
                     ui.end_row();
 
                     ui.label("");
@@ -193,10 +357,35 @@ impl epi::App for MinerApp {
                 });
 
             let hashrate_text = match self.server.log().hashrates().last() {
-                Some(hashrate) => hashrate.to_string(),
+                Some(hashrate) => format!("Hashrate: {:.3} MH/s", hashrate.hashrate / 1_000_000.0),
                 None => "Hashrate: calculating...".to_string(),
             };
             ui.add(Label::new(hashrate_text).heading());
+            if let Some(hashrate) = self.server.log().hashrates().last() {
+                if let (Some(difficulty), Some(time)) =
+                    (hashrate.difficulty, hashrate.expected_time_to_block())
+                {
+                    ui.label(format!(
+                        "Network difficulty: {:.3}, expected time to block: {}",
+                        difficulty,
+                        difficulty::format_duration(time)
+                    ));
+                }
+            }
+            for device_hashrate in self.server.log().device_hashrates().values() {
+                ui.label(format!(
+                    "Device {} ({}): {:.3} MH/s",
+                    device_hashrate.device_idx,
+                    device_hashrate.device_name,
+                    device_hashrate.hashrate / 1_000_000.0
+                ));
+            }
+            if let Some(active_node) = self.server.log().active_node() {
+                ui.label(format!(
+                    "Active node: {} ({} switches)",
+                    active_node.url, active_node.switches
+                ));
+            }
             ui.horizontal(|ui| {
                 ui.radio_value(&mut self.hashrate_zoom, HashrateZoom::T10m, "10m");
                 ui.radio_value(&mut self.hashrate_zoom, HashrateZoom::T1h, "1h");
@@ -254,6 +443,30 @@ impl epi::App for MinerApp {
         });
 
         egui::CentralPanel::default().show(ctx, |ui| {
+            let found_blocks = self.server.ledger().blocks();
+            if !found_blocks.is_empty() {
+                ui.heading("Found Blocks");
+                egui::Grid::new("found_blocks_grid")
+                    .striped(true)
+                    .spacing([20.0, 4.0])
+                    .show(ui, |ui| {
+                        let headers = ["Submitted", "Height", "Hash", "Status", "Confs", "Device"];
+                        for header in &headers {
+                            ui.add(Label::new(*header).strong());
+                        }
+                        ui.end_row();
+                        for block in found_blocks.iter().rev() {
+                            ui.label(&block.timestamp);
+                            ui.label(block.height.to_string());
+                            ui.label(&block.hash).on_hover_text(&block.submit_result);
+                            ui.label(block.status.to_string());
+                            ui.label(block.confirmations.to_string());
+                            ui.label(&block.device);
+                            ui.end_row();
+                        }
+                    });
+            }
+
             ui.heading("Logs");
             if ui.button("Copy").clicked() {
                 let mut ctx: ClipboardContext = ClipboardProvider::new().unwrap();
@@ -283,16 +496,39 @@ impl MinerApp {
         self.server.log().info("Applying settings");
         let user_settings = self.user_settings.clone();
         self.rt.spawn(async move {
+            let cookie_file = Some(user_settings.rpc_cookie_file)
+                .filter(|cookie_file| !cookie_file.is_empty());
+            let mut nodes = vec![NodeConfig {
+                url: user_settings.bitcoind_url,
+                user: user_settings.bitcoind_user,
+                password: user_settings.bitcoind_password,
+                cookie_file,
+            }];
+            nodes.extend(user_settings.backup_nodes);
             let mut node_settings = server.node_settings().await;
-            node_settings.bitcoind_url = user_settings.bitcoind_url;
-            node_settings.bitcoind_user = user_settings.bitcoind_user;
-            node_settings.bitcoind_password = user_settings.bitcoind_password;
+            if node_settings.nodes != nodes {
+                node_settings.nodes = nodes;
+                node_settings.active_node = 0;
+            }
             node_settings.rpc_poll_interval = user_settings.rpc_poll_interval;
+            node_settings.zmq_hashblock = user_settings.zmq_hashblock;
             node_settings.miner_addr = user_settings.mine_to_address;
-            let mut miner = server.miner();
-            miner.set_intensity(user_settings.intensity);
-            let result = miner.update_gpu_index(user_settings.gpu_index);
-            if let Err(err) = result {
+            node_settings.pool_url = user_settings.pool_url;
+            node_settings.pool_user = user_settings.pool_user;
+            node_settings.pool_password = user_settings.pool_password;
+            drop(node_settings);
+            if !user_settings.autotune {
+                server.set_intensity(user_settings.intensity);
+            }
+            if user_settings.gpu_indices.is_empty() {
+                server.log().warn("No GPU selected, mining stopped");
+            }
+            let gpu_indices = user_settings
+                .gpu_indices
+                .iter()
+                .map(|&gpu_index| gpu_index as usize)
+                .collect();
+            if let Err(err) = server.update_gpu_indices(gpu_indices) {
                 server.log().error(err);
             }
         });
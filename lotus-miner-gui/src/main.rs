@@ -1,7 +1,13 @@
 mod app;
 
 fn main() {
-    let app = app::MinerApp::load();
+    let app = match app::MinerApp::load() {
+        Ok(app) => app,
+        Err(err) => {
+            eprintln!("Failed to start miner: {}", err);
+            std::process::exit(1);
+        }
+    };
     let native_options = eframe::NativeOptions::default();
     eframe::run_native(Box::new(app), native_options);
 }
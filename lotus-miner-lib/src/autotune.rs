@@ -0,0 +1,256 @@
+use std::{
+    collections::BTreeMap,
+    convert::TryInto,
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use eyre::Result;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    bench::measure,
+    miner::{BackendKind, Miner, MiningSettings},
+    settings::FOLDER_DIR,
+    ConfigSettings, Log,
+};
+
+const AUTOTUNE_FILE: &str = "autotune.json";
+/// Intensities (log2 of `kernel_size`) the autotuner tries.
+const AUTOTUNE_INTENSITIES: RangeInclusive<u32> = 16..=28;
+const AUTOTUNE_LOCAL_WORK_SIZES: &[i32] = &[64, 128, 256];
+const AUTOTUNE_INNER_ITER_SIZES: &[i32] = &[8, 16, 32];
+/// Each intensity is measured for this many times the target latency.
+const MEASURE_DISPATCHES: u32 = 4;
+/// Raising the intensity further stops once it gains less than this.
+const MIN_HASHRATE_GAIN: f64 = 1.02;
+
+#[derive(Debug, Error)]
+pub enum AutotuneError {
+    #[error("None of the kernel settings could be set up on device {0:?}")]
+    NoWorkingSettings(Vec<usize>),
+    #[error("Couldn't write autotune results to {0}: {1}")]
+    Write(String, std::io::Error),
+}
+
+/// Best settings found for a device, stored in `~/.lotus-miner/autotune.json`
+/// keyed by device name.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TunedSettings {
+    pub kernel_name: String,
+    pub intensity: u32,
+    pub local_work_size: i32,
+    pub inner_iter_size: i32,
+    /// Hashes per second measured with these settings.
+    pub hashrate: f64,
+    /// Latency per `find_nonces` call these settings were tuned for.
+    pub target_latency_ms: u64,
+}
+
+impl TunedSettings {
+    fn apply(&self, settings: &MiningSettings) -> MiningSettings {
+        MiningSettings {
+            kernel_size: 1 << self.intensity,
+            local_work_size: self.local_work_size,
+            inner_iter_size: self.inner_iter_size,
+            ..settings.clone()
+        }
+    }
+}
+
+/// Sets up miners like `Miner::setup_devices`, using each device's stored
+/// tuned settings; devices without any for this kernel and latency are
+/// tuned first.
+pub(crate) fn setup_tuned_devices(
+    settings: &MiningSettings,
+    target_latency: Duration,
+    log: &Log,
+) -> Result<Vec<Miner>> {
+    let tuned_settings = tune_devices_with(settings, target_latency, false, log)?;
+    settings
+        .per_device()
+        .iter()
+        .zip(tuned_settings)
+        .map(|(device_settings, (_, tuned))| Miner::setup(tuned.apply(device_settings)))
+        .collect()
+}
+
+/// Tunes all devices selected in `config` again, ignoring stored results,
+/// and stores the new ones.
+pub fn tune_devices(config: &ConfigSettings, log: &Log) -> Result<Vec<(String, TunedSettings)>> {
+    let settings = MiningSettings::from_config(config)?;
+    let target_latency = Duration::from_millis(config.autotune_latency_ms.try_into()?);
+    tune_devices_with(&settings, target_latency, true, log)
+}
+
+fn tune_devices_with(
+    settings: &MiningSettings,
+    target_latency: Duration,
+    retune: bool,
+    log: &Log,
+) -> Result<Vec<(String, TunedSettings)>> {
+    let path = autotune_file();
+    let mut stored = path.as_deref().map(load_tuned_settings).unwrap_or_default();
+    let mut results = Vec::new();
+    let mut changed = false;
+    for device_settings in settings.per_device() {
+        let device_name = Miner::device_name_of(&device_settings)?;
+        let tuned = match stored.get(&device_name) {
+            Some(tuned)
+                if !retune
+                    && tuned.kernel_name == device_settings.kernel_name
+                    && tuned.target_latency_ms == target_latency.as_millis() as u64 =>
+            {
+                tuned.clone()
+            }
+            _ => {
+                log.info(format!(
+                    "Autotuning {} for {} ms per dispatch, this takes a while",
+                    device_name,
+                    target_latency.as_millis()
+                ));
+                let tuned =
+                    autotune_device(&device_settings, target_latency, AUTOTUNE_INTENSITIES, log)?;
+                stored.insert(device_name.clone(), tuned.clone());
+                changed = true;
+                tuned
+            }
+        };
+        log.info(format!(
+            "Using intensity {}, local_work_size {} and inner_iter_size {} on {} ({:.3} MH/s)",
+            tuned.intensity,
+            tuned.local_work_size,
+            tuned.inner_iter_size,
+            device_name,
+            tuned.hashrate / 1_000_000.0
+        ));
+        results.push((device_name, tuned));
+    }
+    if let (Some(path), true) = (path, changed) {
+        save_tuned_settings(&path, &stored)?;
+    }
+    Ok(results)
+}
+
+/// Finds the settings with the best hashrate whose `find_nonces` calls take
+/// at most `target_latency`, so new tips are picked up promptly. The lowest
+/// intensity is accepted even if it's slower than that.
+fn autotune_device(
+    settings: &MiningSettings,
+    target_latency: Duration,
+    intensities: RangeInclusive<u32>,
+    log: &Log,
+) -> Result<TunedSettings> {
+    // Only the hashrates matter, the logs of the measurements are dropped
+    let measure_log = Log::new();
+    // The CPU backend has no kernel to tune, only the batch size
+    let (local_work_sizes, inner_iter_sizes) = match settings.backend {
+        BackendKind::OpenCl => (AUTOTUNE_LOCAL_WORK_SIZES, AUTOTUNE_INNER_ITER_SIZES),
+        BackendKind::Cpu => (
+            std::slice::from_ref(&settings.local_work_size),
+            std::slice::from_ref(&settings.inner_iter_size),
+        ),
+    };
+    let mut best: Option<TunedSettings> = None;
+    for &local_work_size in local_work_sizes {
+        for &inner_iter_size in inner_iter_sizes {
+            let mut miner = match Miner::setup(MiningSettings {
+                local_work_size,
+                inner_iter_size,
+                ..settings.clone()
+            }) {
+                Ok(miner) => miner,
+                Err(err) => {
+                    log.warn(format!(
+                        "Skipping local_work_size {} and inner_iter_size {}: {}",
+                        local_work_size, inner_iter_size, err
+                    ));
+                    continue;
+                }
+            };
+            let mut last_hashrate = 0.0;
+            for intensity in intensities.clone() {
+                miner.set_intensity(intensity as i32);
+                let (hashrate, latency) = measure(
+                    &mut miner,
+                    target_latency * MEASURE_DISPATCHES,
+                    &measure_log,
+                )?;
+                measure_log.get_logs_and_clear();
+                if latency > target_latency && intensity != *intensities.start() {
+                    break;
+                }
+                if !matches!(&best, Some(best) if best.hashrate >= hashrate) {
+                    best = Some(TunedSettings {
+                        kernel_name: settings.kernel_name.clone(),
+                        intensity,
+                        local_work_size,
+                        inner_iter_size,
+                        hashrate,
+                        target_latency_ms: target_latency.as_millis() as u64,
+                    });
+                }
+                if hashrate < last_hashrate * MIN_HASHRATE_GAIN {
+                    break;
+                }
+                last_hashrate = hashrate;
+            }
+        }
+    }
+    Ok(best.ok_or(AutotuneError::NoWorkingSettings(
+        settings.gpu_indices.clone(),
+    ))?)
+}
+
+fn autotune_file() -> Option<PathBuf> {
+    Some(dirs::home_dir()?.join(FOLDER_DIR).join(AUTOTUNE_FILE))
+}
+
+/// Stored results by device name; none if the file is missing or invalid.
+fn load_tuned_settings(path: &Path) -> BTreeMap<String, TunedSettings> {
+    std::fs::read_to_string(path)
+        .ok()
+        .and_then(|json| serde_json::from_str(&json).ok())
+        .unwrap_or_default()
+}
+
+fn save_tuned_settings(
+    path: &Path,
+    tuned_settings: &BTreeMap<String, TunedSettings>,
+) -> Result<(), AutotuneError> {
+    let json = serde_json::to_string_pretty(tuned_settings).unwrap();
+    if let Some(folder) = path.parent() {
+        std::fs::create_dir_all(folder)
+            .map_err(|err| AutotuneError::Write(path.to_string_lossy().to_string(), err))?;
+    }
+    std::fs::write(path, json)
+        .map_err(|err| AutotuneError::Write(path.to_string_lossy().to_string(), err))
+}
+
+#[test]
+fn test_autotune_cpu() {
+    use crate::settings::test_config;
+
+    let settings = MiningSettings::from_config(&test_config()).unwrap();
+    let log = Log::new();
+    let tuned = autotune_device(&settings, Duration::from_millis(20), 6..=16, &log).unwrap();
+    assert!((6..=16).contains(&tuned.intensity));
+    assert!(tuned.hashrate > 0.0);
+    assert_eq!(tuned.target_latency_ms, 20);
+
+    let path = std::env::temp_dir().join(format!("lotus-miner-{}.autotune", std::process::id()));
+    let mut stored = BTreeMap::new();
+    // serde_json may round measured hashrates in the last digit
+    let tuned = TunedSettings {
+        hashrate: 1_000_000.0,
+        ..tuned
+    };
+    let device_name = Miner::device_name_of(&settings).unwrap();
+    assert_eq!(device_name, "CPU (2 threads)");
+    stored.insert(device_name, tuned);
+    save_tuned_settings(&path, &stored).unwrap();
+    assert_eq!(load_tuned_settings(&path), stored);
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,199 @@
+use std::time::{Duration, Instant};
+
+use eyre::Result;
+use serde::{Serialize, Serializer};
+
+use crate::{
+    block::LotusHeader,
+    miner::{BackendKind, Miner, MiningSettings, Work},
+    nonce::NonceAllocator,
+    ConfigSettings, Log,
+};
+
+/// What `lotus-miner bench` sweeps; every combination is run on every
+/// device.
+#[derive(Debug, Clone)]
+pub struct BenchSettings {
+    pub intensities: Vec<u32>,
+    pub local_work_sizes: Vec<i32>,
+    pub inner_iter_sizes: Vec<i32>,
+    pub kernel_names: Vec<String>,
+    /// How long each combination is measured for.
+    pub duration: Duration,
+    pub json: bool,
+}
+
+/// Measured speed of one combination on one device.
+#[derive(Debug, Clone, Serialize)]
+pub struct BenchResult {
+    pub device_idx: usize,
+    pub device_name: String,
+    pub kernel_name: String,
+    pub intensity: u32,
+    pub local_work_size: i32,
+    pub inner_iter_size: i32,
+    /// Hashes per second.
+    pub hashrate: f64,
+    /// Average duration of one `find_nonces` call.
+    #[serde(rename = "latency_ms", serialize_with = "serialize_millis")]
+    pub latency: Duration,
+}
+
+/// Runs `Miner::find_nonces` on a synthetic `Work` for every combination in
+/// `bench` on the devices selected in `config`. Combinations whose kernel
+/// fails to build are reported and skipped.
+pub fn run_bench(config: &ConfigSettings, bench: &BenchSettings) -> Result<Vec<BenchResult>> {
+    let base_settings = MiningSettings {
+        local_work_size: bench.local_work_sizes[0],
+        inner_iter_size: bench.inner_iter_sizes[0],
+        kernel_size: 1 << bench.intensities[0],
+        kernel_name: bench.kernel_names[0].clone(),
+        autotune_latency: None,
+        ..MiningSettings::from_config(config)?
+    };
+    let backend = base_settings.backend;
+    // The CPU backend has no kernel to tune, only the batch size
+    let (kernel_names, local_work_sizes, inner_iter_sizes) = match backend {
+        BackendKind::OpenCl => (
+            bench.kernel_names.as_slice(),
+            bench.local_work_sizes.as_slice(),
+            bench.inner_iter_sizes.as_slice(),
+        ),
+        BackendKind::Cpu => (
+            &bench.kernel_names[..1],
+            &bench.local_work_sizes[..1],
+            &bench.inner_iter_sizes[..1],
+        ),
+    };
+    let device_indices = match backend {
+        BackendKind::OpenCl => base_settings.gpu_indices.clone(),
+        BackendKind::Cpu => vec![0],
+    };
+    let log = Log::new();
+    let mut results = Vec::new();
+    for &device_idx in &device_indices {
+        for kernel_name in kernel_names {
+            for &local_work_size in local_work_sizes {
+                for &inner_iter_size in inner_iter_sizes {
+                    let settings = MiningSettings {
+                        local_work_size,
+                        inner_iter_size,
+                        kernel_name: kernel_name.clone(),
+                        gpu_indices: vec![device_idx],
+                        ..base_settings.clone()
+                    };
+                    let mut miner = match Miner::setup(settings) {
+                        Ok(miner) => miner,
+                        Err(err) => {
+                            eprintln!(
+                                "Skipping {} with local_work_size {} and inner_iter_size {} \
+                                 on device {}: {}",
+                                kernel_name, local_work_size, inner_iter_size, device_idx, err
+                            );
+                            continue;
+                        }
+                    };
+                    for &intensity in &bench.intensities {
+                        miner.set_intensity(intensity as i32);
+                        let (hashrate, latency) = measure(&mut miner, bench.duration, &log)?;
+                        // Candidates found along the way aren't interesting
+                        log.get_logs_and_clear();
+                        results.push(BenchResult {
+                            device_idx,
+                            device_name: miner.device_name(),
+                            kernel_name: kernel_name.clone(),
+                            intensity,
+                            local_work_size,
+                            inner_iter_size,
+                            hashrate,
+                            latency,
+                        });
+                    }
+                }
+            }
+        }
+    }
+    Ok(results)
+}
+
+/// Calls `find_nonces` for at least `duration` (after one warm-up call) and
+/// returns the hashrate and average latency.
+pub(crate) fn measure(miner: &mut Miner, duration: Duration, log: &Log) -> Result<(f64, Duration)> {
+    // A zero target is never met, so every call searches its whole range
+    let mut work = Work::from_header(LotusHeader::from_bytes(&[0x42; 160]), [0; 32]);
+    let nonces = NonceAllocator::new(work.header(), None);
+    let num_nonces = miner.num_nonces_per_search();
+    miner.find_nonces(&work, log)?;
+    let start = Instant::now();
+    let mut num_calls = 0u32;
+    loop {
+        let range = nonces
+            .allocate(num_nonces)
+            .expect("Bench exhausted the nonce space");
+        work.set_nonce_range(&range);
+        miner.find_nonces(&work, log)?;
+        num_calls += 1;
+        if start.elapsed() >= duration {
+            break;
+        }
+    }
+    let elapsed = start.elapsed();
+    let num_nonces = num_calls as u64 * num_nonces;
+    Ok((
+        num_nonces as f64 / elapsed.as_secs_f64(),
+        elapsed / num_calls,
+    ))
+}
+
+fn serialize_millis<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64() * 1000.0)
+}
+
+/// Formats results as an aligned table, one row per measurement.
+pub fn format_bench_table(results: &[BenchResult]) -> String {
+    let mut table = format!(
+        "{:<6} {:<32} {:<12} {:>9} {:>6} {:>6} {:>12} {:>12}\n",
+        "Device", "Name", "Kernel", "Intensity", "LWS", "Iter", "MH/s", "Latency ms"
+    );
+    for result in results {
+        table.push_str(&format!(
+            "{:<6} {:<32} {:<12} {:>9} {:>6} {:>6} {:>12.3} {:>12.3}\n",
+            result.device_idx,
+            result.device_name,
+            result.kernel_name,
+            result.intensity,
+            result.local_work_size,
+            result.inner_iter_size,
+            result.hashrate / 1_000_000.0,
+            result.latency.as_secs_f64() * 1000.0,
+        ));
+    }
+    table
+}
+
+pub fn format_bench_json(results: &[BenchResult]) -> String {
+    serde_json::to_string_pretty(results).unwrap()
+}
+
+#[test]
+fn test_bench_cpu() {
+    use crate::settings::test_config;
+
+    let bench = BenchSettings {
+        intensities: vec![8, 10],
+        local_work_sizes: vec![256],
+        inner_iter_sizes: vec![16],
+        kernel_names: vec!["lotus_og".to_string()],
+        duration: Duration::from_millis(10),
+        json: false,
+    };
+    let results = run_bench(&test_config(), &bench).unwrap();
+    assert_eq!(results.len(), 2);
+    assert_eq!(results[0].intensity, 8);
+    assert_eq!(results[1].intensity, 10);
+    assert!(results.iter().all(|result| result.hashrate > 0.0));
+    let table = format_bench_table(&results);
+    assert_eq!(table.lines().count(), 3);
+    let json: serde_json::Value = serde_json::from_str(&format_bench_json(&results)).unwrap();
+    assert_eq!(json[1]["intensity"], 10);
+}
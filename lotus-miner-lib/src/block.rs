@@ -1,11 +1,170 @@
-use std::convert::TryInto;
+use std::{
+    convert::TryInto,
+    ops::{Range, RangeFrom},
+};
 
+use bitcoincash_addr::{Address, HashType};
 use serde::Deserialize;
+use sha2::Digest;
+use thiserror::Error;
 
+use crate::{
+    difficulty::bits_to_target,
+    display_hash,
+    sha256::{lotus_hash, sha256d},
+};
+
+#[derive(Debug, Clone)]
 pub struct Block {
-    pub header: [u8; 160],
+    pub header: LotusHeader,
     pub body: Vec<u8>,
     pub target: [u8; 32],
+    /// Set for pool jobs; found nonces are submitted as shares.
+    pub job: Option<PoolJob>,
+}
+
+/// A Lotus block header, serialized as the 160 bytes that are hashed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LotusHeader {
+    pub prev_hash: [u8; 32],
+    pub bits: u32,
+    /// Unix timestamp; only the lower 48 bits are serialized.
+    pub time: u64,
+    pub reserved: u16,
+    pub nonce: u64,
+    pub version: u8,
+    /// Size of the serialized block; only the lower 56 bits are serialized.
+    pub size: u64,
+    pub height: u32,
+    pub epoch_hash: [u8; 32],
+    pub merkle_root: [u8; 32],
+    pub extended_metadata_hash: [u8; 32],
+}
+
+/// A Stratum job, with the extranonce2 we put into its coinbase.
+#[derive(Debug, Clone)]
+pub struct PoolJob {
+    pub job_id: String,
+    pub extranonce2: Vec<u8>,
+}
+
+/// A block whose coinbase leaves a gap for an extranonce, as handed out to
+/// Stratum miners. Each extranonce gives a different merkle root, so miners
+/// never search the same headers.
+#[derive(Debug, Clone)]
+pub struct SplitBlock {
+    /// Header with a null merkle root, as that depends on the extranonce.
+    pub header: LotusHeader,
+    pub target: [u8; 32],
+    /// Coinbase before and after the extranonce.
+    pub coinbase1: Vec<u8>,
+    pub coinbase2: Vec<u8>,
+    /// Hashes the coinbase's merkle leaf is folded with to get the root.
+    pub merkle_branch: Vec<[u8; 32]>,
+    pub extranonce_size: usize,
+    num_transactions: u64,
+    /// The transactions after the coinbase and the extended metadata.
+    body_tail: Vec<u8>,
+}
+
+#[derive(Debug, Error)]
+pub enum BlockError {
+    #[error("Invalid hex in {0}: {1}")]
+    InvalidHex(&'static str, hex::FromHexError),
+    #[error("Invalid length of {0}: expected {1} bytes, got {2}")]
+    InvalidLength(&'static str, usize, usize),
+    #[error("Invalid miner address {0:?}")]
+    InvalidAddress(String),
+    #[error("Coinbase value {0} too small to pay the miner fund {1}")]
+    CoinbaseTooSmall(u64, u64),
+    #[error("Block of {0} bytes is shorter than its header")]
+    BlockTooShort(usize),
+    #[error("Invalid nBits {0:08x}")]
+    InvalidBits(u32),
+    #[error("Target {1} doesn't match the header's nBits {0:08x}")]
+    TargetMismatch(u32, String),
+    #[error("Previous block hash is null")]
+    NullPrevHash,
+    #[error("Malformed block body: {0}")]
+    MalformedBody(String),
+    #[error("Merkle root {0} doesn't match the block's transactions, expected {1}")]
+    MerkleRootMismatch(String, String),
+    #[error("Extended metadata hash {0} doesn't match the block's metadata, expected {1}")]
+    MetadataHashMismatch(String, String),
+    #[error("Block has no coinbase")]
+    NoCoinbase,
+    #[error("Coinbase doesn't pay the miner address {0:?}")]
+    CoinbaseNotPayingMiner(String),
+}
+
+impl LotusHeader {
+    pub const SIZE: usize = 160;
+    // Where each field is serialized, all integers little endian
+    pub const PREV_HASH: Range<usize> = 0..32;
+    pub const BITS: Range<usize> = 32..36;
+    pub const TIME: Range<usize> = 36..42;
+    pub const RESERVED: Range<usize> = 42..44;
+    pub const NONCE: Range<usize> = 44..52;
+    pub const VERSION: usize = 52;
+    pub const BLOCK_SIZE: Range<usize> = 53..60;
+    pub const HEIGHT: Range<usize> = 60..64;
+    pub const EPOCH_HASH: Range<usize> = 64..96;
+    pub const MERKLE_ROOT: Range<usize> = 96..128;
+    pub const EXTENDED_METADATA_HASH: Range<usize> = 128..160;
+    /// Bytes the pow layer hashes directly, the nonce among them.
+    pub const POW_LAYER: Range<usize> = 32..52;
+    /// Bytes hashed by the tx layer, which doesn't depend on the nonce.
+    pub const TX_LAYER: RangeFrom<usize> = 52..;
+
+    pub fn parse(bytes: &[u8]) -> Result<Self, BlockError> {
+        let bytes: &[u8; 160] = bytes
+            .try_into()
+            .map_err(|_| BlockError::InvalidLength("header", Self::SIZE, bytes.len()))?;
+        Ok(Self::from_bytes(bytes))
+    }
+
+    pub fn from_bytes(bytes: &[u8; 160]) -> Self {
+        LotusHeader {
+            prev_hash: bytes[Self::PREV_HASH].try_into().unwrap(),
+            bits: u32::from_le_bytes(bytes[Self::BITS].try_into().unwrap()),
+            time: read_le_u64(&bytes[Self::TIME]),
+            reserved: u16::from_le_bytes(bytes[Self::RESERVED].try_into().unwrap()),
+            nonce: u64::from_le_bytes(bytes[Self::NONCE].try_into().unwrap()),
+            version: bytes[Self::VERSION],
+            size: read_le_u64(&bytes[Self::BLOCK_SIZE]),
+            height: u32::from_le_bytes(bytes[Self::HEIGHT].try_into().unwrap()),
+            epoch_hash: bytes[Self::EPOCH_HASH].try_into().unwrap(),
+            merkle_root: bytes[Self::MERKLE_ROOT].try_into().unwrap(),
+            extended_metadata_hash: bytes[Self::EXTENDED_METADATA_HASH].try_into().unwrap(),
+        }
+    }
+
+    pub fn to_bytes(self) -> [u8; 160] {
+        let mut bytes = [0u8; 160];
+        bytes[Self::PREV_HASH].copy_from_slice(&self.prev_hash);
+        bytes[Self::BITS].copy_from_slice(&self.bits.to_le_bytes());
+        bytes[Self::TIME].copy_from_slice(&self.time.to_le_bytes()[..6]);
+        bytes[Self::RESERVED].copy_from_slice(&self.reserved.to_le_bytes());
+        bytes[Self::NONCE].copy_from_slice(&self.nonce.to_le_bytes());
+        bytes[Self::VERSION] = self.version;
+        bytes[Self::BLOCK_SIZE].copy_from_slice(&self.size.to_le_bytes()[..7]);
+        bytes[Self::HEIGHT].copy_from_slice(&self.height.to_le_bytes());
+        bytes[Self::EPOCH_HASH].copy_from_slice(&self.epoch_hash);
+        bytes[Self::MERKLE_ROOT].copy_from_slice(&self.merkle_root);
+        bytes[Self::EXTENDED_METADATA_HASH].copy_from_slice(&self.extended_metadata_hash);
+        bytes
+    }
+
+    /// Block hash, little endian; see `lotus_hash`.
+    pub fn hash(&self) -> [u8; 32] {
+        lotus_hash(&self.to_bytes())
+    }
+}
+
+fn read_le_u64(bytes: &[u8]) -> u64 {
+    let mut padded = [0u8; 8];
+    padded[..bytes.len()].copy_from_slice(bytes);
+    u64::from_le_bytes(padded)
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -20,23 +179,843 @@ pub struct RawUnsolvedBlockAndTarget {
     pub target: String,
 }
 
-pub fn create_block(unsolved_block_and_target: &RawUnsolvedBlockAndTarget) -> Block {
-    let block = hex::decode(&unsolved_block_and_target.blockhex).unwrap();
-    // nBits (4 bytes)
-    let mut target: [u8; 32] = hex::decode(&unsolved_block_and_target.target)
-        .unwrap()
+#[derive(Deserialize, Debug, Clone)]
+pub struct GetBlockTemplateResponse {
+    pub result: Option<BlockTemplate>,
+    pub error: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct BlockTemplate {
+    pub previousblockhash: String,
+    #[serde(default)]
+    pub epochblockhash: Option<String>,
+    pub bits: String,
+    pub target: String,
+    pub curtime: u64,
+    pub height: u32,
+    pub coinbasevalue: u64,
+    pub transactions: Vec<TemplateTransaction>,
+    #[serde(default)]
+    pub coinbasetxn: Option<TemplateCoinbase>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct TemplateTransaction {
+    pub data: String,
+    pub txid: String,
+    pub hash: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct TemplateCoinbase {
+    #[serde(default)]
+    pub minerfund: Option<TemplateMinerFund>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct TemplateMinerFund {
+    pub addresses: Vec<String>,
+    pub minimumvalue: u64,
+}
+
+/// Parses a `getrawunsolvedblock` result, checking that it's consistent and
+/// its coinbase pays `miner_addr`.
+pub fn create_block(
+    unsolved_block_and_target: &RawUnsolvedBlockAndTarget,
+    miner_addr: &str,
+) -> Result<Block, BlockError> {
+    let block = decode_hex("blockhex", &unsolved_block_and_target.blockhex)?;
+    if block.len() < LotusHeader::SIZE {
+        return Err(BlockError::BlockTooShort(block.len()));
+    }
+    let (header, body) = block.split_at(LotusHeader::SIZE);
+    let block = Block {
+        header: LotusHeader::parse(header)?,
+        body: body.to_vec(),
+        target: decode_hash("target", &unsolved_block_and_target.target)?,
+        job: None,
+    };
+    check_header(&block.header, &block.target)?;
+    let BlockBody {
+        transactions,
+        metadata,
+    } = split_body(&block.body)?;
+    let metadata_hash = sha256d(metadata);
+    if metadata_hash != block.header.extended_metadata_hash {
+        return Err(BlockError::MetadataHashMismatch(
+            display_hash(&block.header.extended_metadata_hash),
+            display_hash(&metadata_hash),
+        ));
+    }
+    let leaves = transactions
+        .iter()
+        .map(|tx| merkle_leaf(&sha256d(tx.bytes), &lotus_txid(tx.bytes)))
+        .collect();
+    let merkle_root = merkle_root(leaves);
+    if merkle_root != block.header.merkle_root {
+        return Err(BlockError::MerkleRootMismatch(
+            display_hash(&block.header.merkle_root),
+            display_hash(&merkle_root),
+        ));
+    }
+    let coinbase = transactions.first().ok_or(BlockError::NoCoinbase)?;
+    let miner_script = address_script(miner_addr)?;
+    if !coinbase.output_scripts.contains(&&miner_script[..]) {
+        return Err(BlockError::CoinbaseNotPayingMiner(miner_addr.to_string()));
+    }
+    Ok(block)
+}
+
+/// Checks what the node decides about a header: it builds on a block and
+/// `target` is the one its nBits encode.
+fn check_header(header: &LotusHeader, target: &[u8; 32]) -> Result<(), BlockError> {
+    if header.prev_hash == [0; 32] {
+        return Err(BlockError::NullPrevHash);
+    }
+    let bits_target = bits_to_target(header.bits).ok_or(BlockError::InvalidBits(header.bits))?;
+    if &bits_target != target {
+        return Err(BlockError::TargetMismatch(
+            header.bits,
+            display_hash(target),
+        ));
+    }
+    Ok(())
+}
+
+/// Builds a block from a `getblocktemplate` result, with our own coinbase
+/// paying `miner_addr` and carrying `extra_nonce`.
+pub fn create_block_from_template(
+    template: &BlockTemplate,
+    miner_addr: &str,
+    extra_nonce: u64,
+) -> Result<Block, BlockError> {
+    let split_block = split_block_from_template(template, miner_addr, 8)?;
+    Ok(split_block.block(&extra_nonce.to_le_bytes()))
+}
+
+/// Like `create_block_from_template`, but leaves the last `extranonce_size`
+/// bytes of the coinbase's input script for Stratum miners to fill in.
+pub fn split_block_from_template(
+    template: &BlockTemplate,
+    miner_addr: &str,
+    extranonce_size: usize,
+) -> Result<SplitBlock, BlockError> {
+    let (coinbase1, coinbase2) = create_coinbase(template, miner_addr, extranonce_size)?;
+    let num_transactions = template.transactions.len() as u64 + 1;
+    // The coinbase's leaf isn't known yet, and isn't needed for the branch
+    let mut leaves = Vec::with_capacity(template.transactions.len() + 1);
+    leaves.push([0; 32]);
+    let mut body_tail = Vec::new();
+    for tx in &template.transactions {
+        let txhash = decode_hash("transaction hash", &tx.hash)?;
+        let txid = decode_hash("transaction txid", &tx.txid)?;
+        leaves.push(merkle_leaf(&txhash, &txid));
+        body_tail.extend_from_slice(&decode_hex("transaction data", &tx.data)?);
+    }
+    // Empty extended metadata, serialized after the transactions
+    write_var_int(&mut body_tail, 0);
+    let mut num_transactions_bytes = Vec::new();
+    write_var_int(&mut num_transactions_bytes, num_transactions);
+    let body_len = num_transactions_bytes.len()
+        + coinbase1.len()
+        + extranonce_size
+        + coinbase2.len()
+        + body_tail.len();
+
+    let bits = decode_hex("bits", &template.bits)?;
+    let bits: [u8; 4] = bits
         .try_into()
-        .unwrap();
-    target.reverse();
-    Block {
-        header: block[0..160].try_into().unwrap(),
-        body: block[160..].try_into().unwrap(),
+        .map_err(|bits: Vec<u8>| BlockError::InvalidLength("bits", 4, bits.len()))?;
+    let epoch_hash = match &template.epochblockhash {
+        Some(epochblockhash) => decode_hash("epochblockhash", epochblockhash)?,
+        None => [0; 32],
+    };
+    let header = LotusHeader {
+        prev_hash: decode_hash("previousblockhash", &template.previousblockhash)?,
+        bits: u32::from_be_bytes(bits),
+        time: template.curtime,
+        reserved: 0,
+        nonce: 0,
+        version: 1,
+        size: (LotusHeader::SIZE + body_len) as u64,
+        height: template.height,
+        epoch_hash,
+        merkle_root: [0; 32],
+        // Hash of the empty extended metadata
+        extended_metadata_hash: sha256d(&[0]),
+    };
+
+    let target = decode_hash("target", &template.target)?;
+    check_header(&header, &target)?;
+    Ok(SplitBlock {
+        header,
         target,
+        coinbase1,
+        coinbase2,
+        merkle_branch: merkle_branch(leaves),
+        extranonce_size,
+        num_transactions,
+        body_tail,
+    })
+}
+
+/// Our coinbase paying `miner_addr`, split where its input script ends with
+/// a push of `extranonce_size` bytes.
+fn create_coinbase(
+    template: &BlockTemplate,
+    miner_addr: &str,
+    extranonce_size: usize,
+) -> Result<(Vec<u8>, Vec<u8>), BlockError> {
+    let mut outputs: Vec<(u64, Vec<u8>)> = Vec::new();
+    // Lotus txids don't commit to input scripts, so the height goes into an
+    // OP_RETURN output to make the coinbase txid unique.
+    let mut height_script = vec![0x6a];
+    push_script_num(&mut height_script, template.height as i64);
+    outputs.push((0, height_script));
+    let mut miner_value = template.coinbasevalue;
+    if let Some(minerfund) = template
+        .coinbasetxn
+        .as_ref()
+        .and_then(|coinbasetxn| coinbasetxn.minerfund.as_ref())
+    {
+        if !minerfund.addresses.is_empty() {
+            let num_addresses = minerfund.addresses.len() as u64;
+            let value = minerfund.minimumvalue.div_ceil(num_addresses);
+            let total_value = value * num_addresses;
+            miner_value =
+                miner_value
+                    .checked_sub(total_value)
+                    .ok_or(BlockError::CoinbaseTooSmall(
+                        template.coinbasevalue,
+                        total_value,
+                    ))?;
+            for address in &minerfund.addresses {
+                outputs.push((value, address_script(address)?));
+            }
+        }
+    }
+    outputs.insert(1, (miner_value, address_script(miner_addr)?));
+
+    let mut script_sig = Vec::new();
+    push_script_num(&mut script_sig, template.height as i64);
+    // Push opcode of the extranonce, which follows in the gap
+    assert!(extranonce_size < 0x4c);
+    script_sig.push(extranonce_size as u8);
+
+    let mut coinbase1 = Vec::new();
+    coinbase1.extend_from_slice(&1i32.to_le_bytes()); // version
+    write_var_int(&mut coinbase1, 1);
+    coinbase1.extend_from_slice(&[0; 32]);
+    coinbase1.extend_from_slice(&u32::MAX.to_le_bytes());
+    write_var_int(&mut coinbase1, (script_sig.len() + extranonce_size) as u64);
+    coinbase1.extend_from_slice(&script_sig);
+    let mut coinbase2 = Vec::new();
+    coinbase2.extend_from_slice(&u32::MAX.to_le_bytes()); // sequence
+    write_var_int(&mut coinbase2, outputs.len() as u64);
+    for (value, script) in outputs {
+        coinbase2.extend_from_slice(&value.to_le_bytes());
+        write_var_int(&mut coinbase2, script.len() as u64);
+        coinbase2.extend_from_slice(&script);
+    }
+    coinbase2.extend_from_slice(&0u32.to_le_bytes()); // locktime
+    Ok((coinbase1, coinbase2))
+}
+
+/// Output script paying to a Lotus XAddress, CashAddr or legacy address.
+pub fn address_script(address: &str) -> Result<Vec<u8>, BlockError> {
+    if address.starts_with(XADDRESS_TOKEN) {
+        return xaddress_script(address)
+            .ok_or_else(|| BlockError::InvalidAddress(address.to_string()));
+    }
+    let decoded =
+        Address::decode(address).map_err(|_| BlockError::InvalidAddress(address.to_string()))?;
+    if decoded.body.len() != 20 {
+        return Err(BlockError::InvalidAddress(address.to_string()));
+    }
+    let mut script = Vec::with_capacity(25);
+    match decoded.hash_type {
+        HashType::Key => {
+            script.extend_from_slice(&[0x76, 0xa9, 0x14]);
+            script.extend_from_slice(&decoded.body);
+            script.extend_from_slice(&[0x88, 0xac]);
+        }
+        HashType::Script => {
+            script.extend_from_slice(&[0xa9, 0x14]);
+            script.extend_from_slice(&decoded.body);
+            script.push(0x87);
+        }
+    }
+    Ok(script)
+}
+
+const XADDRESS_TOKEN: &str = "lotus";
+/// Network characters following the token: mainnet, testnet and regtest.
+const XADDRESS_NETWORKS: [char; 3] = ['_', 'T', 'R'];
+/// Type byte of XAddresses whose payload is the output script itself.
+const XADDRESS_TYPE_SCRIPT_PUBKEY: u8 = 0;
+
+/// Decodes an XAddress, the token and network character followed by base58
+/// of the type byte, payload and checksum. The checksum is the first 4 bytes
+/// of sha256 of everything before it, token and network character included.
+fn xaddress_script(address: &str) -> Option<Vec<u8>> {
+    let rest = address.strip_prefix(XADDRESS_TOKEN)?;
+    let network = rest.chars().next()?;
+    if !XADDRESS_NETWORKS.contains(&network) {
+        return None;
+    }
+    let decoded = decode_base58(&rest[network.len_utf8()..])?;
+    if decoded.len() < 5 {
+        return None;
+    }
+    let (content, checksum) = decoded.split_at(decoded.len() - 4);
+    let checksum_hash = sha2::Sha256::new()
+        .chain(&address.as_bytes()[..XADDRESS_TOKEN.len() + network.len_utf8()])
+        .chain(content)
+        .finalize();
+    if checksum != &checksum_hash[..4] || content[0] != XADDRESS_TYPE_SCRIPT_PUBKEY {
+        return None;
+    }
+    Some(content[1..].to_vec())
+}
+
+fn decode_base58(base58: &str) -> Option<Vec<u8>> {
+    const ALPHABET: &[u8] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+    // Big endian digits in base 256, built up one base58 digit at a time
+    let mut bytes: Vec<u8> = Vec::new();
+    for c in base58.bytes() {
+        let mut carry = ALPHABET.iter().position(|&digit| digit == c)? as u32;
+        for byte in bytes.iter_mut().rev() {
+            carry += *byte as u32 * 58;
+            *byte = carry as u8;
+            carry >>= 8;
+        }
+        while carry > 0 {
+            bytes.insert(0, carry as u8);
+            carry >>= 8;
+        }
+    }
+    // Each leading '1' encodes a leading zero byte
+    let num_zeros = base58.bytes().take_while(|&c| c == b'1').count();
+    let mut decoded = vec![0; num_zeros];
+    decoded.extend_from_slice(&bytes);
+    Some(decoded)
+}
+
+/// A transaction of a block body, with what the miner checks of it.
+struct BodyTransaction<'a> {
+    bytes: &'a [u8],
+    output_scripts: Vec<&'a [u8]>,
+}
+
+/// A block body split into its transactions and the serialized extended
+/// metadata following them.
+struct BlockBody<'a> {
+    transactions: Vec<BodyTransaction<'a>>,
+    metadata: &'a [u8],
+}
+
+/// Splits a block body into its transactions and extended metadata, failing
+/// on anything truncated or left over.
+fn split_body(body: &[u8]) -> Result<BlockBody<'_>, BlockError> {
+    let mut pos = 0;
+    let num_txs = read_var_int(body, &mut pos)
+        .ok_or_else(|| BlockError::MalformedBody("missing transaction count".to_string()))?;
+    let mut transactions = Vec::new();
+    for tx_idx in 0..num_txs {
+        let tx = parse_transaction(&body[pos..]).ok_or_else(|| {
+            BlockError::MalformedBody(format!("transaction {} is truncated", tx_idx))
+        })?;
+        pos += tx.bytes.len();
+        transactions.push(tx);
+    }
+    let metadata_start = pos;
+    let num_fields = read_var_int(body, &mut pos)
+        .ok_or_else(|| BlockError::MalformedBody("missing extended metadata".to_string()))?;
+    for field_idx in 0..num_fields {
+        pos = parse_metadata_field(body, pos).ok_or_else(|| {
+            BlockError::MalformedBody(format!("metadata field {} is truncated", field_idx))
+        })?;
+    }
+    if pos != body.len() {
+        return Err(BlockError::MalformedBody(format!(
+            "{} bytes after the extended metadata",
+            body.len() - pos
+        )));
+    }
+    Ok(BlockBody {
+        transactions,
+        metadata: &body[metadata_start..],
+    })
+}
+
+/// Skips the metadata field (u32 type, then its data) at `pos`, returning
+/// where it ends; `None` if truncated.
+fn parse_metadata_field(body: &[u8], mut pos: usize) -> Option<usize> {
+    pos += 4;
+    let data_len = read_var_int(body, &mut pos)?;
+    let end = pos.checked_add(data_len.try_into().ok()?)?;
+    if end > body.len() {
+        return None;
+    }
+    Some(end)
+}
+
+/// Parses the transaction at the start of `bytes`; `None` if truncated.
+fn parse_transaction(bytes: &[u8]) -> Option<BodyTransaction<'_>> {
+    let mut pos = 4;
+    let num_inputs = read_var_int(bytes, &mut pos)?;
+    for _ in 0..num_inputs {
+        pos += 36;
+        let script_len = read_var_int(bytes, &mut pos)?;
+        pos = pos.checked_add(script_len.try_into().ok()?)? + 4;
+        if pos > bytes.len() {
+            return None;
+        }
+    }
+    let num_outputs = read_var_int(bytes, &mut pos)?;
+    let mut output_scripts = Vec::new();
+    for _ in 0..num_outputs {
+        pos += 8;
+        let script_len = read_var_int(bytes, &mut pos)?;
+        let script_end = pos.checked_add(script_len.try_into().ok()?)?;
+        output_scripts.push(bytes.get(pos..script_end)?);
+        pos = script_end;
+    }
+    pos += 4; // locktime
+    Some(BodyTransaction {
+        bytes: bytes.get(..pos)?,
+        output_scripts,
+    })
+}
+
+/// Lotus txid: hash of the transaction with all input scripts left empty.
+/// `tx` must be well-formed.
+fn lotus_txid(tx: &[u8]) -> [u8; 32] {
+    let mut stripped = Vec::with_capacity(tx.len());
+    let mut pos = 4;
+    stripped.extend_from_slice(&tx[..pos]);
+    let num_inputs = read_var_int(tx, &mut pos).unwrap();
+    write_var_int(&mut stripped, num_inputs);
+    for _ in 0..num_inputs {
+        stripped.extend_from_slice(&tx[pos..pos + 36]);
+        pos += 36;
+        let script_len = read_var_int(tx, &mut pos).unwrap() as usize;
+        pos += script_len;
+        write_var_int(&mut stripped, 0);
+        stripped.extend_from_slice(&tx[pos..pos + 4]);
+        pos += 4;
+    }
+    stripped.extend_from_slice(&tx[pos..]);
+    sha256d(&stripped)
+}
+
+fn merkle_leaf(txhash: &[u8; 32], txid: &[u8; 32]) -> [u8; 32] {
+    let mut leaf = [0u8; 64];
+    leaf[..32].copy_from_slice(txhash);
+    leaf[32..].copy_from_slice(txid);
+    sha256d(&leaf)
+}
+
+/// Merkle root where odd layers are padded with the null hash.
+fn merkle_root(mut hashes: Vec<[u8; 32]>) -> [u8; 32] {
+    if hashes.is_empty() {
+        return [0; 32];
+    }
+    while hashes.len() > 1 {
+        if hashes.len() % 2 == 1 {
+            hashes.push([0; 32]);
+        }
+        hashes = hashes
+            .chunks(2)
+            .map(|pair| merkle_node(&pair[0], &pair[1]))
+            .collect();
+    }
+    hashes[0]
+}
+
+fn merkle_node(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut concat = [0u8; 64];
+    concat[..32].copy_from_slice(left);
+    concat[32..].copy_from_slice(right);
+    sha256d(&concat)
+}
+
+/// Hashes to fold the first of `hashes` with, layer by layer, to get their
+/// merkle root. The first hash itself doesn't matter.
+fn merkle_branch(mut hashes: Vec<[u8; 32]>) -> Vec<[u8; 32]> {
+    let mut branch = Vec::new();
+    while hashes.len() > 1 {
+        if hashes.len() % 2 == 1 {
+            hashes.push([0; 32]);
+        }
+        branch.push(hashes[1]);
+        hashes = hashes
+            .chunks(2)
+            .map(|pair| merkle_node(&pair[0], &pair[1]))
+            .collect();
+    }
+    branch
+}
+
+fn fold_merkle_branch(leaf: [u8; 32], merkle_branch: &[[u8; 32]]) -> [u8; 32] {
+    merkle_branch
+        .iter()
+        .fold(leaf, |hash, branch_hash| merkle_node(&hash, branch_hash))
+}
+
+/// Merkle root of the block with `coinbase`, whose leaf is folded with
+/// `merkle_branch`; `None` if the coinbase isn't a well-formed transaction.
+pub fn coinbase_merkle_root(coinbase: &[u8], merkle_branch: &[[u8; 32]]) -> Option<[u8; 32]> {
+    let tx = parse_transaction(coinbase)?;
+    if tx.bytes.len() != coinbase.len() {
+        return None;
+    }
+    let leaf = merkle_leaf(&sha256d(coinbase), &lotus_txid(coinbase));
+    Some(fold_merkle_branch(leaf, merkle_branch))
+}
+
+fn push_script_num(script: &mut Vec<u8>, num: i64) {
+    match num {
+        0 => script.push(0x00),
+        1..=16 => script.push(0x50 + num as u8),
+        _ => {
+            let negative = num < 0;
+            let mut abs = num.unsigned_abs();
+            let mut bytes = Vec::new();
+            while abs > 0 {
+                bytes.push(abs as u8);
+                abs >>= 8;
+            }
+            if bytes.last().unwrap() & 0x80 != 0 {
+                bytes.push(if negative { 0x80 } else { 0x00 });
+            } else if negative {
+                *bytes.last_mut().unwrap() |= 0x80;
+            }
+            push_bytes(script, &bytes);
+        }
+    }
+}
+
+fn push_bytes(script: &mut Vec<u8>, bytes: &[u8]) {
+    // Only direct pushes are needed for the short pushes we build
+    assert!(bytes.len() < 0x4c);
+    script.push(bytes.len() as u8);
+    script.extend_from_slice(bytes);
+}
+
+fn write_var_int(vec: &mut Vec<u8>, num: u64) {
+    match num {
+        0..=0xfc => vec.push(num as u8),
+        0xfd..=0xffff => {
+            vec.push(0xfd);
+            vec.extend_from_slice(&(num as u16).to_le_bytes());
+        }
+        0x10000..=0xffff_ffff => {
+            vec.push(0xfe);
+            vec.extend_from_slice(&(num as u32).to_le_bytes());
+        }
+        _ => {
+            vec.push(0xff);
+            vec.extend_from_slice(&num.to_le_bytes());
+        }
+    }
+}
+
+fn read_var_int(slice: &[u8], pos: &mut usize) -> Option<u64> {
+    let first = *slice.get(*pos)?;
+    *pos += 1;
+    let len = match first {
+        0xfd => 2,
+        0xfe => 4,
+        0xff => 8,
+        _ => return Some(first as u64),
+    };
+    let mut bytes = [0u8; 8];
+    bytes[..len].copy_from_slice(slice.get(*pos..*pos + len)?);
+    *pos += len;
+    Some(u64::from_le_bytes(bytes))
+}
+
+fn decode_hex(name: &'static str, hex_str: &str) -> Result<Vec<u8>, BlockError> {
+    hex::decode(hex_str).map_err(|err| BlockError::InvalidHex(name, err))
+}
+
+/// Decodes a hash displayed in big endian into internal byte order.
+fn decode_hash(name: &'static str, hex_str: &str) -> Result<[u8; 32], BlockError> {
+    let hash = decode_hex(name, hex_str)?;
+    let mut hash: [u8; 32] = hash
+        .try_into()
+        .map_err(|hash: Vec<u8>| BlockError::InvalidLength(name, 32, hash.len()))?;
+    hash.reverse();
+    Ok(hash)
+}
+
+impl BlockTemplate {
+    /// Whether `other` is for the same block, apart from `curtime`, which
+    /// the node updates on every call.
+    pub fn same_block(&self, other: &BlockTemplate) -> bool {
+        let txids = |template: &BlockTemplate| {
+            template
+                .transactions
+                .iter()
+                .map(|tx| tx.txid.clone())
+                .collect::<Vec<_>>()
+        };
+        self.previousblockhash == other.previousblockhash
+            && self.bits == other.bits
+            && self.height == other.height
+            && self.coinbasevalue == other.coinbasevalue
+            && txids(self) == txids(other)
     }
 }
 
 impl Block {
     pub fn prev_hash(&self) -> &[u8] {
-        &self.header[..32]
+        &self.header.prev_hash
+    }
+
+    /// Header followed by the body, as submitted to the node.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut serialized = self.header.to_bytes().to_vec();
+        serialized.extend_from_slice(&self.body);
+        serialized
+    }
+}
+
+impl SplitBlock {
+    /// The coinbase with `extranonce` filled in.
+    pub fn coinbase(&self, extranonce: &[u8]) -> Vec<u8> {
+        assert_eq!(extranonce.len(), self.extranonce_size);
+        [&self.coinbase1[..], extranonce, &self.coinbase2].concat()
+    }
+
+    /// The full block with `extranonce` in its coinbase.
+    pub fn block(&self, extranonce: &[u8]) -> Block {
+        let coinbase = self.coinbase(extranonce);
+        let mut header = self.header;
+        header.merkle_root = coinbase_merkle_root(&coinbase, &self.merkle_branch)
+            .expect("our own coinbase is well-formed");
+        let mut body = Vec::new();
+        write_var_int(&mut body, self.num_transactions);
+        body.extend_from_slice(&coinbase);
+        body.extend_from_slice(&self.body_tail);
+        Block {
+            header,
+            body,
+            target: self.target,
+            job: None,
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn test_template() -> BlockTemplate {
+    BlockTemplate {
+        previousblockhash: format!("{:064x}", 0xabcdu32),
+        epochblockhash: Some(format!("{:064x}", 0x1234u32)),
+        bits: "1d00ffff".to_string(),
+        target: format!("00000000ffff{}", "0".repeat(52)),
+        curtime: 1_624_000_000,
+        height: 1000,
+        coinbasevalue: 260_000_000,
+        transactions: vec![],
+        coinbasetxn: None,
     }
 }
+
+#[test]
+fn test_script_num() {
+    let mut script = Vec::new();
+    push_script_num(&mut script, 16);
+    push_script_num(&mut script, 128);
+    push_script_num(&mut script, 1000);
+    assert_eq!(script, [0x60, 0x02, 0x80, 0x00, 0x02, 0xe8, 0x03]);
+}
+
+#[test]
+fn test_block_from_template() {
+    let template = test_template();
+    let miner_addr = "bitcoincash:qpm2qsznhks23z7629mms6s4cwef74vcwvy22gdx6a";
+    let block = create_block_from_template(&template, miner_addr, 7).unwrap();
+    assert_eq!(block.prev_hash()[0], 0xcd);
+    let header = block.header.to_bytes();
+    assert_eq!(&header[32..36], &[0xff, 0xff, 0x00, 0x1d]);
+    assert_eq!(&header[60..64], &1000u32.to_le_bytes());
+    assert_eq!(header[64], 0x34);
+    assert_eq!(block.header.size as usize, block.serialize().len());
+    // single transaction: merkle root is its leaf
+    assert_eq!(block.body.last(), Some(&0));
+    let coinbase = &block.body[1..block.body.len() - 1];
+    let leaf = merkle_leaf(&sha256d(coinbase), &lotus_txid(coinbase));
+    assert_eq!(block.header.merkle_root, leaf);
+    let miner_script = address_script(miner_addr).unwrap();
+    assert!(coinbase
+        .windows(miner_script.len())
+        .any(|window| window == &miner_script[..]));
+    assert_eq!(block.target[27], 0xff);
+    assert_eq!(block.target[31], 0);
+
+    // a different extra nonce changes the txhash but not the txid
+    let other = create_block_from_template(&template, miner_addr, 8).unwrap();
+    let other_coinbase = &other.body[1..other.body.len() - 1];
+    assert_eq!(lotus_txid(coinbase), lotus_txid(other_coinbase));
+    assert_ne!(block.header.merkle_root, other.header.merkle_root);
+}
+
+#[test]
+fn test_merkle_branch() {
+    for num_leaves in 1..=7u8 {
+        let leaves = (0..num_leaves).map(|idx| [idx; 32]).collect::<Vec<_>>();
+        let branch = merkle_branch(leaves.clone());
+        assert_eq!(fold_merkle_branch(leaves[0], &branch), merkle_root(leaves));
+    }
+
+    // Any extranonce gives a consistent block
+    let template = BlockTemplate {
+        transactions: vec![TemplateTransaction {
+            data: "00".to_string(),
+            txid: format!("{:064x}", 1),
+            hash: format!("{:064x}", 2),
+        }],
+        ..test_template()
+    };
+    let miner_addr = "bitcoincash:qpm2qsznhks23z7629mms6s4cwef74vcwvy22gdx6a";
+    let split_block = split_block_from_template(&template, miner_addr, 6).unwrap();
+    let block = split_block.block(&[1, 2, 3, 4, 5, 6]);
+    let coinbase = split_block.coinbase(&[1, 2, 3, 4, 5, 6]);
+    assert_eq!(block.header.size as usize, block.serialize().len());
+    assert_eq!(&block.body[1..coinbase.len() + 1], &coinbase[..]);
+    let leaves = vec![
+        merkle_leaf(&sha256d(&coinbase), &lotus_txid(&coinbase)),
+        merkle_leaf(
+            &decode_hash("", &format!("{:064x}", 2)).unwrap(),
+            &decode_hash("", &format!("{:064x}", 1)).unwrap(),
+        ),
+    ];
+    assert_eq!(block.header.merkle_root, merkle_root(leaves));
+    assert_eq!(
+        coinbase_merkle_root(&coinbase[1..], &split_block.merkle_branch),
+        None
+    );
+}
+
+#[test]
+fn test_lotus_header_round_trip() {
+    let mut bytes = [0u8; 160];
+    for (idx, byte) in bytes.iter_mut().enumerate() {
+        *byte = idx as u8;
+    }
+    let header = LotusHeader::parse(&bytes).unwrap();
+    assert_eq!(header.prev_hash[31], 31);
+    assert_eq!(header.bits, 0x23222120);
+    assert_eq!(header.time, 0x2928_2726_2524);
+    assert_eq!(header.reserved, 0x2b2a);
+    assert_eq!(header.nonce, 0x3332_3130_2f2e_2d2c);
+    assert_eq!(header.version, 52);
+    assert_eq!(header.size, 0x003b_3a39_3837_3635);
+    assert_eq!(header.height, 0x3f3e3d3c);
+    assert_eq!(header.epoch_hash[0], 64);
+    assert_eq!(header.merkle_root[0], 96);
+    assert_eq!(header.extended_metadata_hash[31], 159);
+    assert_eq!(header.to_bytes(), bytes);
+    assert_eq!(header.hash(), lotus_hash(&bytes));
+    assert!(matches!(
+        LotusHeader::parse(&bytes[..159]),
+        Err(BlockError::InvalidLength("header", 160, 159))
+    ));
+}
+
+#[test]
+fn test_create_block_checks() {
+    let miner_addr = "bitcoincash:qpm2qsznhks23z7629mms6s4cwef74vcwvy22gdx6a";
+    let template_block = create_block_from_template(&test_template(), miner_addr, 7).unwrap();
+    let unsolved_block = |block: &Block| RawUnsolvedBlockAndTarget {
+        blockhex: hex::encode(block.serialize()),
+        target: display_hash(&block.target),
+    };
+    let block = create_block(&unsolved_block(&template_block), miner_addr).unwrap();
+    assert_eq!(block.header, template_block.header);
+    assert_eq!(block.body, template_block.body);
+    assert_eq!(block.target, template_block.target);
+
+    let check = |unsolved: RawUnsolvedBlockAndTarget, miner_addr: &str| {
+        create_block(&unsolved, miner_addr).unwrap_err()
+    };
+    let mut unsolved = unsolved_block(&template_block);
+    unsolved.blockhex.truncate(300);
+    assert!(matches!(
+        check(unsolved, miner_addr),
+        BlockError::BlockTooShort(150)
+    ));
+    let mut unsolved = unsolved_block(&template_block);
+    unsolved.target.truncate(62);
+    let err = check(unsolved, miner_addr);
+    assert!(matches!(err, BlockError::InvalidLength("target", 32, 31)));
+    let mut block = template_block.clone();
+    block.target[20] = 1;
+    let err = check(unsolved_block(&block), miner_addr);
+    assert!(matches!(err, BlockError::TargetMismatch(0x1d00ffff, _)));
+    let mut block = template_block.clone();
+    block.header.prev_hash = [0; 32];
+    assert!(matches!(
+        check(unsolved_block(&block), miner_addr),
+        BlockError::NullPrevHash
+    ));
+    let mut block = template_block.clone();
+    block.body.pop();
+    let err = check(unsolved_block(&block), miner_addr);
+    assert!(matches!(err, BlockError::MalformedBody(_)));
+    let mut block = template_block.clone();
+    block.body.push(0);
+    let err = check(unsolved_block(&block), miner_addr);
+    assert!(matches!(err, BlockError::MalformedBody(_)));
+    // one metadata field of type 1 carrying 0xab
+    let mut block = template_block.clone();
+    block.body.pop();
+    block.body.extend_from_slice(&[1, 1, 0, 0, 0, 1, 0xab]);
+    let err = check(unsolved_block(&block), miner_addr);
+    assert!(matches!(err, BlockError::MetadataHashMismatch(_, _)));
+    block.header.extended_metadata_hash = sha256d(&[1, 1, 0, 0, 0, 1, 0xab]);
+    create_block(&unsolved_block(&block), miner_addr).unwrap();
+    let mut block = template_block.clone();
+    block.header.merkle_root[0] ^= 1;
+    let err = check(unsolved_block(&block), miner_addr);
+    assert!(matches!(err, BlockError::MerkleRootMismatch(_, _)));
+    let other_addr = "bitcoincash:qr95sy3j9xwd2ap32xkykttr4cvcu7as4y0qverfuy";
+    let err = check(unsolved_block(&template_block), other_addr);
+    assert!(matches!(err, BlockError::CoinbaseNotPayingMiner(_)));
+}
+
+#[test]
+fn test_xaddress_script() {
+    // Same key hash as bitcoincash:qpm2qsznhks23z7629mms6s4cwef74vcwvy22gdx6a
+    let p2pkh = hex::decode("76a91476a04053bda0a88bda5177b86a15c3b29f55987388ac").unwrap();
+    let xaddress = "lotus_16PSJLk9W86KAZp26x3uM176w6N9vUU8YNQQnQTHN";
+    assert_eq!(address_script(xaddress).unwrap(), p2pkh);
+    let testnet_xaddress = "lotusT16PSJLk9W86KAZp26x3uM176w6N9vUU8YNQLdWh4b";
+    assert_eq!(address_script(testnet_xaddress).unwrap(), p2pkh);
+    for invalid in [
+        // wrong checksum
+        "lotus_16PSJLk9W86KAZp26x3uM176w6N9vUU8YNQQnQTHM",
+        // checksum of another network
+        "lotusR16PSJLk9W86KAZp26x3uM176w6N9vUU8YNQQnQTHN",
+        // not base58
+        "lotus_16PSJLk9W86KAZp26x3uM176w6N9vUU8YNQQnQTH0",
+        "lotus_",
+    ] {
+        assert!(matches!(
+            address_script(invalid),
+            Err(BlockError::InvalidAddress(_))
+        ));
+    }
+
+    // Blocks from the node pay the same script whichever way it's addressed
+    let miner_addr = "bitcoincash:qpm2qsznhks23z7629mms6s4cwef74vcwvy22gdx6a";
+    let block = create_block_from_template(&test_template(), miner_addr, 7).unwrap();
+    let unsolved = RawUnsolvedBlockAndTarget {
+        blockhex: hex::encode(block.serialize()),
+        target: display_hash(&block.target),
+    };
+    create_block(&unsolved, xaddress).unwrap();
+}
@@ -0,0 +1,144 @@
+use eyre::Result;
+use std::convert::TryInto;
+
+use crate::{
+    difficulty::hash_below_target,
+    miner::{check_candidate, Candidate, MiningBackend, MiningSettings, Work},
+    sha256::LotusMidstate,
+    Log,
+};
+
+/// Pure-Rust backend hashing on `cpu_threads` OS threads; needs no OpenCL.
+pub struct CpuBackend {
+    num_threads: usize,
+    /// Work of the enqueued search; it only runs in `finish_search`.
+    pending: Option<Work>,
+}
+
+impl CpuBackend {
+    pub fn setup(settings: &MiningSettings) -> Result<Self> {
+        let num_threads = num_threads(settings);
+        println!("Mining on CPU with {} threads", num_threads);
+        Ok(CpuBackend {
+            num_threads,
+            pending: None,
+        })
+    }
+
+    pub fn device_name_of(settings: &MiningSettings) -> String {
+        device_name(num_threads(settings))
+    }
+}
+
+/// Threads `cpu_threads` asks for; 0 means one per core.
+fn num_threads(settings: &MiningSettings) -> usize {
+    match settings.cpu_threads {
+        0 => std::thread::available_parallelism()
+            .map(|num_threads| num_threads.get())
+            .unwrap_or(1),
+        num_threads => num_threads,
+    }
+}
+
+fn device_name(num_threads: usize) -> String {
+    format!("CPU ({} threads)", num_threads)
+}
+
+impl MiningBackend for CpuBackend {
+    fn device_name(&self) -> String {
+        device_name(self.num_threads)
+    }
+
+    fn num_nonces_per_search(&self, settings: &MiningSettings) -> u64 {
+        settings.kernel_size as u64
+    }
+
+    fn enqueue_search(
+        &mut self,
+        work: &Work,
+        _settings: &MiningSettings,
+        _log: &Log,
+    ) -> Result<()> {
+        assert!(self.pending.is_none(), "CPU search already enqueued");
+        self.pending = Some(*work);
+        Ok(())
+    }
+
+    fn finish_search(&mut self, settings: &MiningSettings, log: &Log) -> Result<Vec<Candidate>> {
+        let work = &self
+            .pending
+            .take()
+            .expect("finish_search called without a search in flight");
+        let num_nonces = self.num_nonces_per_search(settings);
+        let base = u64::from(work.nonce_base);
+        let midstate = LotusMidstate::new(&work.header_bytes());
+        let target = *work.target();
+        let num_threads = self.num_threads as u64;
+        let chunk_size = num_nonces.div_ceil(num_threads);
+        let threads = (0..num_threads)
+            .map(|thread_idx| {
+                let start = thread_idx * chunk_size;
+                let end = num_nonces.min(start + chunk_size);
+                // `LotusMidstate` takes the nonce as the little endian word
+                std::thread::spawn(move || {
+                    (start..end)
+                        .map(|offset| (base + offset).try_into().unwrap())
+                        .filter(|&nonce: &u32| {
+                            hash_below_target(&midstate.hash(nonce.swap_bytes()), &target)
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut result = Vec::new();
+        for thread in threads {
+            let nonces = thread.join().expect("CPU mining thread panicked");
+            for nonce in nonces {
+                let candidate = check_candidate(work, nonce, log);
+                if !candidate.meets_target {
+                    log.bug(
+                        "BUG: CPU midstate hash disagrees with lotus_hash. Contact the \
+                               developers.",
+                    );
+                }
+                result.push(candidate);
+            }
+        }
+        Ok(result)
+    }
+}
+
+#[test]
+fn test_cpu_backend_finds_nonce() {
+    use crate::{block::LotusHeader, miner::BackendKind};
+    let settings = MiningSettings {
+        local_work_size: 256,
+        inner_iter_size: 16,
+        kernel_size: 1 << 12,
+        kernel_name: "lotus_og".to_string(),
+        kernel_path: None,
+        sleep: 0,
+        gpu_indices: vec![0],
+        backend: BackendKind::Cpu,
+        cpu_threads: 4,
+        autotune_latency: None,
+    };
+    let mut backend = CpuBackend::setup(&settings).unwrap();
+    let log = Log::new();
+    // One in 256 hashes meets this target, so 4096 nonces find about 16.
+    let mut target = [0xff; 32];
+    target[31] = 0;
+    let mut work = Work::from_header(LotusHeader::from_bytes(&[1; 160]), target);
+    work.set_big_nonce(0);
+    work.nonce_base = 0xffff_f000;
+    assert_eq!(backend.num_nonces_per_search(&settings), 1 << 12);
+    backend.enqueue_search(&work, &settings, &log).unwrap();
+    let candidates = backend.finish_search(&settings, &log).unwrap();
+    assert!(candidates.len() > 1, "found {} nonces", candidates.len());
+    for candidate in candidates {
+        assert!(candidate.meets_target);
+        work.set_big_nonce(candidate.nonce);
+        assert_eq!(work.header().hash()[31], 0);
+        assert!(work.header_bytes()[44..48] >= [0xff, 0xff, 0xf0, 0x00][..]);
+    }
+}
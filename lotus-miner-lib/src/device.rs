@@ -0,0 +1,199 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, AtomicI32, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::runtime::Handle;
+
+use crate::{block::Block, miner::Work, nonce::NonceAllocator, Miner, Server, ServerRef};
+
+/// How often an idle device thread checks whether it was stopped.
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+/// Pause after a failed search, doubled with each further failure in a row.
+const ERROR_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_ERROR_BACKOFF: Duration = Duration::from_secs(5);
+/// A device failing this many searches in a row is lost, e.g. because the
+/// driver crashed, and stops mining.
+const MAX_CONSECUTIVE_ERRORS: u32 = 10;
+
+/// One mining device (e.g. a GPU), mined on by its own OS thread.
+pub(crate) struct MiningDevice {
+    pub device_idx: usize,
+    pub device_name: String,
+    /// Handed to the device thread when it starts.
+    miner: Mutex<Option<Miner>>,
+    /// Intensity the device thread should switch to; 0 if unchanged.
+    pending_intensity: AtomicI32,
+    /// Nonces searched since the last hashrate report.
+    pub metrics_nonces: AtomicU64,
+    stopped: AtomicBool,
+}
+
+/// The block all devices currently mine on, broadcast to the device threads.
+pub(crate) struct MiningJob {
+    pub block: Arc<Block>,
+    work: Work,
+    /// Each batch claims its own range, so devices search disjoint nonces.
+    nonces: NonceAllocator,
+}
+
+/// What device threads report back to the server.
+pub(crate) enum DeviceEvent {
+    /// `nonce` solves `job`'s block, with its timestamp rolled to `time`.
+    Found {
+        job: Arc<MiningJob>,
+        time: u64,
+        nonce: u64,
+        device_name: String,
+    },
+    /// All nonces of `job` have been handed out.
+    Exhausted(Arc<MiningJob>),
+}
+
+impl MiningDevice {
+    pub fn new(device_idx: usize, miner: Miner) -> Self {
+        MiningDevice {
+            device_idx,
+            device_name: miner.device_name(),
+            miner: Mutex::new(Some(miner)),
+            pending_intensity: AtomicI32::new(0),
+            metrics_nonces: AtomicU64::new(0),
+            stopped: AtomicBool::new(false),
+        }
+    }
+
+    /// Applied by the device thread before its next batch.
+    pub fn set_intensity(&self, intensity: i32) {
+        self.pending_intensity.store(intensity, Ordering::Release);
+    }
+
+    /// Lets the device thread exit after its current batch.
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::Release);
+    }
+}
+
+impl MiningJob {
+    pub fn new(block: Block) -> Self {
+        MiningJob {
+            work: Work::from_header(block.header, block.target),
+            nonces: NonceAllocator::new(&block.header, block.job.as_ref()),
+            block: Arc::new(block),
+        }
+    }
+}
+
+/// Starts the thread mining on `device`, unless it's already running.
+pub(crate) fn spawn_device_thread(server: &ServerRef, device: &Arc<MiningDevice>) {
+    let miner = match device.miner.lock().unwrap().take() {
+        Some(miner) => miner,
+        None => return,
+    };
+    let server = Arc::clone(server);
+    let device = Arc::clone(device);
+    let handle = Handle::current();
+    std::thread::Builder::new()
+        .name(format!("mining device {}", device.device_idx))
+        .spawn(move || run_device(&server, &device, miner, &handle))
+        .expect("Failed to spawn mining thread");
+}
+
+/// Keeps the device's pipeline full with batches of the latest job. Found
+/// nonces are sent to the server, so node requests never hold up the device.
+fn run_device(server: &Server, device: &MiningDevice, mut miner: Miner, handle: &Handle) {
+    let log = server.log();
+    let mut work_receiver = server.work_sender.subscribe();
+    let events = &server.device_events;
+    // Each search's job and the timestamp its header was rolled to
+    let mut in_flight: VecDeque<(Arc<MiningJob>, u64)> = VecDeque::new();
+    let mut exhausted_job: Option<Arc<MiningJob>> = None;
+    let mut consecutive_errors = 0;
+    while !device.stopped.load(Ordering::Acquire) {
+        let intensity = device.pending_intensity.swap(0, Ordering::AcqRel);
+        if intensity != 0 {
+            miner.set_intensity(intensity);
+        }
+        let job = work_receiver.borrow_and_update().clone();
+        let mut enqueued = false;
+        let is_exhausted = matches!(
+            (&job, &exhausted_job),
+            (Some(job), Some(exhausted_job)) if Arc::ptr_eq(job, exhausted_job)
+        );
+        if let (Some(job), false) = (job, is_exhausted) {
+            match job.nonces.allocate(miner.num_nonces_per_search()) {
+                None => {
+                    // The server rolls the extra nonce or logs an error; the
+                    // receiver is only dropped on shutdown
+                    exhausted_job = Some(Arc::clone(&job));
+                    let _ = events.send(DeviceEvent::Exhausted(job));
+                }
+                Some(range) => {
+                    let mut work = job.work;
+                    work.set_nonce_range(&range);
+                    if let Err(err) = miner.enqueue_search(&work, log) {
+                        handle_search_error(server, device, err, &mut consecutive_errors);
+                    } else {
+                        in_flight.push_back((job, range.time));
+                        enqueued = true;
+                    }
+                }
+            }
+        }
+        let pipeline_full = in_flight.len() >= miner.pipeline_depth();
+        if pipeline_full || (!enqueued && !in_flight.is_empty()) {
+            let (job, time) = in_flight.pop_front().unwrap();
+            match miner.finish_search(log) {
+                Ok(nonces) => {
+                    consecutive_errors = 0;
+                    for nonce in nonces {
+                        let _ = events.send(DeviceEvent::Found {
+                            job: Arc::clone(&job),
+                            time,
+                            nonce,
+                            device_name: device.device_name.clone(),
+                        });
+                    }
+                }
+                Err(err) => handle_search_error(server, device, err, &mut consecutive_errors),
+            }
+            device
+                .metrics_nonces
+                .fetch_add(miner.num_nonces_per_search(), Ordering::AcqRel);
+        } else if !enqueued {
+            // Nothing to mine until the server broadcasts new work
+            handle.block_on(async {
+                let _ = tokio::time::timeout(IDLE_CHECK_INTERVAL, work_receiver.changed()).await;
+            });
+        }
+    }
+}
+
+/// Logs a failed search and backs off before the device tries again, so a
+/// broken device doesn't spin. Stops the device after too many failures.
+fn handle_search_error(
+    server: &Server,
+    device: &MiningDevice,
+    err: eyre::Report,
+    consecutive_errors: &mut u32,
+) {
+    let log = server.log();
+    log.error(format!(
+        "Search error on device {}: {:?}",
+        device.device_idx, err
+    ));
+    *consecutive_errors += 1;
+    if *consecutive_errors >= MAX_CONSECUTIVE_ERRORS {
+        log.error(format!(
+            "Device {} ({}) failed {} searches in a row, not mining on it anymore",
+            device.device_idx, device.device_name, consecutive_errors
+        ));
+        device.stop();
+        return;
+    }
+    let backoff = ERROR_BACKOFF * 2u32.pow(*consecutive_errors - 1);
+    std::thread::sleep(backoff.min(MAX_ERROR_BACKOFF));
+}
@@ -0,0 +1,166 @@
+//! Conversions between compact nBits, targets and difficulties. Targets and
+//! hashes are little endian, as returned by `lotus_hash`.
+
+use std::time::Duration;
+
+/// Difficulty 1 target of Lotus, nBits 0x1d00ffff.
+pub const DIFFICULTY_1_TARGET: f64 = 65535.0 * (1u128 << 104) as f64 * (1u128 << 104) as f64;
+
+/// Compares a hash with a target.
+pub fn hash_below_target(hash: &[u8; 32], target: &[u8; 32]) -> bool {
+    for (&h, &t) in hash.iter().zip(target.iter()).rev() {
+        if h > t {
+            return false;
+        }
+        if t > h {
+            return true;
+        }
+    }
+    false
+}
+
+/// Expands compact nBits into a target; `None` if negative or overflowing.
+pub fn bits_to_target(bits: u32) -> Option<[u8; 32]> {
+    let exponent = (bits >> 24) as usize;
+    let mantissa = bits & 0x007f_ffff;
+    if bits & 0x0080_0000 != 0 && mantissa != 0 {
+        return None;
+    }
+    let mut target = [0u8; 32];
+    for (idx, &byte) in mantissa.to_le_bytes()[..3].iter().enumerate() {
+        // Mantissa bytes below the target's lowest byte are shifted out
+        match (exponent + idx).checked_sub(3) {
+            Some(pos) if pos < target.len() => target[pos] = byte,
+            Some(_) if byte != 0 => return None,
+            _ => {}
+        }
+    }
+    Some(target)
+}
+
+/// Compact nBits of `target`, keeping its top three significant bytes like
+/// the node does.
+pub fn target_to_bits(target: &[u8; 32]) -> u32 {
+    let size = match target.iter().rposition(|&byte| byte != 0) {
+        Some(top) => top + 1,
+        None => return 0,
+    };
+    let mut mantissa_bytes = [0u8; 4];
+    for (idx, byte) in mantissa_bytes[..3].iter_mut().enumerate() {
+        if let Some(pos) = (size + idx).checked_sub(3) {
+            *byte = target[pos];
+        }
+    }
+    let mut mantissa = u32::from_le_bytes(mantissa_bytes);
+    let mut size = size as u32;
+    // The top mantissa bit is the sign, so it must stay clear
+    if mantissa & 0x0080_0000 != 0 {
+        mantissa >>= 8;
+        size += 1;
+    }
+    size << 24 | mantissa
+}
+
+pub fn target_to_difficulty(target: &[u8; 32]) -> f64 {
+    DIFFICULTY_1_TARGET / to_f64(target)
+}
+
+pub fn bits_to_difficulty(bits: u32) -> Option<f64> {
+    bits_to_target(bits).map(|target| target_to_difficulty(&target))
+}
+
+/// Target of a pool share with the given difficulty.
+pub fn difficulty_to_target(difficulty: f64) -> [u8; 32] {
+    let mut value = DIFFICULTY_1_TARGET / difficulty;
+    let mut target = [0xff; 32];
+    if value >= 256f64.powi(32) {
+        return target;
+    }
+    for (idx, byte) in target.iter_mut().enumerate().rev() {
+        let place = 256f64.powi(idx as i32);
+        let digit = (value / place).floor().min(255.0);
+        *byte = digit as u8;
+        value -= digit * place;
+    }
+    target
+}
+
+/// Share difficulty a found `hash` meets, i.e. the difficulty of the
+/// easiest target it's not below.
+pub fn hash_difficulty(hash: &[u8; 32]) -> f64 {
+    target_to_difficulty(hash)
+}
+
+/// Expected time for `hashrate` hashes per second to find a block at
+/// `difficulty`; `None` while not hashing.
+pub fn expected_time_to_block(hashrate: f64, difficulty: f64) -> Option<Duration> {
+    let expected_hashes = difficulty * 256f64.powi(32) / DIFFICULTY_1_TARGET;
+    let secs = expected_hashes / hashrate;
+    if hashrate > 0.0 && secs.is_finite() && secs < u64::MAX as f64 {
+        Some(Duration::from_secs_f64(secs))
+    } else {
+        None
+    }
+}
+
+/// Rough duration with its two most significant units, e.g. "3h 12m".
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let (days, hours, mins) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60);
+    match (days, hours, mins) {
+        (0, 0, 0) => format!("{}s", secs),
+        (0, 0, _) => format!("{}m {}s", mins, secs % 60),
+        (0, _, _) => format!("{}h {}m", hours, mins),
+        _ => format!("{}d {}h", days, hours),
+    }
+}
+
+fn to_f64(target: &[u8; 32]) -> f64 {
+    target
+        .iter()
+        .rev()
+        .fold(0.0, |value, &byte| value * 256.0 + byte as f64)
+}
+
+#[test]
+fn test_difficulty_to_target() {
+    let target = difficulty_to_target(1.0);
+    assert_eq!(&target[26..], &[0xff, 0xff, 0, 0, 0, 0]);
+    assert!(target[..26].iter().all(|&byte| byte == 0));
+    let target = difficulty_to_target(256.0);
+    assert_eq!(&target[25..], &[0xff, 0xff, 0, 0, 0, 0, 0]);
+}
+
+#[test]
+fn test_bits_to_target() {
+    let target = bits_to_target(0x1d00ffff).unwrap();
+    assert_eq!(target, difficulty_to_target(1.0));
+    let target = bits_to_target(0x2000ffff).unwrap();
+    assert_eq!(&target[29..], &[0xff, 0xff, 0]);
+    assert_eq!(bits_to_target(0x0200ffff).unwrap()[0], 0xff);
+    assert_eq!(bits_to_target(0x1d80ffff), None);
+    assert_eq!(bits_to_target(0x2200ffff), None);
+    for &bits in &[0x1d00ffff, 0x2000ffff, 0x1b0404cb, 0x1c7fffff, 0x03123456] {
+        assert_eq!(target_to_bits(&bits_to_target(bits).unwrap()), bits);
+    }
+    // 0x80 in the top byte needs a leading zero byte
+    let mut target = [0; 32];
+    target[28] = 0x80;
+    assert_eq!(target_to_bits(&target), 0x1e008000);
+    assert_eq!(target_to_bits(&[0; 32]), 0);
+}
+
+#[test]
+fn test_difficulty_estimates() {
+    assert_eq!(bits_to_difficulty(0x1d00ffff), Some(1.0));
+    let difficulty = bits_to_difficulty(0x1b0404cb).unwrap();
+    assert!((difficulty - 16307.420938523983).abs() < 1e-6);
+    assert_eq!(hash_difficulty(&bits_to_target(0x1c00ffff).unwrap()), 256.0);
+    // Difficulty 1 takes about 2^32 hashes
+    let time = expected_time_to_block(1_000_000.0, 1.0).unwrap();
+    assert_eq!(time.as_secs(), 4295);
+    assert_eq!(expected_time_to_block(0.0, 1.0), None);
+    assert_eq!(format_duration(time), "1h 11m");
+    assert_eq!(format_duration(Duration::from_secs(42)), "42s");
+    assert_eq!(format_duration(Duration::from_secs(100_000)), "1d 3h");
+}
@@ -0,0 +1,160 @@
+use sha2::Digest;
+use std::convert::TryInto;
+
+use crate::{block::LotusHeader, sha256::LotusPrecalc};
+
+/// An OpenCL kernel shipped with the miner, and how to call it.
+#[derive(Debug, Clone, Copy)]
+pub struct KernelInfo {
+    pub name: &'static str,
+    /// `.cl` file the source is embedded from, also looked up in a
+    /// `kernel_path` folder.
+    pub file_name: &'static str,
+    pub source: &'static str,
+    /// Compiler definitions besides `WORKSIZE` and `ITERATIONS`.
+    pub defines: &'static [&'static str],
+    /// Nonces each work item tests per iteration; `inner_iter_size` must be
+    /// a multiple of it.
+    pub vector_width: i32,
+    /// Whether to define `BITALIGN` on devices with `cl_amd_media_ops`.
+    pub bitalign: bool,
+    pub header_args: HeaderArgs,
+}
+
+/// How a kernel takes the header to search, between its `offset` and
+/// `output` arguments.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderArgs {
+    /// A `partial_header` buffer with the big endian words of header bytes
+    /// 0..52 and the tx layer hash.
+    PartialHeader,
+    /// The `LotusPrecalc` values, one scalar argument each.
+    Precalc,
+}
+
+const LOTUS_OG: &str = include_str!("../../kernels/lotus_og.cl");
+const LOTUS_MIDSTATE: &str = include_str!("../../kernels/lotus_midstate.cl");
+const POCLBM: &str = include_str!("../../kernels/poclbm120327.cl");
+
+/// Kernels shipped with the miner, selected by `kernel` in the config.
+pub const KERNELS: &[KernelInfo] = &[
+    KernelInfo {
+        name: "lotus_og",
+        file_name: "lotus_og.cl",
+        source: LOTUS_OG,
+        defines: &[],
+        vector_width: 1,
+        bitalign: false,
+        header_args: HeaderArgs::PartialHeader,
+    },
+    KernelInfo {
+        name: "lotus_midstate",
+        file_name: "lotus_midstate.cl",
+        source: LOTUS_MIDSTATE,
+        defines: &[],
+        vector_width: 1,
+        bitalign: false,
+        header_args: HeaderArgs::Precalc,
+    },
+    KernelInfo {
+        name: "poclbm",
+        file_name: "poclbm120327.cl",
+        source: POCLBM,
+        defines: &[],
+        vector_width: 1,
+        bitalign: true,
+        header_args: HeaderArgs::Precalc,
+    },
+    KernelInfo {
+        name: "poclbm_v2",
+        file_name: "poclbm120327.cl",
+        source: POCLBM,
+        defines: &["VECTORS2"],
+        vector_width: 2,
+        bitalign: true,
+        header_args: HeaderArgs::Precalc,
+    },
+    KernelInfo {
+        name: "poclbm_v4",
+        file_name: "poclbm120327.cl",
+        source: POCLBM,
+        defines: &["VECTORS4"],
+        vector_width: 4,
+        bitalign: true,
+        header_args: HeaderArgs::Precalc,
+    },
+];
+
+/// The shipped kernel `name`. Kernels only found in a `kernel_path` folder
+/// are called like `lotus_og`.
+pub fn kernel_info(name: &str) -> &'static KernelInfo {
+    KERNELS
+        .iter()
+        .find(|kernel| kernel.name == name)
+        .unwrap_or(&KERNELS[0])
+}
+
+impl HeaderArgs {
+    /// Names of the kernel arguments after `offset`; `partial_header` is a
+    /// buffer, all others are `uint`s.
+    pub fn names(self) -> &'static [&'static str] {
+        match self {
+            HeaderArgs::PartialHeader => &["partial_header"],
+            HeaderArgs::Precalc => &LotusPrecalc::KERNEL_ARG_NAMES,
+        }
+    }
+
+    /// Host side precalculation for `header`: the `partial_header` buffer's
+    /// contents, or the values of the `names` arguments.
+    pub fn precalc(self, header: &[u8; 160]) -> Vec<u32> {
+        match self {
+            HeaderArgs::PartialHeader => {
+                let mut partial_header = [0u8; 84];
+                let tx_layer_hash = sha2::Sha256::digest(&header[LotusHeader::TX_LAYER]);
+                partial_header[..52].copy_from_slice(&header[..LotusHeader::TX_LAYER.start]);
+                partial_header[52..].copy_from_slice(&tx_layer_hash);
+                partial_header
+                    .chunks(4)
+                    .map(|chunk| u32::from_be_bytes(chunk.try_into().unwrap()))
+                    .collect()
+            }
+            HeaderArgs::Precalc => LotusPrecalc::new(header).kernel_args().to_vec(),
+        }
+    }
+}
+
+#[test]
+fn test_kernel_args_declared() {
+    for kernel in KERNELS {
+        assert_eq!(kernel_info(kernel.name).name, kernel.name);
+        // Autotune and bench only try multiples of 8
+        assert_eq!(8 % kernel.vector_width, 0, "{}", kernel.name);
+        let mut expected = vec!["offset"];
+        expected.extend(kernel.header_args.names());
+        expected.push("output");
+        let signature = kernel.source.split("void search(").nth(1).unwrap();
+        let signature = signature
+            .lines()
+            .map(|line| line.split("//").next().unwrap())
+            .collect::<String>();
+        let signature = &signature[..signature.find(')').unwrap()];
+        let declared = signature
+            .split(',')
+            .map(|arg| arg.trim().rsplit(&[' ', '*'][..]).next().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(declared, expected, "{}", kernel.name);
+    }
+    assert_eq!(kernel_info("custom").name, "lotus_og");
+}
+
+#[test]
+fn test_partial_header_precalc() {
+    let mut header = [0u8; 160];
+    header[..4].copy_from_slice(&[1, 2, 3, 4]);
+    let partial_header = HeaderArgs::PartialHeader.precalc(&header);
+    assert_eq!(partial_header.len(), 21);
+    assert_eq!(partial_header[0], 0x01020304);
+    let tx_layer_hash = sha2::Sha256::digest(&header[52..]);
+    assert_eq!(partial_header[13].to_be_bytes(), tx_layer_hash[..4]);
+    assert_eq!(HeaderArgs::Precalc.precalc(&header).len(), 37);
+}
@@ -0,0 +1,406 @@
+use std::{
+    fmt::Display,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::RwLock,
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    block::Block, display_hash, send_request, submit::SubmitError, ConfigSettings, Server,
+};
+
+/// How often accepted blocks are checked for confirmations or orphaning.
+const BLOCK_STATUS_CHECK_INTERVAL: Duration = Duration::from_secs(10 * 60);
+/// Once the coinbase is spendable, a block's status isn't checked anymore.
+const COINBASE_MATURITY: u64 = 100;
+
+#[derive(Debug, Error)]
+pub enum LedgerError {
+    #[error("Couldn't write found blocks to {0}: {1}")]
+    Write(String, std::io::Error),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BlockStatus {
+    /// Not accepted by the node; `submit_result` says why.
+    Rejected,
+    /// Never sent to the node, as it was outdated or failed verification;
+    /// `submit_result` says why.
+    Dropped,
+    /// Accepted by the node, but not seen in its chain yet.
+    Accepted,
+    /// In the node's active chain.
+    Confirmed,
+    /// Was in the node's chain, but isn't anymore.
+    Orphaned,
+}
+
+/// A block found by one of our devices or proxy workers.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FoundBlock {
+    /// RFC 3339 time the block was submitted.
+    pub timestamp: String,
+    pub height: u32,
+    /// Block hash, as displayed by the node.
+    pub hash: String,
+    pub nonce: u64,
+    /// Name of the device, or the proxy worker, that found the block.
+    pub device: String,
+    /// URL of the node the block was submitted to.
+    pub node: String,
+    /// "accepted", or why submitting failed.
+    pub submit_result: String,
+    pub status: BlockStatus,
+    /// Confirmations as of the last status check.
+    pub confirmations: u64,
+}
+
+/// Found blocks, stored as one JSON object per line in `blocks_file`
+/// (`~/.lotus-miner/blocks.jsonl` by default). New blocks are appended and
+/// synced to disk right away; status updates rewrite the file.
+pub struct Ledger {
+    /// `None` keeps the ledger in memory only.
+    path: Option<PathBuf>,
+    blocks: RwLock<Vec<FoundBlock>>,
+}
+
+impl Ledger {
+    /// Opens the ledger at `path`, skipping entries that can't be parsed.
+    pub fn open(path: Option<PathBuf>) -> Self {
+        let blocks = path.as_deref().map(load_found_blocks).unwrap_or_default();
+        Ledger {
+            path,
+            blocks: RwLock::new(blocks),
+        }
+    }
+
+    /// All found blocks, oldest first.
+    pub fn blocks(&self) -> Vec<FoundBlock> {
+        self.blocks.read().unwrap().clone()
+    }
+
+    pub fn record(&self, block: FoundBlock) -> Result<(), LedgerError> {
+        let mut blocks = self.blocks.write().unwrap();
+        if let Some(path) = &self.path {
+            let mut line = serde_json::to_string(&block).unwrap();
+            line.push('\n');
+            append_line(path, &line).map_err(|err| write_error(path, err))?;
+        }
+        blocks.push(block);
+        Ok(())
+    }
+
+    /// Sets the status of the block with `hash`. The file is read again
+    /// first, so blocks recorded by another process aren't lost.
+    fn update_status(
+        &self,
+        hash: &str,
+        status: BlockStatus,
+        confirmations: u64,
+    ) -> Result<(), LedgerError> {
+        let mut blocks = self.blocks.write().unwrap();
+        if let Some(path) = &self.path {
+            *blocks = load_found_blocks(path);
+        }
+        for block in blocks.iter_mut().filter(|block| block.hash == hash) {
+            block.status = status;
+            block.confirmations = confirmations;
+        }
+        if let Some(path) = &self.path {
+            save_found_blocks(path, &blocks).map_err(|err| write_error(path, err))?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for BlockStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let status = match self {
+            BlockStatus::Rejected => "rejected",
+            BlockStatus::Dropped => "dropped",
+            BlockStatus::Accepted => "accepted",
+            BlockStatus::Confirmed => "confirmed",
+            BlockStatus::Orphaned => "orphaned",
+        };
+        write!(f, "{}", status)
+    }
+}
+
+/// Stored blocks; none if the file is missing.
+fn load_found_blocks(path: &Path) -> Vec<FoundBlock> {
+    std::fs::read_to_string(path)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect()
+}
+
+fn append_line(path: &Path, line: &str) -> std::io::Result<()> {
+    if let Some(folder) = path.parent() {
+        std::fs::create_dir_all(folder)?;
+    }
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    file.write_all(line.as_bytes())?;
+    file.sync_data()
+}
+
+/// Replaces the file through a renamed temporary file, so a crash never
+/// leaves it half written.
+fn save_found_blocks(path: &Path, blocks: &[FoundBlock]) -> std::io::Result<()> {
+    let mut lines = String::new();
+    for block in blocks {
+        lines.push_str(&serde_json::to_string(block).unwrap());
+        lines.push('\n');
+    }
+    let tmp_path = path.with_extension("jsonl.tmp");
+    let mut file = std::fs::File::create(&tmp_path)?;
+    file.write_all(lines.as_bytes())?;
+    file.sync_data()?;
+    std::fs::rename(&tmp_path, path)
+}
+
+fn write_error(path: &Path, err: std::io::Error) -> LedgerError {
+    LedgerError::Write(path.to_string_lossy().to_string(), err)
+}
+
+/// Records the outcome of submitting a block `device` found.
+pub(crate) async fn record_found_block(
+    server: &Server,
+    block: &Block,
+    device: &str,
+    result: &Result<(), SubmitError>,
+) {
+    let (submit_result, status) = match result {
+        Ok(()) => ("accepted".to_string(), BlockStatus::Accepted),
+        Err(err) if err.is_dropped() => (err.to_string(), BlockStatus::Dropped),
+        Err(err) => (err.to_string(), BlockStatus::Rejected),
+    };
+    record_block(server, block, device, submit_result, status).await;
+}
+
+/// Records a block `device` found that was dropped before submitting it,
+/// for `reason`.
+pub(crate) async fn record_dropped_block(
+    server: &Server,
+    block: &Block,
+    device: &str,
+    reason: &str,
+) {
+    record_block(
+        server,
+        block,
+        device,
+        reason.to_string(),
+        BlockStatus::Dropped,
+    )
+    .await;
+}
+
+async fn record_block(
+    server: &Server,
+    block: &Block,
+    device: &str,
+    submit_result: String,
+    status: BlockStatus,
+) {
+    let node = {
+        let node_settings = server.node_settings.lock().await;
+        node_settings.nodes[node_settings.active_node].url.clone()
+    };
+    let found_block = FoundBlock {
+        timestamp: chrono::Local::now().to_rfc3339(),
+        height: block.header.height,
+        hash: display_hash(&block.header.hash()),
+        nonce: block.header.nonce,
+        device: device.to_string(),
+        node,
+        submit_result,
+        status,
+        confirmations: 0,
+    };
+    if let Err(err) = server.ledger.record(found_block) {
+        server.log().error(err);
+    }
+}
+
+/// Asks the node how many confirmations each accepted block has; blocks
+/// that left the active chain are marked orphaned. Blocks the node can't
+/// tell us about keep their status.
+pub(crate) async fn check_block_statuses(server: &Server) {
+    #[derive(Deserialize)]
+    struct GetBlockResponse {
+        result: Option<GetBlockResult>,
+    }
+    #[derive(Deserialize)]
+    struct GetBlockResult {
+        confirmations: i64,
+    }
+    let log = server.log();
+    let unsettled_blocks = server.ledger.blocks().into_iter().filter(|block| {
+        block.status == BlockStatus::Accepted
+            || (block.status == BlockStatus::Confirmed && block.confirmations < COINBASE_MATURITY)
+    });
+    for block in unsettled_blocks {
+        let body = format!(r#"{{"method":"getblock","params":["{}",1]}}"#, block.hash);
+        let confirmations = match send_request(server, body).await {
+            Ok((status, response)) if status.is_success() => {
+                serde_json::from_str::<GetBlockResponse>(&response)
+                    .ok()
+                    .and_then(|response| response.result)
+                    .map(|result| result.confirmations)
+            }
+            _ => None,
+        };
+        let (status, confirmations) = match confirmations {
+            Some(confirmations) if confirmations > 0 => {
+                (BlockStatus::Confirmed, confirmations as u64)
+            }
+            Some(_) => (BlockStatus::Orphaned, 0),
+            None => continue,
+        };
+        if status == BlockStatus::Orphaned {
+            log.warn(format!(
+                "Block {} at height {} was orphaned",
+                block.hash, block.height
+            ));
+        } else if block.status != status {
+            log.info(format!(
+                "Block {} at height {} is confirmed",
+                block.hash, block.height
+            ));
+        }
+        if let Err(err) = server
+            .ledger
+            .update_status(&block.hash, status, confirmations)
+        {
+            log.error(err);
+        }
+    }
+}
+
+pub(crate) async fn run_block_status_checks(server: &Server) {
+    loop {
+        check_block_statuses(server).await;
+        tokio::time::sleep(BLOCK_STATUS_CHECK_INTERVAL).await;
+    }
+}
+
+/// Found blocks of the ledger in `config`, with their status checked with
+/// the node first.
+pub async fn check_found_blocks(config: ConfigSettings) -> eyre::Result<Vec<FoundBlock>> {
+    let server = Server::without_devices(config, Duration::from_secs(10))?;
+    check_block_statuses(&server).await;
+    Ok(server.ledger.blocks())
+}
+
+pub fn format_found_blocks_table(blocks: &[FoundBlock]) -> String {
+    let mut table = format!(
+        "{:<25} {:>7} {:<64} {:>20} {:<10} {:>5} {:<24} {:<24} {}\n",
+        "Submitted", "Height", "Hash", "Nonce", "Status", "Confs", "Device", "Node", "Result"
+    );
+    for block in blocks {
+        table.push_str(&format!(
+            "{:<25} {:>7} {:<64} {:>20} {:<10} {:>5} {:<24} {:<24} {}\n",
+            block.timestamp,
+            block.height,
+            block.hash,
+            block.nonce,
+            block.status,
+            block.confirmations,
+            block.device,
+            block.node,
+            block.submit_result,
+        ));
+    }
+    table
+}
+
+#[test]
+fn test_ledger() {
+    let path = std::env::temp_dir().join(format!("lotus-miner-{}.ledger", std::process::id()));
+    let block = FoundBlock {
+        timestamp: "2021-06-18T12:00:00+00:00".to_string(),
+        height: 1000,
+        hash: "00aa".to_string(),
+        nonce: 42,
+        device: "CPU (2 threads)".to_string(),
+        node: "http://127.0.0.1:10604".to_string(),
+        submit_result: "accepted".to_string(),
+        status: BlockStatus::Accepted,
+        confirmations: 0,
+    };
+    let ledger = Ledger::open(Some(path.clone()));
+    ledger.record(block.clone()).unwrap();
+    let other_block = FoundBlock {
+        hash: "00bb".to_string(),
+        ..block.clone()
+    };
+    ledger.record(other_block.clone()).unwrap();
+    ledger
+        .update_status("00aa", BlockStatus::Confirmed, 3)
+        .unwrap();
+
+    let confirmed_block = FoundBlock {
+        status: BlockStatus::Confirmed,
+        confirmations: 3,
+        ..block
+    };
+    let reopened = Ledger::open(Some(path.clone()));
+    assert_eq!(reopened.blocks(), vec![confirmed_block, other_block]);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn test_check_block_statuses() {
+    use crate::settings::test_config;
+    use lotus_miner_mock_node::MockNode;
+
+    let node = MockNode::start().await.unwrap();
+    let server = Server::without_devices(
+        ConfigSettings {
+            rpc_url: node.url(),
+            work_source: "getrawunsolvedblock".to_string(),
+            ..test_config()
+        },
+        Duration::from_secs(10),
+    )
+    .unwrap();
+    let ledger = Ledger::open(None);
+    let block = FoundBlock {
+        timestamp: "2021-06-18T12:00:00+00:00".to_string(),
+        height: 1000,
+        hash: "00aa".to_string(),
+        nonce: 42,
+        device: "CPU (2 threads)".to_string(),
+        node: node.url(),
+        submit_result: "accepted".to_string(),
+        status: BlockStatus::Accepted,
+        confirmations: 0,
+    };
+    ledger.record(block).unwrap();
+    let server = Server { ledger, ..server };
+
+    node.set_block_confirmations(2);
+    check_block_statuses(&server).await;
+    let blocks = server.ledger.blocks();
+    assert_eq!(
+        (blocks[0].status, blocks[0].confirmations),
+        (BlockStatus::Confirmed, 2)
+    );
+    node.set_block_confirmations(-1);
+    check_block_statuses(&server).await;
+    assert_eq!(server.ledger.blocks()[0].status, BlockStatus::Orphaned);
+    // Orphaned blocks aren't checked again
+    node.set_block_confirmations(5);
+    check_block_statuses(&server).await;
+    assert_eq!(server.ledger.blocks()[0].status, BlockStatus::Orphaned);
+    assert_eq!(node.methods(), vec!["getblock", "getblock"]);
+}
@@ -1,52 +1,113 @@
+mod autotune;
+mod bench;
 mod block;
+mod cpu;
+mod device;
+pub mod difficulty;
+mod kernels;
+mod ledger;
 mod miner;
+mod node;
+mod nonce;
+mod notify;
+mod opencl;
+mod proxy;
+mod selftest;
 pub mod settings;
 mod sha256;
+mod stratum;
+mod submit;
 
-use eyre::Result;
-pub use miner::Miner;
-pub use settings::ConfigSettings;
+pub use autotune::{tune_devices, TunedSettings};
+pub use bench::{format_bench_json, format_bench_table, run_bench, BenchResult, BenchSettings};
+use eyre::{eyre, Result};
+pub use ledger::{check_found_blocks, format_found_blocks_table, BlockStatus, FoundBlock, Ledger};
+pub use miner::{BackendKind, Miner};
+pub use proxy::{Proxy, WorkerStats};
+pub use settings::{Command, ConfigSettings, NodeConfig};
 
 use std::{
+    collections::{BTreeMap, HashMap},
     convert::TryInto,
     fmt::Display,
+    str::FromStr,
     sync::{
-        atomic::{AtomicU64, Ordering},
+        atomic::{AtomicBool, Ordering},
         Arc,
     },
-    time::{Duration, SystemTime},
+    time::{Duration, Instant},
 };
 
-use block::{create_block, Block, GetRawUnsolvedBlockResponse};
-use miner::{MiningSettings, Work};
-use rand::{Rng, SeedableRng};
-use reqwest::{RequestBuilder, StatusCode};
-use serde::Deserialize;
-use tokio::sync::{Mutex, MutexGuard};
+use autotune::setup_tuned_devices;
+use block::{
+    create_block, create_block_from_template, Block, BlockTemplate, GetBlockTemplateResponse,
+    GetRawUnsolvedBlockResponse,
+};
+use device::{spawn_device_thread, DeviceEvent, MiningDevice, MiningJob};
+use difficulty::{bits_to_difficulty, expected_time_to_block, format_duration, hash_difficulty};
+use ledger::{record_dropped_block, record_found_block, run_block_status_checks};
+use miner::MiningSettings;
+use node::{run_node_health_checks, select_active_node, send_node_request, NodeError};
+use notify::{poll_interval, run_tip_notifications};
+use reqwest::StatusCode;
+use selftest::self_test;
+use stratum::{run_stratum, StratumConnection};
+use submit::{log_submit_error, submit_block};
+use tokio::sync::{
+    mpsc::{self, UnboundedReceiver, UnboundedSender},
+    watch, Mutex, MutexGuard, Notify,
+};
 
 pub struct Server {
     client: reqwest::Client,
-    miner: std::sync::Mutex<Miner>,
+    mining_settings: std::sync::Mutex<MiningSettings>,
+    devices: std::sync::RwLock<Vec<Arc<MiningDevice>>>,
+    devices_changed: Notify,
     node_settings: Mutex<NodeSettings>,
+    stratum: Mutex<Option<StratumConnection>>,
+    cookies: std::sync::Mutex<HashMap<String, (String, String)>>,
+    new_tip: Notify,
+    tip_notifications_active: AtomicBool,
     block_state: Mutex<BlockState>,
-    rng: Mutex<rand::rngs::StdRng>,
-    metrics_timestamp: Mutex<SystemTime>,
-    metrics_nonces: AtomicU64,
+    /// Broadcasts the job to mine on to the device threads.
+    work_sender: watch::Sender<Option<Arc<MiningJob>>>,
+    device_events: UnboundedSender<DeviceEvent>,
+    device_events_receiver: Mutex<Option<UnboundedReceiver<DeviceEvent>>>,
     log: Log,
+    ledger: Ledger,
     report_hashrate_interval: Duration,
 }
 
 pub struct NodeSettings {
-    pub bitcoind_url: String,
-    pub bitcoind_user: String,
-    pub bitcoind_password: String,
+    /// Nodes in order of preference; requests go to `nodes[active_node]`.
+    pub nodes: Vec<NodeConfig>,
+    pub active_node: usize,
     pub rpc_poll_interval: u64,
+    /// lotusd's `zmqpubhashblock` address; empty to only poll.
+    pub zmq_hashblock: String,
     pub miner_addr: String,
+    pub work_source: WorkSource,
+    pub pool_url: String,
+    pub pool_user: String,
+    pub pool_password: String,
+}
+
+/// Where the miner gets block templates from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WorkSource {
+    /// `getrawunsolvedblock`; the node builds the whole block.
+    RawUnsolvedBlock,
+    /// `getblocktemplate`; we build the coinbase and roll the extra nonce.
+    BlockTemplate,
+    /// A Stratum v1 pool; found nonces are submitted as shares.
+    Stratum,
 }
 
 pub struct Log {
     logs: std::sync::RwLock<Vec<LogEntry>>,
     hashrates: std::sync::RwLock<Vec<HashrateEntry>>,
+    device_hashrates: std::sync::RwLock<BTreeMap<usize, DeviceHashrateEntry>>,
+    active_node: std::sync::RwLock<Option<ActiveNodeEntry>>,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -65,51 +126,100 @@ pub struct LogEntry {
 
 pub struct HashrateEntry {
     pub hashrate: f64,
+    /// Network difficulty of the block mined on, if any.
+    pub difficulty: Option<f64>,
+    pub timestamp: chrono::DateTime<chrono::Local>,
+}
+
+pub struct DeviceHashrateEntry {
+    pub device_idx: usize,
+    pub device_name: String,
+    pub hashrate: f64,
+    pub timestamp: chrono::DateTime<chrono::Local>,
+}
+
+/// The node we switched to last, and how often we switched so far.
+#[derive(Debug, Clone)]
+pub struct ActiveNodeEntry {
+    pub url: String,
+    pub switches: u64,
     pub timestamp: chrono::DateTime<chrono::Local>,
 }
 
 struct BlockState {
-    current_work: Work,
-    current_block: Option<Block>,
-    next_block: Option<Block>,
+    /// The block devices mine on; also broadcast to them via `work_sender`.
+    current_block: Option<Arc<Block>>,
+    template: Option<BlockTemplate>,
     extra_nonce: u64,
 }
 
 pub type ServerRef = Arc<Server>;
 
+impl FromStr for WorkSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "getrawunsolvedblock" => Ok(WorkSource::RawUnsolvedBlock),
+            "getblocktemplate" => Ok(WorkSource::BlockTemplate),
+            "stratum" => Ok(WorkSource::Stratum),
+            _ => Err(format!(
+                "Unknown work source {:?}, expected \"getrawunsolvedblock\", \
+                 \"getblocktemplate\" or \"stratum\"",
+                s
+            )),
+        }
+    }
+}
+
 impl Server {
-    pub fn from_config(config: ConfigSettings, report_hashrate_interval: Duration) -> Self {
-        let mining_settings = MiningSettings {
-            local_work_size: 256,
-            inner_iter_size: 16,
-            kernel_size: 1 << config.kernel_size,
-            kernel_name: "lotus_og".to_string(),
-            sleep: 0,
-            gpu_indices: vec![config.gpu_index as usize],
-        };
-        let miner = Miner::setup(mining_settings.clone()).unwrap();
-        Server {
-            miner: std::sync::Mutex::new(miner),
+    pub fn from_config(config: ConfigSettings, report_hashrate_interval: Duration) -> Result<Self> {
+        let mut server = Server::without_devices(config, report_hashrate_interval)?;
+        let devices = setup_devices(server.mining_settings.get_mut().unwrap(), &server.log)?;
+        *server.devices.get_mut().unwrap() = devices;
+        Ok(server)
+    }
+
+    /// A server that only talks to the node and doesn't mine itself, as
+    /// used by the Stratum proxy.
+    fn without_devices(config: ConfigSettings, report_hashrate_interval: Duration) -> Result<Self> {
+        let mining_settings = MiningSettings::from_config(&config)?;
+        let rpc_poll_interval = config.rpc_poll_interval.try_into()?;
+        let work_source = WorkSource::from_str(&config.work_source).map_err(|err| eyre!(err))?;
+        let (device_events, device_events_receiver) = mpsc::unbounded_channel();
+        let ledger = Ledger::open(config.blocks_file());
+        Ok(Server {
+            mining_settings: std::sync::Mutex::new(mining_settings),
+            devices: std::sync::RwLock::new(Vec::new()),
+            devices_changed: Notify::new(),
             client: reqwest::Client::new(),
             node_settings: Mutex::new(NodeSettings {
-                bitcoind_url: config.rpc_url.clone(),
-                bitcoind_user: config.rpc_user.clone(),
-                bitcoind_password: config.rpc_password.clone(),
-                rpc_poll_interval: config.rpc_poll_interval.try_into().unwrap(),
+                nodes: config.nodes(),
+                active_node: 0,
+                rpc_poll_interval,
+                zmq_hashblock: config.zmq_hashblock.clone(),
                 miner_addr: config.mine_to_address.clone(),
+                work_source,
+                pool_url: config.pool_url.clone(),
+                pool_user: config.pool_user.clone(),
+                pool_password: config.pool_password.clone(),
             }),
+            stratum: Mutex::new(None),
+            cookies: std::sync::Mutex::new(HashMap::new()),
+            new_tip: Notify::new(),
+            tip_notifications_active: AtomicBool::new(false),
             block_state: Mutex::new(BlockState {
-                current_work: Work::default(),
                 current_block: None,
-                next_block: None,
+                template: None,
                 extra_nonce: 0,
             }),
-            rng: Mutex::new(rand::rngs::StdRng::from_entropy()),
-            metrics_timestamp: Mutex::new(SystemTime::now()),
-            metrics_nonces: AtomicU64::new(0),
+            work_sender: watch::channel(None).0,
+            device_events,
+            device_events_receiver: Mutex::new(Some(device_events_receiver)),
             log: Log::new(),
+            ledger,
             report_hashrate_interval,
-        }
+        })
     }
 
     pub async fn run(self: ServerRef) -> Result<(), Box<dyn std::error::Error>> {
@@ -118,28 +228,73 @@ impl Server {
             async move {
                 let log = server.log();
                 loop {
-                    if let Err(err) = update_next_block(&server).await {
-                        log.error(format!("update_next_block error: {:?}", err));
+                    let work_source = server.node_settings.lock().await.work_source;
+                    if work_source == WorkSource::Stratum {
+                        // Only returns once the pool connection is lost
+                        if let Err(err) = run_stratum(&server).await {
+                            log.error(format!("Stratum error: {}", err));
+                        }
+                    } else {
+                        let failed = match update_next_block(&server).await {
+                            Ok(()) => false,
+                            Err(err) => {
+                                log.error(format!("update_next_block error: {:?}", err));
+                                true
+                            }
+                        };
+                        if failed && server.node_settings.lock().await.nodes.len() > 1 {
+                            select_active_node(&server).await;
+                        }
+                    }
+                    let poll_interval = poll_interval(&server).await;
+                    tokio::select! {
+                        _ = tokio::time::sleep(poll_interval) => {}
+                        _ = server.new_tip.notified() => {}
                     }
-                    let rpc_poll_interval = server.node_settings.lock().await.rpc_poll_interval;
-                    tokio::time::sleep(Duration::from_secs(rpc_poll_interval)).await;
                 }
             }
         });
         let t2 = tokio::spawn({
             let server = Arc::clone(&self);
             async move {
-                let log = server.log();
                 loop {
-                    if let Err(err) = mine_some_nonces(Arc::clone(&server)).await {
-                        log.error(format!("mine_some_nonces error: {:?}", err));
+                    let devices = server.devices.read().unwrap().clone();
+                    for device in &devices {
+                        spawn_device_thread(&server, device);
                     }
-                    tokio::time::sleep(Duration::from_micros(3)).await;
+                    server.devices_changed.notified().await;
                 }
             }
         });
+        let t3 = tokio::spawn({
+            let server = Arc::clone(&self);
+            async move { run_node_health_checks(&server).await }
+        });
+        let t4 = tokio::spawn(run_tip_notifications(Arc::clone(&self)));
+        let t5 = tokio::spawn({
+            let server = Arc::clone(&self);
+            let events = self.device_events_receiver.lock().await.take();
+            async move {
+                if let Some(events) = events {
+                    handle_device_events(&server, events).await
+                }
+            }
+        });
+        let t6 = tokio::spawn({
+            let server = Arc::clone(&self);
+            async move { report_hashrates(&server).await }
+        });
+        let t7 = tokio::spawn({
+            let server = Arc::clone(&self);
+            async move { run_block_status_checks(&server).await }
+        });
         t1.await?;
         t2.await?;
+        t3.await?;
+        t4.await?;
+        t5.await?;
+        t6.await?;
+        t7.await?;
         Ok(())
     }
 
@@ -147,21 +302,75 @@ impl Server {
         self.node_settings.lock().await
     }
 
-    pub fn miner<'a>(&'a self) -> std::sync::MutexGuard<'a, Miner> {
-        self.miner.lock().unwrap()
+    pub fn set_intensity(&self, intensity: i32) {
+        self.mining_settings.lock().unwrap().kernel_size = 1 << intensity;
+        for device in self.devices.read().unwrap().iter() {
+            device.set_intensity(intensity);
+        }
+    }
+
+    /// Replaces the mining devices if `gpu_indices` differ from the current ones.
+    pub fn update_gpu_indices(&self, gpu_indices: Vec<usize>) -> Result<()> {
+        let mut mining_settings = self.mining_settings.lock().unwrap();
+        if mining_settings.gpu_indices == gpu_indices {
+            return Ok(());
+        }
+        let mut new_settings = mining_settings.clone();
+        new_settings.gpu_indices = gpu_indices;
+        let new_devices = setup_devices(&new_settings, &self.log)?;
+        *mining_settings = new_settings;
+        let mut devices = self.devices.write().unwrap();
+        for device in devices.iter() {
+            device.stop();
+        }
+        *devices = new_devices;
+        self.log.clear_device_hashrates();
+        self.devices_changed.notify_one();
+        Ok(())
     }
 
     pub fn log(&self) -> &Log {
         &self.log
     }
+
+    /// Blocks found so far, including those of earlier runs.
+    pub fn ledger(&self) -> &Ledger {
+        &self.ledger
+    }
 }
 
-async fn init_request(server: &Server) -> RequestBuilder {
-    let node_settings = server.node_settings.lock().await;
-    server.client.post(&node_settings.bitcoind_url).basic_auth(
-        &node_settings.bitcoind_user,
-        Some(&node_settings.bitcoind_password),
-    )
+/// Sets up the miners of `mining_settings`, leaving out devices that fail
+/// the self-test.
+fn setup_devices(mining_settings: &MiningSettings, log: &Log) -> Result<Vec<Arc<MiningDevice>>> {
+    let miners = match mining_settings.autotune_latency {
+        Some(target_latency) => setup_tuned_devices(mining_settings, target_latency, log)?,
+        None => Miner::setup_devices(mining_settings.clone())?,
+    };
+    Ok(miners
+        .into_iter()
+        .enumerate()
+        .filter_map(|(device_idx, mut miner)| {
+            if let Err(err) = self_test(&mut miner) {
+                log.error(format!(
+                    "Device {} ({}) failed the self-test, not mining on it: {}",
+                    device_idx,
+                    miner.device_name(),
+                    err
+                ));
+                return None;
+            }
+            Some(Arc::new(MiningDevice::new(device_idx, miner)))
+        })
+        .collect())
+}
+
+/// Sends a JSON-RPC request to the active node.
+async fn send_request(server: &Server, body: String) -> Result<(StatusCode, String), NodeError> {
+    let node = {
+        let node_settings = server.node_settings.lock().await;
+        node_settings.nodes[node_settings.active_node].clone()
+    };
+    send_node_request(server, &node, body, None).await
 }
 
 fn display_hash(hash: &[u8]) -> String {
@@ -171,17 +380,27 @@ fn display_hash(hash: &[u8]) -> String {
 }
 
 async fn update_next_block(server: &Server) -> Result<(), Box<dyn std::error::Error>> {
+    let work_source = server.node_settings.lock().await.work_source;
+    match work_source {
+        WorkSource::RawUnsolvedBlock => update_next_block_from_unsolved(server).await,
+        WorkSource::BlockTemplate => update_next_block_from_template(server).await,
+        WorkSource::Stratum => Ok(()),
+    }
+}
+
+async fn update_next_block_from_unsolved(
+    server: &Server,
+) -> Result<(), Box<dyn std::error::Error>> {
     let log = server.log();
-    let response = init_request(&server)
-        .await
-        .body(format!(
+    let miner_addr = server.node_settings.lock().await.miner_addr.clone();
+    let (status, response_str) = send_request(
+        server,
+        format!(
             r#"{{"method":"getrawunsolvedblock","params":["{}"]}}"#,
-            server.node_settings.lock().await.miner_addr
-        ))
-        .send()
-        .await?;
-    let status = response.status();
-    let response_str = response.text().await?;
+            miner_addr
+        ),
+    )
+    .await?;
     let response: Result<GetRawUnsolvedBlockResponse, _> = serde_json::from_str(&response_str);
     let response = match response {
         Ok(response) => response,
@@ -191,7 +410,7 @@ async fn update_next_block(server: &Server) -> Result<(), Box<dyn std::error::Er
                 status, response_str
             ));
             if status == StatusCode::UNAUTHORIZED {
-                log.error("It seems you specified the wrong username/password");
+                log.error("It seems you specified the wrong username/password or cookie file");
             }
             return Ok(());
         }
@@ -207,134 +426,245 @@ async fn update_next_block(server: &Server) -> Result<(), Box<dyn std::error::Er
             return Ok(());
         }
     };
-    let block = create_block(&unsolved_block);
+    let block = match create_block(&unsolved_block, &miner_addr) {
+        Ok(block) => block,
+        Err(err) => {
+            log.error(format!("Invalid block from getrawunsolvedblock: {}", err));
+            return Ok(());
+        }
+    };
+    log_chain_tip(log, &block_state, &block);
+    block_state.extra_nonce += 1;
+    block_state.template = None;
+    set_current_block(server, &mut block_state, Some(block));
+    Ok(())
+}
+
+async fn update_next_block_from_template(
+    server: &Server,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let log = server.log();
+    let template = match fetch_block_template(server).await? {
+        Some(template) => template,
+        None => return Ok(()),
+    };
+    let miner_addr = server.node_settings.lock().await.miner_addr.clone();
+    let mut block_state = server.block_state.lock().await;
+    block_state.extra_nonce += 1;
+    let block = match create_block_from_template(&template, &miner_addr, block_state.extra_nonce) {
+        Ok(block) => block,
+        Err(err) => {
+            log.error(format!("Invalid block template: {}", err));
+            return Ok(());
+        }
+    };
+    log_chain_tip(log, &block_state, &block);
+    block_state.template = Some(template);
+    set_current_block(server, &mut block_state, Some(block));
+    Ok(())
+}
+
+/// Asks the active node for a block template; `None` if it couldn't give
+/// us one, which is logged.
+async fn fetch_block_template(
+    server: &Server,
+) -> Result<Option<BlockTemplate>, Box<dyn std::error::Error>> {
+    let log = server.log();
+    let (status, response_str) = send_request(
+        server,
+        r#"{"method":"getblocktemplate","params":[]}"#.to_string(),
+    )
+    .await?;
+    let response: Result<GetBlockTemplateResponse, _> = serde_json::from_str(&response_str);
+    let response = match response {
+        Ok(response) => response,
+        Err(_) => {
+            log.error(format!(
+                "getblocktemplate failed ({}): {}",
+                status, response_str
+            ));
+            if status == StatusCode::UNAUTHORIZED {
+                log.error("It seems you specified the wrong username/password or cookie file");
+            }
+            return Ok(None);
+        }
+    };
+    if response.result.is_none() {
+        log.error(format!(
+            "getblocktemplate failed: {}",
+            response.error.unwrap_or("unknown error".to_string())
+        ));
+    }
+    Ok(response.result)
+}
+
+/// Makes `block` the block all devices mine on, or stops them with `None`.
+fn set_current_block(server: &Server, block_state: &mut BlockState, block: Option<Block>) {
+    let job = block.map(|block| Arc::new(MiningJob::new(block)));
+    block_state.current_block = job.as_ref().map(|job| Arc::clone(&job.block));
+    server.work_sender.send_replace(job);
+}
+
+/// Rebuilds the current template's block with a new extra nonce, giving
+/// fresh nonce space without asking the node for new work.
+async fn roll_extra_nonce(
+    server: &Server,
+    exhausted: &Arc<Block>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let log = server.log();
+    let miner_addr = server.node_settings.lock().await.miner_addr.clone();
+    let mut block_state = server.block_state.lock().await;
+    // Every device reports the exhausted block, but it's rolled only once
+    if !matches!(&block_state.current_block, Some(block) if Arc::ptr_eq(block, exhausted)) {
+        return Ok(());
+    }
+    let template = match &block_state.template {
+        Some(template) => template,
+        None => {
+            log.error(
+                "Error: Exhaustively searched nonces. This could be fixed by lowering \
+                       rpc_poll_interval.",
+            );
+            return Ok(());
+        }
+    };
+    let block = create_block_from_template(template, &miner_addr, block_state.extra_nonce + 1)?;
+    block_state.extra_nonce += 1;
+    log.info(format!(
+        "Searched all nonces, rolled extra nonce to {}",
+        block_state.extra_nonce
+    ));
+    set_current_block(server, &mut block_state, Some(block));
+    Ok(())
+}
+
+fn log_chain_tip(log: &Log, block_state: &BlockState, block: &Block) {
     if let Some(current_block) = &block_state.current_block {
         if current_block.prev_hash() != block.prev_hash() {
             log.info(format!(
                 "Switched to new chain tip: {}",
-                display_hash(&block.prev_hash())
+                display_hash(block.prev_hash())
             ));
         }
     } else {
         log.info(format!(
             "Started mining on chain tip: {}",
-            display_hash(&block.prev_hash())
+            display_hash(block.prev_hash())
         ));
     }
-    block_state.extra_nonce += 1;
-    block_state.next_block = Some(block);
-    Ok(())
 }
 
-async fn mine_some_nonces(server: ServerRef) -> Result<()> {
+/// Handles what the device threads report until the server shuts down.
+async fn handle_device_events(server: &Server, mut events: UnboundedReceiver<DeviceEvent>) {
     let log = server.log();
-    let mut block_state = server.block_state.lock().await;
-    if let Some(next_block) = block_state.next_block.take() {
-        block_state.current_work = Work::from_header(next_block.header, next_block.target);
-        block_state.current_block = Some(next_block);
-    }
-    if block_state.current_block.is_none() {
-        return Ok(());
-    }
-    let mut work = block_state.current_work;
-    let big_nonce = server.rng.lock().await.gen();
-    work.set_big_nonce(big_nonce);
-    drop(block_state); // release lock
-    let (nonce, num_nonces_per_search) = tokio::task::spawn_blocking({
-        let server = Arc::clone(&server);
-        move || {
-            let log = server.log();
-            let mut miner = server.miner.lock().unwrap();
-            if !miner.has_nonces_left(&work) {
-                log.error(format!(
-                    "Error: Exhaustively searched nonces. This could be fixed by lowering \
-                           rpc_poll_interval."
-                ));
-                return Ok((None, 0));
+    while let Some(event) = events.recv().await {
+        match event {
+            DeviceEvent::Found {
+                job,
+                time,
+                nonce,
+                device_name,
+            } => {
+                handle_found_nonce(server, &job.block, time, nonce, &device_name).await;
             }
-            miner
-                .find_nonce(&work, server.log())
-                .map(|nonce| (nonce, miner.num_nonces_per_search()))
-        }
-    })
-    .await
-    .unwrap()?;
-    let mut block_state = server.block_state.lock().await;
-    if let Some(nonce) = nonce {
-        work.set_big_nonce(nonce);
-        log.info(format!("Block hash below target with nonce: {}", nonce));
-        if let Some(mut block) = block_state.current_block.take() {
-            block.header = *work.header();
-            if let Err(err) = submit_block(&server, &block).await {
-                log.error(format!(
-                    "submit_block error: {:?}. This could be a connection issue.",
-                    err
-                ));
+            DeviceEvent::Exhausted(job) => {
+                if let Err(err) = roll_extra_nonce(server, &job.block).await {
+                    log.error(format!("roll_extra_nonce error: {}", err));
+                }
             }
-        } else {
-            log.bug("BUG: Found nonce but no block! Contact the developers.");
         }
     }
-    block_state.current_work.nonce_idx += 1;
-    server
-        .metrics_nonces
-        .fetch_add(num_nonces_per_search, Ordering::AcqRel);
-    let mut timestamp = server.metrics_timestamp.lock().await;
-    let elapsed = match SystemTime::now().duration_since(*timestamp) {
-        Ok(elapsed) => elapsed,
-        Err(err) => {
-            log.bug(format!(
-                "BUG: Elapsed time error: {}. Contact the developers.",
-                err
-            ));
-            return Ok(());
-        }
-    };
-    if elapsed > server.report_hashrate_interval {
-        let num_nonces = server.metrics_nonces.load(Ordering::Acquire);
-        let hashrate = num_nonces as f64 / elapsed.as_secs_f64();
-        log.report_hashrate(hashrate);
-        server.metrics_nonces.store(0, Ordering::Release);
-        *timestamp = SystemTime::now();
-    }
-    Ok(())
 }
 
-async fn submit_block(server: &Server, block: &Block) -> Result<(), Box<dyn std::error::Error>> {
-    #[derive(Deserialize)]
-    struct SubmitBlockResponse {
-        result: Option<String>,
-    }
+/// Submits a nonce `device_name` found for `block` with its timestamp rolled
+/// to `time`, as a share for pool jobs. Found blocks are recorded in the
+/// ledger, also if they are dropped before submitting.
+async fn handle_found_nonce(
+    server: &Server,
+    block: &Block,
+    time: u64,
+    nonce: u64,
+    device_name: &str,
+) {
     let log = server.log();
-    let mut serialized_block = block.header.to_vec();
-    serialized_block.extend_from_slice(&block.body);
-    let response = init_request(server)
-        .await
-        .body(format!(
-            r#"{{"method":"submitblock","params":[{:?}]}}"#,
-            hex::encode(&serialized_block)
-        ))
-        .send()
-        .await?;
-    let response: SubmitBlockResponse = serde_json::from_str(&response.text().await?)?;
-    match response.result {
-        None => log.info("BLOCK ACCEPTED!"),
-        Some(reason) => {
-            log.error(format!("REJECTED BLOCK: {}", reason));
-            if reason == "inconclusive" {
-                log.warn(
-                    "This is an orphan race; might be fixed by lowering rpc_poll_interval or \
-                          updating to the newest lotus-gpu-miner.",
-                );
-            } else {
-                log.error(
-                    "Something is misconfigured; make sure you run the latest \
-                          lotusd/Lotus-QT and lotus-gpu-miner.",
-                );
+    let mut block = block.clone();
+    block.header.time = time;
+    block.header.nonce = nonce;
+    let difficulty = hash_difficulty(&block.header.hash());
+    if let Some(job) = &block.job {
+        log.info(format!(
+            "Share found for job {} with nonce: {} (difficulty {:.3})",
+            job.job_id, nonce, difficulty
+        ));
+        match server.stratum.lock().await.as_mut() {
+            Some(connection) => {
+                if let Err(err) = connection.submit_share(job, time, nonce).await {
+                    log.error(format!("Submitting share failed: {}", err));
+                }
             }
+            None => log.warn("Dropping share, not connected to the pool"),
         }
+        return;
+    }
+    let mut block_state = server.block_state.lock().await;
+    log.info(format!(
+        "Block hash below target with nonce: {} (difficulty {:.3})",
+        nonce, difficulty
+    ));
+    // Batches enqueued before a tip change can still come back with a nonce
+    let is_current = matches!(
+        &block_state.current_block,
+        Some(current_block) if current_block.prev_hash() == block.prev_hash()
+    );
+    if !is_current {
+        drop(block_state);
+        log.warn("Dropping nonce found for an outdated block");
+        record_dropped_block(server, &block, device_name, "Outdated block").await;
+        return;
+    }
+    // Stop mining until the node gives us the next block
+    set_current_block(server, &mut block_state, None);
+    // Submitting can take seconds of retries, new work mustn't wait for it
+    drop(block_state);
+    let result = submit_block(server, &block).await;
+    if let Err(err) = &result {
+        log_submit_error(log, err);
+    }
+    record_found_block(server, &block, device_name, &result).await;
+}
+
+/// Reports the hashrate of each device and the total every
+/// `report_hashrate_interval`, from the nonces the device threads counted.
+async fn report_hashrates(server: &Server) {
+    let mut timestamp = Instant::now();
+    loop {
+        tokio::time::sleep(server.report_hashrate_interval).await;
+        let elapsed = timestamp.elapsed().as_secs_f64();
+        timestamp = Instant::now();
+        let devices = server.devices.read().unwrap().clone();
+        let mut total_hashrate = 0.0;
+        for device in &devices {
+            let num_nonces = device.metrics_nonces.swap(0, Ordering::AcqRel);
+            let hashrate = num_nonces as f64 / elapsed;
+            server.log.report_device_hashrate(
+                device.device_idx,
+                device.device_name.clone(),
+                hashrate,
+            );
+            total_hashrate += hashrate;
+        }
+        let difficulty = match &server.block_state.lock().await.current_block {
+            Some(block) => bits_to_difficulty(block.header.bits),
+            None => None,
+        };
+        server.log.report_hashrate(total_hashrate, difficulty);
+    }
+}
+
+impl Default for Log {
+    fn default() -> Self {
+        Self::new()
     }
-    Ok(())
 }
 
 impl Log {
@@ -342,6 +672,8 @@ impl Log {
         Log {
             logs: std::sync::RwLock::new(Vec::new()),
             hashrates: std::sync::RwLock::new(Vec::new()),
+            device_hashrates: std::sync::RwLock::new(BTreeMap::new()),
+            active_node: std::sync::RwLock::new(None),
         }
     }
 
@@ -381,10 +713,11 @@ impl Log {
         logs.drain(..).collect()
     }
 
-    pub fn report_hashrate(&self, hashrate: f64) {
+    pub fn report_hashrate(&self, hashrate: f64, difficulty: Option<f64>) {
         let mut hashrates = self.hashrates.write().unwrap();
         hashrates.push(HashrateEntry {
             hashrate,
+            difficulty,
             timestamp: chrono::Local::now(),
         });
     }
@@ -392,6 +725,45 @@ impl Log {
     pub fn hashrates<'a>(&'a self) -> std::sync::RwLockReadGuard<'a, Vec<HashrateEntry>> {
         self.hashrates.read().unwrap()
     }
+
+    pub fn report_device_hashrate(&self, device_idx: usize, device_name: String, hashrate: f64) {
+        let mut device_hashrates = self.device_hashrates.write().unwrap();
+        device_hashrates.insert(
+            device_idx,
+            DeviceHashrateEntry {
+                device_idx,
+                device_name,
+                hashrate,
+                timestamp: chrono::Local::now(),
+            },
+        );
+    }
+
+    /// Latest hashrate of each mining device, by device index.
+    pub fn device_hashrates<'a>(
+        &'a self,
+    ) -> std::sync::RwLockReadGuard<'a, BTreeMap<usize, DeviceHashrateEntry>> {
+        self.device_hashrates.read().unwrap()
+    }
+
+    pub fn clear_device_hashrates(&self) {
+        self.device_hashrates.write().unwrap().clear();
+    }
+
+    pub fn report_active_node(&self, url: String) {
+        let mut active_node = self.active_node.write().unwrap();
+        let switches = active_node.as_ref().map_or(0, |entry| entry.switches);
+        *active_node = Some(ActiveNodeEntry {
+            url,
+            switches: switches + 1,
+            timestamp: chrono::Local::now(),
+        });
+    }
+
+    /// The node we last switched to; `None` if we never switched nodes.
+    pub fn active_node(&self) -> Option<ActiveNodeEntry> {
+        self.active_node.read().unwrap().clone()
+    }
 }
 
 impl Display for LogEntry {
@@ -406,6 +778,14 @@ impl Display for LogEntry {
     }
 }
 
+impl HashrateEntry {
+    /// Expected time until this hashrate finds a block at the network
+    /// difficulty.
+    pub fn expected_time_to_block(&self) -> Option<Duration> {
+        expected_time_to_block(self.hashrate, self.difficulty?)
+    }
+}
+
 impl Display for HashrateEntry {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
@@ -413,6 +793,173 @@ impl Display for HashrateEntry {
             "{} Hashrate {:.3} MH/s",
             self.timestamp.to_rfc3339(),
             self.hashrate / 1_000_000.0
+        )?;
+        if let (Some(difficulty), Some(time)) = (self.difficulty, self.expected_time_to_block()) {
+            write!(
+                f,
+                ", difficulty {:.3}, expected time to block {}",
+                difficulty,
+                format_duration(time)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for DeviceHashrateEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} Device {} ({}) Hashrate {:.3} MH/s",
+            self.timestamp.to_rfc3339(),
+            self.device_idx,
+            self.device_name,
+            self.hashrate / 1_000_000.0
         )
     }
 }
+
+impl Display for ActiveNodeEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} Active node {} ({} switches)",
+            self.timestamp.to_rfc3339(),
+            self.url,
+            self.switches
+        )
+    }
+}
+
+#[tokio::test]
+async fn test_mine_and_submit_block() {
+    use difficulty::hash_below_target;
+    use lotus_miner_mock_node::{MockNode, MINER_ADDR};
+    use settings::test_config;
+    use sha256::lotus_hash;
+
+    let node = MockNode::start().await.unwrap();
+    let config = ConfigSettings {
+        rpc_url: node.url(),
+        work_source: "getrawunsolvedblock".to_string(),
+        mine_to_address: MINER_ADDR.to_string(),
+        ..test_config()
+    };
+    let blocks_file = config.blocks_file().unwrap();
+    let server: ServerRef = Arc::new(Server::from_config(config, Duration::from_secs(10)).unwrap());
+    tokio::spawn({
+        let server = Arc::clone(&server);
+        async move { server.run().await.unwrap() }
+    });
+    let blocks = tokio::time::timeout(Duration::from_secs(30), node.wait_for_submitted_blocks(1))
+        .await
+        .expect("no block submitted");
+    let unsolved_block = node.unsolved_block();
+    assert_eq!(blocks[0].len(), unsolved_block.len());
+    assert_eq!(&blocks[0][..44], &unsolved_block[..44]);
+    assert_eq!(&blocks[0][52..], &unsolved_block[52..]);
+    let header = blocks[0][..160].try_into().unwrap();
+    assert!(hash_below_target(&lotus_hash(&header), &node.target()));
+
+    // The block is recorded once the node answered
+    let found_block = loop {
+        if let Some(found_block) = server.ledger().blocks().into_iter().next() {
+            break found_block;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    };
+    assert_eq!(found_block.height, 1000);
+    assert_eq!(found_block.status, BlockStatus::Accepted);
+    let _ = std::fs::remove_file(blocks_file);
+}
+
+#[tokio::test]
+async fn test_node_faults() {
+    use lotus_miner_mock_node::{Fault, MockNode, MINER_ADDR};
+    use settings::test_config;
+    use submit::SubmitError;
+
+    fn logged(server: &Server, msg: &str) -> bool {
+        let logs = server.log().get_logs_and_clear();
+        logs.iter().any(|entry| entry.msg.contains(msg))
+    }
+
+    let node = MockNode::start().await.unwrap();
+    let config = ConfigSettings {
+        rpc_url: node.url(),
+        work_source: "getrawunsolvedblock".to_string(),
+        mine_to_address: MINER_ADDR.to_string(),
+        ..test_config()
+    };
+    let blocks_file = config.blocks_file().unwrap();
+    let server = Server::from_config(config, Duration::from_secs(10)).unwrap();
+
+    node.inject_fault(Fault::Unauthorized);
+    update_next_block(&server).await.unwrap();
+    assert!(logged(&server, "wrong username/password"));
+    node.inject_fault(Fault::MalformedJson);
+    update_next_block(&server).await.unwrap();
+    assert!(logged(&server, "getrawunsolvedblock failed"));
+    assert!(server.block_state.lock().await.current_block.is_none());
+
+    update_next_block(&server).await.unwrap();
+    let block = server
+        .block_state
+        .lock()
+        .await
+        .current_block
+        .take()
+        .unwrap();
+    assert_eq!(block.target, node.target());
+    // Not solved yet, so it's not sent to the node
+    let mut block = Block::clone(&block);
+    while difficulty::hash_below_target(&block.header.hash(), &block.target) {
+        block.header.nonce += 1;
+    }
+    let err = submit_block(&server, &block).await.unwrap_err();
+    assert!(matches!(err, SubmitError::HashAboveTarget(_)));
+    while !difficulty::hash_below_target(&block.header.hash(), &block.target) {
+        block.header.nonce += 1;
+    }
+
+    // The answer got lost, the retry finds the block already accepted
+    node.inject_fault(Fault::DropSubmission);
+    submit_block(&server, &block).await.unwrap();
+    assert!(logged(&server, "by an earlier attempt"));
+    node.inject_fault(Fault::Inconclusive);
+    let err = submit_block(&server, &block).await.unwrap_err();
+    log_submit_error(server.log(), &err);
+    assert!(logged(&server, "orphan race"));
+    assert_eq!(node.submitted_blocks().len(), 3);
+
+    // The node moved on to another block
+    let mut unsolved_block = node.unsolved_block();
+    unsolved_block[0] = 0x22;
+    node.set_unsolved_block(unsolved_block);
+    let err = submit_block(&server, &block).await.unwrap_err();
+    assert!(matches!(err, SubmitError::StaleTip(..)));
+    assert_eq!(node.submitted_blocks().len(), 3);
+    assert_eq!(
+        node.methods(),
+        vec![
+            "getrawunsolvedblock",
+            "getrawunsolvedblock",
+            "getrawunsolvedblock",
+            "getbestblockhash",
+            "submitblock",
+            "submitblock",
+            "getbestblockhash",
+            "submitblock",
+            "getbestblockhash",
+        ]
+    );
+
+    // Nonces found for an outdated block are recorded, but not submitted
+    let (time, nonce) = (block.header.time, block.header.nonce);
+    handle_found_nonce(&server, &block, time, nonce, "cpu").await;
+    assert_eq!(node.submitted_blocks().len(), 3);
+    let found_block = server.ledger().blocks().pop().unwrap();
+    assert_eq!(found_block.status, BlockStatus::Dropped);
+    assert_eq!(found_block.submit_result, "Outdated block");
+    let _ = std::fs::remove_file(blocks_file);
+}
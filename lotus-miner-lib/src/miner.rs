@@ -1,18 +1,32 @@
-use ocl::{
-    builders::{DeviceSpecifier, ProgramBuilder},
-    Buffer, Context, Device, Kernel, Platform, Queue,
-};
-use sha2::Digest;
-use std::convert::TryInto;
+use std::{convert::TryInto, str::FromStr, time::Duration};
 use eyre::Result;
 use thiserror::Error;
 
-use crate::{sha256::lotus_hash, Log};
+use crate::{
+    block::LotusHeader, cpu::CpuBackend, difficulty::hash_below_target, nonce::NonceRange,
+    opencl::OpenClBackend, ConfigSettings, Log,
+};
 
 #[derive(Debug, Error)]
 pub enum MinerError {
     #[error("Ocl error: {0:?}")]
-    Ocl(ocl::Error)
+    Ocl(ocl::Error),
+    #[error("Unknown mining backend {0:?}, expected \"opencl\" or \"cpu\"")]
+    UnknownBackend(String),
+    #[error("Unknown kernel {0:?}, available kernels: {1}")]
+    UnknownKernel(String, String),
+    #[error("Couldn't read kernel {0}: {1}")]
+    ReadKernel(String, std::io::Error),
+    #[error("Kernel {0} tests {1} nonces per iteration, inner_iter_size {2} must be a multiple")]
+    InvalidIterations(String, i32, i32),
+    #[error("No GPU with index {0}")]
+    NoSuchDevice(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackendKind {
+    OpenCl,
+    Cpu,
 }
 
 #[derive(Debug, Clone)]
@@ -21,22 +35,63 @@ pub struct MiningSettings {
     pub kernel_size: u32,
     pub inner_iter_size: i32,
     pub kernel_name: String,
+    /// Kernel file, or folder to look up `kernel_name` in before the
+    /// embedded kernels.
+    pub kernel_path: Option<String>,
     pub sleep: u32,
     pub gpu_indices: Vec<usize>,
+    pub backend: BackendKind,
+    pub cpu_threads: usize,
+    /// Tune each device for this latency per `find_nonces` call at setup,
+    /// instead of using the settings above.
+    pub autotune_latency: Option<Duration>,
+}
+
+/// Something that can search a range of nonces of a `Work` for a block.
+pub trait MiningBackend: Send {
+    fn device_name(&self) -> String;
+
+    /// Nonces searched per call, starting at `Work::nonce_base`; at most
+    /// 2^32.
+    fn num_nonces_per_search(&self, settings: &MiningSettings) -> u64;
+
+    /// How many searches can be enqueued before the oldest one has to be
+    /// finished.
+    fn pipeline_depth(&self) -> usize {
+        1
+    }
+
+    /// Starts searching the nonces of `work`, without waiting for the result.
+    fn enqueue_search(&mut self, work: &Work, settings: &MiningSettings, log: &Log) -> Result<()>;
+
+    /// Waits for the oldest enqueued search and returns every nonce the
+    /// device reported, checked on the host but not filtered.
+    fn finish_search(&mut self, settings: &MiningSettings, log: &Log) -> Result<Vec<Candidate>>;
+}
+
+/// A nonce reported by a backend, with its hash recomputed on the host.
+#[derive(Debug, Clone, Copy)]
+pub struct Candidate {
+    /// The full 64-bit nonce.
+    pub nonce: u64,
+    pub hash: [u8; 32],
+    /// Whether `hash` meets the work's target; devices also report nonces
+    /// that only meet a weaker one.
+    pub meets_target: bool,
 }
 
 pub struct Miner {
-    search_kernel: Kernel,
-    header_buffer: Buffer<u32>,
-    buffer: Buffer<u32>,
+    backend: Box<dyn MiningBackend>,
     settings: MiningSettings,
+    num_in_flight: usize,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Default)]
 pub struct Work {
-    header: [u8; 160],
+    header: LotusHeader,
     target: [u8; 32],
-    pub nonce_idx: u32,
+    /// First nonce to search, as the big endian word of header bytes 44..48.
+    pub nonce_base: u32,
 }
 
 impl From<ocl::Error> for MinerError {
@@ -45,205 +100,224 @@ impl From<ocl::Error> for MinerError {
     }
 }
 
-use self::MinerError::*;
+impl FromStr for BackendKind {
+    type Err = MinerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "opencl" => Ok(BackendKind::OpenCl),
+            "cpu" => Ok(BackendKind::Cpu),
+            _ => Err(MinerError::UnknownBackend(s.to_string())),
+        }
+    }
+}
+
+impl MiningSettings {
+    pub fn from_config(config: &ConfigSettings) -> Result<Self> {
+        Ok(MiningSettings {
+            local_work_size: 256,
+            inner_iter_size: 16,
+            kernel_size: 1 << config.kernel_size,
+            kernel_name: config.kernel.clone(),
+            kernel_path: Some(config.kernel_path.clone()).filter(|path| !path.is_empty()),
+            sleep: 0,
+            gpu_indices: config.selected_gpu_indices(),
+            backend: BackendKind::from_str(&config.backend)?,
+            cpu_threads: config.cpu_threads.try_into()?,
+            autotune_latency: if config.autotune {
+                Some(Duration::from_millis(config.autotune_latency_ms.try_into()?))
+            } else {
+                None
+            },
+        })
+    }
+
+    /// Settings of each device to mine on, one per entry of `gpu_indices`.
+    /// The CPU backend always uses a single device.
+    pub fn per_device(&self) -> Vec<MiningSettings> {
+        match self.backend {
+            BackendKind::OpenCl => self
+                .gpu_indices
+                .iter()
+                .map(|&gpu_index| MiningSettings {
+                    gpu_indices: vec![gpu_index],
+                    ..self.clone()
+                })
+                .collect(),
+            BackendKind::Cpu => vec![self.clone()],
+        }
+    }
+}
 
 impl Work {
-    pub fn from_header(header: [u8; 160], target: [u8; 32]) -> Work {
+    pub fn from_header(header: LotusHeader, target: [u8; 32]) -> Work {
         Work {
             header,
             target,
-            nonce_idx: 0,
+            nonce_base: 0,
         }
     }
 
     pub fn set_big_nonce(&mut self, big_nonce: u64) {
-        self.header[44..52].copy_from_slice(&big_nonce.to_le_bytes());
+        self.header.nonce = big_nonce;
     }
 
-    pub fn header(&self) -> &[u8; 160] {
+    pub fn set_time(&mut self, time: u64) {
+        self.header.time = time;
+    }
+
+    /// Prepares searching `range`, a batch handed out by a `NonceAllocator`.
+    pub fn set_nonce_range(&mut self, range: &NonceRange) {
+        self.header.time = range.time;
+        self.header.nonce = with_nonce_word(u64::from(range.upper_nonce) << 32, range.nonce_base);
+        self.nonce_base = range.nonce_base;
+    }
+
+    pub fn header(&self) -> &LotusHeader {
         &self.header
     }
+
+    /// The serialized header, as the backends hash it.
+    pub fn header_bytes(&self) -> [u8; 160] {
+        self.header.to_bytes()
+    }
+
+    pub fn target(&self) -> &[u8; 32] {
+        &self.target
+    }
 }
 
-impl Default for Work {
-    fn default() -> Self {
-        Work {
-            header: [0; 160],
-            target: [0; 32],
-            nonce_idx: 0,
-        }
+/// Recomputes the hash of a nonce reported by a backend on the host and logs
+/// it. `nonce` is the big endian word of header bytes 44..48, like
+/// `Work::nonce_base`.
+pub fn check_candidate(work: &Work, nonce: u32, log: &Log) -> Candidate {
+    let mut header = work.header;
+    header.nonce = with_nonce_word(header.nonce, nonce);
+    let result_nonce = header.nonce;
+    let hash = header.hash();
+    let mut candidate_hash = hash;
+    candidate_hash.reverse();
+    log.info(format!(
+        "Candidate: nonce={}, hash={}",
+        result_nonce,
+        hex::encode(candidate_hash)
+    ));
+    Candidate {
+        nonce: result_nonce,
+        hash,
+        meets_target: hash_below_target(&hash, work.target()),
     }
 }
 
+/// Replaces header bytes 44..48 of `big_nonce` with the big endian
+/// `nonce_word` the backends count in.
+fn with_nonce_word(big_nonce: u64, nonce_word: u32) -> u64 {
+    big_nonce & !0xffff_ffff | u64::from(nonce_word.swap_bytes())
+}
+
 impl Miner {
     pub fn setup(settings: MiningSettings) -> Result<Self> {
-        let mut prog_builder = ProgramBuilder::new();
-        prog_builder
-            .src_file(format!("kernels/{}.cl", settings.kernel_name))
-            .cmplr_def("WORKSIZE", settings.local_work_size)
-            .cmplr_def("ITERATIONS", settings.inner_iter_size);
-        let platforms = Platform::list();
-        println!("Platforms:");
-        for (platform_idx, platform) in platforms.iter().enumerate() {
-            println!(
-                "{}: {}",
-                platform_idx,
-                platform.name().unwrap_or("<invalid platform>".to_string())
-            );
-            let devices = Device::list_all(platform).map_err(Ocl)?;
-            for (device_idx, device) in devices.iter().enumerate() {
-                println!("- device {}: {}", device_idx, device.name().map_err(Ocl)?);
-            }
-        }
-        let mut platform_device = None;
-        let mut gpu_index = 0;
-        for cur_platform in platforms {
-            if let Ok(devices) = Device::list_all(cur_platform.clone()) {
-                for cur_device in devices {
-                    if gpu_index == settings.gpu_indices[0] {
-                        platform_device = Some((cur_platform, cur_device));
-                    }
-                    gpu_index += 1;
-                }
-            }
-        }
-        let (platform, device) = platform_device.expect("No such GPU");
-        let ctx = Context::builder()
-            .platform(platform.clone())
-            .devices(DeviceSpecifier::Single(device.clone()))
-            .build().map_err(Ocl)?;
-        let queue = Queue::new(&ctx, device, None).map_err(Ocl)?;
-        prog_builder.devices(DeviceSpecifier::Single(device.clone()));
-        let program = prog_builder.build(&ctx).map_err(Ocl)?;
-        let mut kernel_builder = Kernel::builder();
-        kernel_builder
-            .program(&program)
-            .name("search")
-            .queue(queue.clone());
-        let buffer = Buffer::builder().len(0xff).queue(queue.clone()).build().map_err(Ocl)?;
-        let header_buffer = Buffer::builder().len(0xff).queue(queue).build().map_err(Ocl)?;
-        let search_kernel = kernel_builder
-            .arg_named("offset", 0u32)
-            .arg_named("partial_header", None::<&Buffer<u32>>)
-            .arg_named("output", None::<&Buffer<u32>>)
-            .build().map_err(Ocl)?;
+        let backend: Box<dyn MiningBackend> = match settings.backend {
+            BackendKind::OpenCl => Box::new(OpenClBackend::setup(&settings)?),
+            BackendKind::Cpu => Box::new(CpuBackend::setup(&settings)?),
+        };
         Ok(Miner {
-            search_kernel,
-            buffer,
-            header_buffer,
+            backend,
             settings,
+            num_in_flight: 0,
         })
     }
 
-    pub fn list_device_names() -> Vec<String> {
-        let platforms = Platform::list();
-        let mut device_names = Vec::new();
-        for platform in platforms.iter() {
-            let platform_name = platform.name().unwrap_or("<invalid platform>".to_string());
-            let devices = Device::list_all(platform).unwrap_or(vec![]);
-            for device in devices.iter() {
-                device_names.push(format!(
-                    "{} - {}",
-                    platform_name,
-                    device.name().unwrap_or("<invalid device>".to_string())
-                ));
-            }
+    /// Wraps a backend set up elsewhere, e.g. a fake one in tests.
+    #[cfg(test)]
+    pub(crate) fn with_backend(backend: Box<dyn MiningBackend>, settings: MiningSettings) -> Self {
+        Miner {
+            backend,
+            settings,
+            num_in_flight: 0,
         }
-        device_names
     }
 
-    pub fn has_nonces_left(&self, work: &Work) -> bool {
-        work.nonce_idx
-            .checked_mul(self.settings.kernel_size)
-            .is_some()
+    /// Sets up one miner per entry of `settings.gpu_indices`, each with its
+    /// own device state. The CPU backend always uses a single miner.
+    pub fn setup_devices(settings: MiningSettings) -> Result<Vec<Self>> {
+        settings.per_device().into_iter().map(Miner::setup).collect()
     }
 
-    pub fn num_nonces_per_search(&self) -> u64 {
-        self.settings.kernel_size as u64 * self.settings.inner_iter_size as u64
-    }
-
-    pub fn find_nonce(&mut self, work: &Work, log: &Log) -> Result<Option<u64>> {
-        let base = match work
-            .nonce_idx
-            .checked_mul(self.num_nonces_per_search().try_into().unwrap())
-        {
-            Some(base) => base,
-            None => {
-                log.error(
-                    "Error: Nonce base overflow, skipping. This could be fixed by lowering \
-                           rpc_poll_interval.",
-                );
-                return Ok(None);
-            }
-        };
-        let mut partial_header = [0u8; 84];
-        partial_header[..52].copy_from_slice(&work.header[..52]);
-        partial_header[52..].copy_from_slice(&sha2::Sha256::digest(&work.header[52..]));
-        let mut partial_header_ints = [0u32; 21];
-        for (chunk, int) in partial_header.chunks(4).zip(partial_header_ints.iter_mut()) {
-            *int = u32::from_be_bytes(chunk.try_into().unwrap());
-        }
-        self.header_buffer.write(&partial_header_ints[..]).enq().map_err(Ocl)?;
-        self.search_kernel
-            .set_arg("partial_header", &self.header_buffer).map_err(Ocl)?;
-        self.search_kernel.set_arg("output", &self.buffer).map_err(Ocl)?;
-        self.search_kernel.set_arg("offset", base).map_err(Ocl)?;
-        let mut vec = vec![0; self.buffer.len()];
-        self.buffer.write(&vec).enq().map_err(Ocl)?;
-        let cmd = self
-            .search_kernel
-            .cmd()
-            .global_work_size(self.settings.kernel_size);
-        unsafe {
-            cmd.enq().map_err(Ocl)?;
-        }
-        self.buffer.read(&mut vec).enq().map_err(Ocl)?;
-        if vec[0x80] != 0 {
-            let mut header = work.header;
-            'nonce: for &nonce in &vec[..0x7f] {
-                let nonce = nonce.swap_bytes();
-                if nonce != 0 {
-                    header[44..48].copy_from_slice(&nonce.to_le_bytes());
-                    let result_nonce = u64::from_le_bytes(header[44..52].try_into().unwrap());
-                    let hash = lotus_hash(&header);
-                    let mut candidate_hash = hash;
-                    candidate_hash.reverse();
-                    log.info(format!(
-                        "Candidate: nonce={}, hash={}",
-                        result_nonce,
-                        hex::encode(&candidate_hash)
-                    ));
-                    if hash.last() != Some(&0) {
-                        log.bug(
-                            "BUG: found nonce's hash has no leading zero byte. Contact the \
-                                   developers.",
-                        );
-                    }
-                    for (&h, &t) in hash.iter().zip(work.target.iter()).rev() {
-                        if h > t {
-                            continue 'nonce;
-                        }
-                        if t > h {
-                            return Ok(Some(result_nonce));
-                        }
-                    }
-                }
+    pub fn list_device_names() -> Vec<String> {
+        OpenClBackend::list_device_names()
+    }
+
+    pub fn device_name(&self) -> String {
+        self.backend.device_name()
+    }
+
+    /// Name of the device `settings` selects, as `device_name` reports it,
+    /// without building a kernel for it.
+    pub fn device_name_of(settings: &MiningSettings) -> Result<String> {
+        match settings.backend {
+            BackendKind::OpenCl => {
+                let gpu_index = settings.gpu_indices[0];
+                Ok(OpenClBackend::device_name_of(gpu_index)
+                    .ok_or(MinerError::NoSuchDevice(gpu_index))?)
             }
+            BackendKind::Cpu => Ok(CpuBackend::device_name_of(settings)),
         }
-        Ok(None)
     }
 
-    pub fn set_intensity(&mut self, intensity: i32) {
-        self.settings.kernel_size = 1 << intensity;
+    pub fn num_nonces_per_search(&self) -> u64 {
+        self.backend.num_nonces_per_search(&self.settings)
     }
 
-    pub fn update_gpu_index(&mut self, gpu_index: i64) -> Result<()> {
-        if self.settings.gpu_indices[0] == gpu_index as usize {
-            return Ok(());
-        }
-        let mut settings = self.settings.clone();
-        settings.gpu_indices = vec![gpu_index.try_into().unwrap()];
-        *self = Miner::setup(settings)?;
+    /// Searches the nonces of `work` and waits for the result. Must not be
+    /// mixed with pipelined searches still in flight.
+    pub fn find_nonces(&mut self, work: &Work, log: &Log) -> Result<Vec<u64>> {
+        debug_assert_eq!(self.num_in_flight, 0);
+        self.enqueue_search(work, log)?;
+        self.finish_search(log)
+    }
+
+    pub fn pipeline_depth(&self) -> usize {
+        self.backend.pipeline_depth()
+    }
+
+    pub fn num_searches_in_flight(&self) -> usize {
+        self.num_in_flight
+    }
+
+    /// Starts searching `work`; at most `pipeline_depth` searches can be in
+    /// flight at once. Results come back in order from `finish_search`.
+    pub fn enqueue_search(&mut self, work: &Work, log: &Log) -> Result<()> {
+        self.backend.enqueue_search(work, &self.settings, log)?;
+        self.num_in_flight += 1;
         Ok(())
     }
+
+    /// Waits for the oldest search in flight and returns the nonces it found.
+    pub fn finish_search(&mut self, log: &Log) -> Result<Vec<u64>> {
+        Ok(self
+            .finish_search_candidates(log)?
+            .into_iter()
+            .filter(|candidate| candidate.meets_target)
+            .map(|candidate| candidate.nonce)
+            .collect())
+    }
+
+    /// Like `find_nonces`, but returns every nonce the device reported,
+    /// including those whose hash misses the target.
+    pub fn find_candidates(&mut self, work: &Work, log: &Log) -> Result<Vec<Candidate>> {
+        debug_assert_eq!(self.num_in_flight, 0);
+        self.enqueue_search(work, log)?;
+        self.finish_search_candidates(log)
+    }
+
+    fn finish_search_candidates(&mut self, log: &Log) -> Result<Vec<Candidate>> {
+        // The search is done with even if it failed
+        self.num_in_flight -= 1;
+        self.backend.finish_search(&self.settings, log)
+    }
+
+    pub fn set_intensity(&mut self, intensity: i32) {
+        self.settings.kernel_size = 1 << intensity;
+    }
 }
@@ -0,0 +1,273 @@
+use std::time::Duration;
+
+use reqwest::StatusCode;
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::{settings::NodeConfig, Server, WorkSource};
+
+/// How often all nodes are checked, so we fail back to a preferred node once
+/// it is reachable again.
+const NODE_HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+const NODE_HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Error)]
+pub enum NodeError {
+    #[error("Couldn't read cookie file {0}: {1}")]
+    ReadCookie(String, std::io::Error),
+    #[error("Invalid cookie file {0}, expected \"user:password\"")]
+    InvalidCookie(String),
+    #[error("Request to node failed: {0}")]
+    Request(#[from] reqwest::Error),
+}
+
+/// Sends a JSON-RPC request to `node`, returning the status and body. With a
+/// cookie file, a 401 makes us re-read the cookie and retry once, as lotusd
+/// writes a new cookie on every restart.
+pub(crate) async fn send_node_request(
+    server: &Server,
+    node: &NodeConfig,
+    body: String,
+    timeout: Option<Duration>,
+) -> Result<(StatusCode, String), NodeError> {
+    let mut reload_cookie = false;
+    loop {
+        let (user, password) = node_credentials(server, node, reload_cookie)?;
+        let mut request = server
+            .client
+            .post(&node.url)
+            .basic_auth(user, Some(password))
+            .body(body.clone());
+        if let Some(timeout) = timeout {
+            request = request.timeout(timeout);
+        }
+        let response = request.send().await?;
+        let status = response.status();
+        if status == StatusCode::UNAUTHORIZED && node.cookie_file.is_some() && !reload_cookie {
+            server
+                .log()
+                .info("Node rejected our cookie, re-reading cookie file");
+            reload_cookie = true;
+            continue;
+        }
+        return Ok((status, response.text().await?));
+    }
+}
+
+/// User and password for `node`, read from (and cached per) cookie file if
+/// it has one.
+fn node_credentials(
+    server: &Server,
+    node: &NodeConfig,
+    reload_cookie: bool,
+) -> Result<(String, String), NodeError> {
+    let cookie_file = match &node.cookie_file {
+        Some(cookie_file) => cookie_file,
+        None => return Ok((node.user.clone(), node.password.clone())),
+    };
+    let mut cookies = server.cookies.lock().unwrap();
+    if !reload_cookie {
+        if let Some(credentials) = cookies.get(cookie_file) {
+            return Ok(credentials.clone());
+        }
+    }
+    let cookie = std::fs::read_to_string(cookie_file)
+        .map_err(|err| NodeError::ReadCookie(cookie_file.clone(), err))?;
+    let credentials =
+        parse_cookie(&cookie).ok_or_else(|| NodeError::InvalidCookie(cookie_file.clone()))?;
+    cookies.insert(cookie_file.clone(), credentials.clone());
+    Ok(credentials)
+}
+
+/// lotusd's cookie is a single line "__cookie__:<random password>".
+fn parse_cookie(cookie: &str) -> Option<(String, String)> {
+    let (user, password) = cookie.trim().split_once(':')?;
+    Some((user.to_string(), password.to_string()))
+}
+
+/// A node is healthy if it answers `getblockcount`.
+async fn is_node_healthy(server: &Server, node: &NodeConfig) -> bool {
+    #[derive(Deserialize)]
+    struct GetBlockCountResponse {
+        result: Option<u64>,
+    }
+    let body = r#"{"method":"getblockcount","params":[]}"#.to_string();
+    let response = send_node_request(server, node, body, Some(NODE_HEALTH_CHECK_TIMEOUT)).await;
+    match response {
+        Ok((status, response)) if status.is_success() => {
+            serde_json::from_str::<GetBlockCountResponse>(&response)
+                .map(|response| response.result.is_some())
+                .unwrap_or(false)
+        }
+        _ => false,
+    }
+}
+
+/// Makes the first healthy node (in order of preference) the active one. This
+/// fails over if the active node is down, and back once a preferred node is
+/// reachable again.
+pub(crate) async fn select_active_node(server: &Server) {
+    let log = server.log();
+    let nodes = server.node_settings.lock().await.nodes.clone();
+    for (node_idx, node) in nodes.iter().enumerate() {
+        if !is_node_healthy(server, node).await {
+            continue;
+        }
+        let mut node_settings = server.node_settings.lock().await;
+        // Nodes changed in the meantime; the next check picks them up
+        if node_settings.nodes != nodes {
+            return;
+        }
+        if node_settings.active_node != node_idx {
+            log.warn(format!(
+                "Switching active node from {} to {}",
+                nodes[node_settings.active_node].url, node.url
+            ));
+            node_settings.active_node = node_idx;
+            log.report_active_node(node.url.clone());
+        }
+        return;
+    }
+    log.error("None of the configured nodes is reachable");
+}
+
+/// Periodically re-selects the active node when several are configured.
+pub(crate) async fn run_node_health_checks(server: &Server) {
+    loop {
+        tokio::time::sleep(NODE_HEALTH_CHECK_INTERVAL).await;
+        let (num_nodes, work_source) = {
+            let node_settings = server.node_settings.lock().await;
+            (node_settings.nodes.len(), node_settings.work_source)
+        };
+        if num_nodes > 1 && work_source != WorkSource::Stratum {
+            select_active_node(server).await;
+        }
+    }
+}
+
+/// Answers `getblockcount`; with `auth`, only for that Authorization header.
+#[cfg(test)]
+async fn serve_getblockcount(listener: tokio::net::TcpListener, auth: Option<&'static str>) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    loop {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        // Read the whole request so closing the socket doesn't reset it
+        let mut request = Vec::new();
+        let mut buf = [0u8; 1024];
+        loop {
+            let num_bytes = stream.read(&mut buf).await.unwrap();
+            request.extend_from_slice(&buf[..num_bytes]);
+            if num_bytes == 0 || request.ends_with(b"]}") {
+                break;
+            }
+        }
+        let request = String::from_utf8_lossy(&request);
+        let (status, body) = match auth {
+            Some(auth) if !request.contains(&format!("authorization: {}\r\n", auth)) => {
+                ("401 Unauthorized", "")
+            }
+            _ => ("200 OK", r#"{"result":1000,"error":null,"id":null}"#),
+        };
+        let response = format!(
+            "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+             Connection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        );
+        stream.write_all(response.as_bytes()).await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn test_node_failover() {
+    use crate::{settings::test_config, ConfigSettings};
+    use tokio::net::TcpListener;
+
+    // Nothing listens on the primary node's port yet
+    let primary_addr = TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let backup = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let backup_url = format!("http://{}", backup.local_addr().unwrap());
+    tokio::spawn(serve_getblockcount(backup, None));
+    let server = Server::from_config(
+        ConfigSettings {
+            rpc_url: format!("http://{}", primary_addr),
+            backup_nodes: vec![NodeConfig {
+                url: backup_url.clone(),
+                user: String::new(),
+                password: String::new(),
+                cookie_file: None,
+            }],
+            work_source: "getblocktemplate".to_string(),
+            ..test_config()
+        },
+        Duration::from_secs(10),
+    )
+    .unwrap();
+
+    select_active_node(&server).await;
+    assert_eq!(server.node_settings().await.active_node, 1);
+    let active_node = server.log().active_node().unwrap();
+    assert_eq!(active_node.url, backup_url);
+    assert_eq!(active_node.switches, 1);
+
+    // Primary is back, fail back to it
+    let primary = TcpListener::bind(primary_addr).await.unwrap();
+    tokio::spawn(serve_getblockcount(primary, None));
+    select_active_node(&server).await;
+    assert_eq!(server.node_settings().await.active_node, 0);
+    assert_eq!(server.log().active_node().unwrap().switches, 2);
+}
+
+#[test]
+fn test_parse_cookie() {
+    assert_eq!(
+        parse_cookie("__cookie__:a1b2:c3\n"),
+        Some(("__cookie__".to_string(), "a1b2:c3".to_string()))
+    );
+    assert_eq!(parse_cookie("no password"), None);
+}
+
+#[tokio::test]
+async fn test_cookie_reread_on_unauthorized() {
+    use crate::{settings::test_config, ConfigSettings};
+    use tokio::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    // base64 of "__cookie__:new"
+    tokio::spawn(serve_getblockcount(
+        listener,
+        Some("Basic X19jb29raWVfXzpuZXc="),
+    ));
+    let cookie_file =
+        std::env::temp_dir().join(format!("lotus-miner-{}.cookie", std::process::id()));
+    std::fs::write(&cookie_file, "__cookie__:old").unwrap();
+    let server = Server::from_config(
+        ConfigSettings {
+            rpc_url: url,
+            rpc_cookie_file: cookie_file.to_string_lossy().to_string(),
+            ..test_config()
+        },
+        Duration::from_secs(10),
+    )
+    .unwrap();
+    let node = server.node_settings().await.nodes[0].clone();
+    let body = r#"{"method":"getblockcount","params":[]}"#;
+    let (status, _) = send_node_request(&server, &node, body.to_string(), None)
+        .await
+        .unwrap();
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    // lotusd restarted with a new cookie
+    std::fs::write(&cookie_file, "__cookie__:new\n").unwrap();
+    let (status, _) = send_node_request(&server, &node, body.to_string(), None)
+        .await
+        .unwrap();
+    assert_eq!(status, StatusCode::OK);
+    std::fs::remove_file(&cookie_file).unwrap();
+}
@@ -0,0 +1,141 @@
+use std::{convert::TryInto, sync::Mutex};
+
+use crate::block::{LotusHeader, PoolJob};
+
+/// Furthest the header timestamp is rolled ahead of the job's. Nodes reject
+/// blocks more than 2 hours in the future; this leaves a margin for clock
+/// skew.
+pub const MAX_NTIME_ROLL: u64 = 60 * 60;
+
+/// Nonces the backends count through for one upper nonce word.
+const NONCES_PER_UPPER_WORD: u64 = 1 << 32;
+const NUM_UPPER_WORDS: u64 = 1 << 32;
+
+/// Nonces `nonce_base..nonce_base + num_nonces` as the big endian word of
+/// header bytes 44..48, with `upper_nonce` in bytes 48..52 (little endian)
+/// and the header timestamp `time`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NonceRange {
+    pub time: u64,
+    pub upper_nonce: u32,
+    pub nonce_base: u32,
+}
+
+/// Hands out disjoint nonce ranges of one job to the batches of all devices.
+///
+/// Ranges are taken in order, and never cross into the next upper nonce
+/// word, so a backend's 32-bit nonce counter can't wrap around. Once all
+/// upper words are used up, the timestamp is rolled a second forward and
+/// allocation starts over, until `MAX_NTIME_ROLL`. Pool jobs keep the
+/// pool's timestamp, which it may not accept rolled. So no header is ever
+/// searched twice.
+#[derive(Debug)]
+pub struct NonceAllocator {
+    state: Mutex<AllocatorState>,
+    max_time: u64,
+}
+
+#[derive(Debug)]
+struct AllocatorState {
+    time: u64,
+    upper_idx: u64,
+    next_base: u64,
+}
+
+impl NonceAllocator {
+    pub fn new(header: &LotusHeader, pool_job: Option<&PoolJob>) -> Self {
+        let time = header.time;
+        let max_time = match pool_job {
+            Some(_) => time,
+            None => time + MAX_NTIME_ROLL,
+        };
+        NonceAllocator {
+            state: Mutex::new(AllocatorState {
+                time,
+                upper_idx: 0,
+                next_base: 0,
+            }),
+            max_time,
+        }
+    }
+
+    /// Claims the next `num_nonces` (at most 2^32) nonces; `None` once the
+    /// job's nonce space is exhausted.
+    pub fn allocate(&self, num_nonces: u64) -> Option<NonceRange> {
+        assert!(num_nonces <= NONCES_PER_UPPER_WORD);
+        let mut state = self.state.lock().unwrap();
+        if state.next_base + num_nonces > NONCES_PER_UPPER_WORD {
+            state.upper_idx += 1;
+            state.next_base = 0;
+        }
+        if state.upper_idx == NUM_UPPER_WORDS {
+            if state.time >= self.max_time {
+                return None;
+            }
+            state.time += 1;
+            state.upper_idx = 0;
+            state.next_base = 0;
+        }
+        let range = NonceRange {
+            time: state.time,
+            upper_nonce: state.upper_idx.try_into().unwrap(),
+            nonce_base: state.next_base.try_into().unwrap(),
+        };
+        state.next_base += num_nonces;
+        Some(range)
+    }
+}
+
+#[test]
+fn test_nonce_allocator() {
+    let header = LotusHeader {
+        time: 1_624_000_000,
+        ..LotusHeader::default()
+    };
+    let nonces = NonceAllocator::new(&header, None);
+    let first = nonces.allocate(1 << 31).unwrap();
+    assert_eq!(
+        (first.time, first.upper_nonce, first.nonce_base),
+        (1_624_000_000, 0, 0)
+    );
+    let second = nonces.allocate(1 << 31).unwrap();
+    assert_eq!((second.upper_nonce, second.nonce_base), (0, 1 << 31));
+    // Doesn't fit into the rest of upper word 0 anymore
+    let third = nonces.allocate(3).unwrap();
+    assert_eq!((third.upper_nonce, third.nonce_base), (1, 0));
+    assert_eq!(nonces.allocate(1 << 32).unwrap().upper_nonce, 2);
+
+    // Pool jobs are done once all upper words are used up
+    let pool_job = PoolJob {
+        job_id: "job".to_string(),
+        extranonce2: vec![0; 4],
+    };
+    let nonces = NonceAllocator::new(&header, Some(&pool_job));
+    nonces.state.lock().unwrap().upper_idx = u32::MAX.into();
+    let last = nonces.allocate(1 << 32).unwrap();
+    assert_eq!((last.time, last.upper_nonce), (1_624_000_000, u32::MAX));
+    assert_eq!(nonces.allocate(1), None);
+
+    // Solo jobs roll the timestamp once all upper words are used up
+    let nonces = NonceAllocator::new(&header, None);
+    nonces.state.lock().unwrap().upper_idx = u32::MAX.into();
+    let last = nonces.allocate(1 << 32).unwrap();
+    assert_eq!((last.time, last.upper_nonce), (1_624_000_000, u32::MAX));
+    let rolled = nonces.allocate(1 << 32).unwrap();
+    assert_eq!(
+        (rolled.time, rolled.upper_nonce, rolled.nonce_base),
+        (1_624_000_001, 0, 0)
+    );
+
+    // ...up to `MAX_NTIME_ROLL` seconds ahead
+    let mut state = nonces.state.lock().unwrap();
+    state.time = header.time + MAX_NTIME_ROLL;
+    state.upper_idx = u32::MAX.into();
+    state.next_base = 0;
+    drop(state);
+    assert_eq!(
+        nonces.allocate(1 << 32).unwrap().time,
+        1_624_000_000 + MAX_NTIME_ROLL
+    );
+    assert_eq!(nonces.allocate(1), None);
+}
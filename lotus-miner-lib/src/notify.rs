@@ -0,0 +1,197 @@
+use std::{
+    convert::TryInto,
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+
+use thiserror::Error;
+use tokio::runtime::Handle;
+
+use crate::{Server, WorkSource};
+
+/// Polling interval while we're subscribed to ZMQ notifications; polling is
+/// only a fallback then.
+const ZMQ_FALLBACK_POLL_INTERVAL: Duration = Duration::from_secs(30);
+const ZMQ_RECONNECT_INTERVAL: Duration = Duration::from_secs(10);
+/// How often we check whether `zmq_hashblock` changed in the settings.
+const ZMQ_POLL_TIMEOUT_MS: i64 = 1000;
+const ZMQ_MONITOR_ENDPOINT: &str = "inproc://hashblock-monitor";
+
+#[derive(Debug, Error)]
+pub enum NotifyError {
+    #[error("ZMQ error: {0}")]
+    Zmq(#[from] zmq::Error),
+    #[error("Invalid hashblock message: {0:?}")]
+    InvalidMessage(Vec<Vec<u8>>),
+}
+
+/// Subscribes to lotusd's `zmqpubhashblock` at `zmq_hashblock` and wakes up
+/// the work update loop as soon as the node announces a new block.
+pub(crate) async fn run_tip_notifications(server: Arc<Server>) {
+    let log = server.log();
+    loop {
+        let (endpoint, work_source) = {
+            let node_settings = server.node_settings.lock().await;
+            (
+                node_settings.zmq_hashblock.clone(),
+                node_settings.work_source,
+            )
+        };
+        if !endpoint.is_empty() && work_source != WorkSource::Stratum {
+            let result = tokio::task::spawn_blocking({
+                let server = Arc::clone(&server);
+                let handle = Handle::current();
+                let endpoint = endpoint.clone();
+                move || receive_tip_notifications(&server, &handle, &endpoint)
+            })
+            .await
+            .unwrap();
+            server
+                .tip_notifications_active
+                .store(false, Ordering::Release);
+            if let Err(err) = result {
+                log.warn(format!(
+                    "New block notifications from {} failed, falling back to polling: {}",
+                    endpoint, err
+                ));
+            }
+        }
+        tokio::time::sleep(ZMQ_RECONNECT_INTERVAL).await;
+    }
+}
+
+/// How long the work update loop waits for a new block notification before
+/// polling the node anyway.
+pub(crate) async fn poll_interval(server: &Server) -> Duration {
+    let rpc_poll_interval =
+        Duration::from_secs(server.node_settings.lock().await.rpc_poll_interval);
+    if server.tip_notifications_active.load(Ordering::Acquire) {
+        rpc_poll_interval.max(ZMQ_FALLBACK_POLL_INTERVAL)
+    } else {
+        rpc_poll_interval
+    }
+}
+
+/// Blocks until the subscription fails or `zmq_hashblock` changes.
+fn receive_tip_notifications(
+    server: &Server,
+    handle: &Handle,
+    endpoint: &str,
+) -> Result<(), NotifyError> {
+    let log = server.log();
+    let context = zmq::Context::new();
+    let socket = context.socket(zmq::SUB)?;
+    // A SUB socket silently reconnects, so we watch its connection state to
+    // know whether we can rely on notifications
+    let events = zmq::SocketEvent::CONNECTED.to_raw() | zmq::SocketEvent::DISCONNECTED.to_raw();
+    socket.monitor(ZMQ_MONITOR_ENDPOINT, events.into())?;
+    let monitor = context.socket(zmq::PAIR)?;
+    monitor.connect(ZMQ_MONITOR_ENDPOINT)?;
+    socket.connect(endpoint)?;
+    socket.set_subscribe(b"hashblock")?;
+    let mut items = [
+        socket.as_poll_item(zmq::POLLIN),
+        monitor.as_poll_item(zmq::POLLIN),
+    ];
+    loop {
+        zmq::poll(&mut items, ZMQ_POLL_TIMEOUT_MS)?;
+        if items[1].is_readable() {
+            // First frame is the event (u16) and its value (u32)
+            let event = monitor.recv_multipart(0)?;
+            let event = u16::from_le_bytes([event[0][0], event[0][1]]);
+            if event == zmq::SocketEvent::CONNECTED.to_raw() {
+                log.info(format!("Subscribed to new blocks from {}", endpoint));
+                server
+                    .tip_notifications_active
+                    .store(true, Ordering::Release);
+            } else if event == zmq::SocketEvent::DISCONNECTED.to_raw() {
+                log.warn(format!(
+                    "Lost connection to {}, falling back to polling",
+                    endpoint
+                ));
+                server
+                    .tip_notifications_active
+                    .store(false, Ordering::Release);
+            }
+        }
+        if items[0].is_readable() {
+            let block_hash = parse_hashblock(socket.recv_multipart(0)?)?;
+            log.info(format!(
+                "Node announced new block {}, updating work",
+                hex::encode(block_hash)
+            ));
+            server.new_tip.notify_one();
+        }
+        if handle.block_on(server.node_settings.lock()).zmq_hashblock != endpoint {
+            return Ok(());
+        }
+    }
+}
+
+/// A `hashblock` message is the topic, the block hash (in display order) and
+/// a sequence number.
+fn parse_hashblock(parts: Vec<Vec<u8>>) -> Result<[u8; 32], NotifyError> {
+    match parts.as_slice() {
+        [topic, block_hash, _sequence] if topic == b"hashblock" => block_hash
+            .as_slice()
+            .try_into()
+            .map_err(|_| NotifyError::InvalidMessage(parts.clone())),
+        _ => Err(NotifyError::InvalidMessage(parts)),
+    }
+}
+
+#[tokio::test]
+async fn test_tip_notifications() {
+    use crate::{settings::test_config, ConfigSettings};
+
+    let context = zmq::Context::new();
+    let publisher = context.socket(zmq::PUB).unwrap();
+    publisher.bind("tcp://127.0.0.1:*").unwrap();
+    let endpoint = publisher.get_last_endpoint().unwrap().unwrap();
+    let server = Arc::new(
+        Server::from_config(
+            ConfigSettings {
+                zmq_hashblock: endpoint,
+                work_source: "getblocktemplate".to_string(),
+                rpc_poll_interval: 3,
+                ..test_config()
+            },
+            Duration::from_secs(10),
+        )
+        .unwrap(),
+    );
+    tokio::spawn(run_tip_notifications(Arc::clone(&server)));
+    // Messages published before the subscription is up are lost, so keep
+    // publishing until one arrives
+    for sequence in 0u32.. {
+        assert!(sequence < 100, "no new block notification received");
+        let message = [
+            b"hashblock".to_vec(),
+            vec![0xab; 32],
+            sequence.to_le_bytes().to_vec(),
+        ];
+        publisher.send_multipart(message.iter(), 0).unwrap();
+        let notified = server.new_tip.notified();
+        if tokio::time::timeout(Duration::from_millis(100), notified)
+            .await
+            .is_ok()
+        {
+            break;
+        }
+    }
+    assert!(server.tip_notifications_active.load(Ordering::Acquire));
+    assert_eq!(poll_interval(&server).await, ZMQ_FALLBACK_POLL_INTERVAL);
+    // Unsubscribe, as the runtime waits for the blocking thread on shutdown
+    server.node_settings().await.zmq_hashblock.clear();
+    while server.tip_notifications_active.load(Ordering::Acquire) {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+#[test]
+fn test_parse_hashblock() {
+    let hash = parse_hashblock(vec![b"hashblock".to_vec(), vec![1; 32], vec![0; 4]]).unwrap();
+    assert_eq!(hash, [1; 32]);
+    assert!(parse_hashblock(vec![b"hashtx".to_vec(), vec![1; 32], vec![0; 4]]).is_err());
+    assert!(parse_hashblock(vec![b"hashblock".to_vec(), vec![1; 31], vec![0; 4]]).is_err());
+}
@@ -0,0 +1,466 @@
+use ocl::{
+    builders::{DeviceSpecifier, ProgramBuilder},
+    enums::{DeviceInfo, ProgramInfo, ProgramInfoResult},
+    Buffer, Context, Device, Event, Kernel, Platform, Program, Queue,
+};
+use sha2::Digest;
+use std::{
+    collections::VecDeque,
+    ffi::CString,
+    path::{Path, PathBuf},
+};
+use eyre::Result;
+
+use crate::{
+    miner::{
+        check_candidate, Candidate, MinerError, MinerError::*, MiningBackend,
+        MiningSettings, Work,
+    },
+    kernels::{kernel_info, HeaderArgs, KernelInfo, KERNELS},
+    settings::FOLDER_DIR,
+    Log,
+};
+
+/// Folder in `~/.lotus-miner` compiled program binaries are cached in.
+const PROGRAM_CACHE_DIR: &str = "program-cache";
+
+/// Layout of the kernels' `output` buffer: the number of nonces found, an
+/// overflow flag, then up to `MAX_RESULTS` nonces.
+const RESULT_COUNT: usize = 0;
+const RESULT_OVERFLOW: usize = 1;
+const RESULTS: usize = 2;
+const MAX_RESULTS: usize = 0xfd;
+
+/// Number of searches in flight at once; the next one is enqueued while the
+/// previous one's results are read.
+const NUM_SEARCH_SLOTS: usize = 2;
+
+pub struct OpenClBackend {
+    device_name: String,
+    search_kernel: Kernel,
+    header_args: HeaderArgs,
+    slots: Vec<SearchSlot>,
+    /// Indices into `slots` of enqueued searches, oldest first.
+    in_flight: VecDeque<usize>,
+}
+
+/// Device buffers and host memory of one enqueued search. The host memory is
+/// only touched again once `done` completed.
+struct SearchSlot {
+    header_buffer: Buffer<u32>,
+    output_buffer: Buffer<u32>,
+    header: Vec<u32>,
+    output: Vec<u32>,
+    /// Completes once `output` has been read back.
+    done: Event,
+    /// `None` if the search was skipped.
+    work: Option<Work>,
+}
+
+impl OpenClBackend {
+    pub fn setup(settings: &MiningSettings) -> Result<Self> {
+        let kernel = kernel_info(&settings.kernel_name);
+        if settings.inner_iter_size % kernel.vector_width != 0 {
+            return Err(InvalidIterations(
+                settings.kernel_name.clone(),
+                kernel.vector_width,
+                settings.inner_iter_size,
+            )
+            .into());
+        }
+        let source = kernel_source(settings)?;
+        let platforms = Platform::list();
+        println!("Platforms:");
+        for (platform_idx, platform) in platforms.iter().enumerate() {
+            println!(
+                "{}: {}",
+                platform_idx,
+                platform.name().unwrap_or("<invalid platform>".to_string())
+            );
+            let devices = Device::list_all(platform).map_err(Ocl)?;
+            for (device_idx, device) in devices.iter().enumerate() {
+                println!("- device {}: {}", device_idx, device.name().map_err(Ocl)?);
+            }
+        }
+        let mut platform_device = None;
+        let mut gpu_index = 0;
+        for cur_platform in platforms {
+            if let Ok(devices) = Device::list_all(cur_platform) {
+                for cur_device in devices {
+                    if gpu_index == settings.gpu_indices[0] {
+                        platform_device = Some((cur_platform, cur_device));
+                    }
+                    gpu_index += 1;
+                }
+            }
+        }
+        let (platform, device) =
+            platform_device.ok_or(NoSuchDevice(settings.gpu_indices[0]))?;
+        let device_name = device.name().unwrap_or("<invalid device>".to_string());
+        let ctx = Context::builder()
+            .platform(platform)
+            .devices(DeviceSpecifier::Single(device))
+            .build().map_err(Ocl)?;
+        let queue = Queue::new(&ctx, device, None).map_err(Ocl)?;
+        let program = build_program(&ctx, &platform, device, &source, kernel, settings)?;
+        let mut kernel_builder = Kernel::builder();
+        kernel_builder
+            .program(&program)
+            .name("search")
+            .queue(queue.clone());
+        let mut slots = Vec::with_capacity(NUM_SEARCH_SLOTS);
+        for _ in 0..NUM_SEARCH_SLOTS {
+            let output_buffer = Buffer::builder().len(RESULTS + MAX_RESULTS).queue(queue.clone()).build().map_err(Ocl)?;
+            let header_buffer = Buffer::builder().len(0xff).queue(queue.clone()).build().map_err(Ocl)?;
+            slots.push(SearchSlot {
+                header_buffer,
+                output: vec![0; output_buffer.len()],
+                output_buffer,
+                header: Vec::new(),
+                done: Event::empty(),
+                work: None,
+            });
+        }
+        let header_args = kernel.header_args;
+        kernel_builder.arg_named("offset", 0u32);
+        for &name in header_args.names() {
+            match header_args {
+                HeaderArgs::PartialHeader => {
+                    kernel_builder.arg_named(name, None::<&Buffer<u32>>);
+                }
+                HeaderArgs::Precalc => {
+                    kernel_builder.arg_named(name, 0u32);
+                }
+            }
+        }
+        let search_kernel = kernel_builder
+            .arg_named("output", None::<&Buffer<u32>>)
+            .build().map_err(Ocl)?;
+        Ok(OpenClBackend {
+            device_name,
+            search_kernel,
+            header_args,
+            slots,
+            in_flight: VecDeque::with_capacity(NUM_SEARCH_SLOTS),
+        })
+    }
+
+    pub fn list_device_names() -> Vec<String> {
+        let platforms = Platform::list();
+        let mut device_names = Vec::new();
+        for platform in platforms.iter() {
+            let platform_name = platform.name().unwrap_or("<invalid platform>".to_string());
+            let devices = Device::list_all(platform).unwrap_or(vec![]);
+            for device in devices.iter() {
+                device_names.push(format!(
+                    "{} - {}",
+                    platform_name,
+                    device.name().unwrap_or("<invalid device>".to_string())
+                ));
+            }
+        }
+        device_names
+    }
+
+    /// Name of the device at `gpu_index`, counted over all platforms like
+    /// `setup` does.
+    pub fn device_name_of(gpu_index: usize) -> Option<String> {
+        let device = Platform::list()
+            .into_iter()
+            .flat_map(|platform| Device::list_all(platform).unwrap_or_default())
+            .nth(gpu_index)?;
+        Some(device.name().unwrap_or("<invalid device>".to_string()))
+    }
+}
+
+/// Builds the kernel for `device`, from the cached program binary if there
+/// is one. Otherwise, or if the driver rejects the binary, it's compiled from
+/// `source` and the result is cached for the next setup.
+fn build_program(
+    ctx: &Context,
+    platform: &Platform,
+    device: Device,
+    source: &str,
+    kernel: &KernelInfo,
+    settings: &MiningSettings,
+) -> Result<Program, MinerError> {
+    let mut defines = kernel.defines.to_vec();
+    if kernel.bitalign && has_extension(device, "cl_amd_media_ops") {
+        defines.push("BITALIGN");
+    }
+    let cache_path = program_cache_key(platform, device, source, &defines, settings)
+        .and_then(|key| Some(program_cache_dir()?.join(format!("{}.bin", key))));
+    if let Some(binary) = cache_path.as_ref().and_then(|path| std::fs::read(path).ok()) {
+        match Program::with_binary(ctx, &[device], &[&binary], &CString::default()) {
+            Ok(program) => return Ok(program),
+            Err(err) => eprintln!("Cached program binary rejected, recompiling: {}", err),
+        }
+    }
+    let mut program_builder = ProgramBuilder::new();
+    program_builder
+        .src(source)
+        .cmplr_def("WORKSIZE", settings.local_work_size)
+        .cmplr_def("ITERATIONS", settings.inner_iter_size)
+        .cmplr_def("MAX_RESULTS", MAX_RESULTS as i32);
+    for &define in &defines {
+        program_builder.cmplr_def(define, 1);
+    }
+    let program = program_builder
+        .devices(DeviceSpecifier::Single(device))
+        .build(ctx)?;
+    if let Some(cache_path) = cache_path {
+        if let Err(err) = save_program_binary(&program, &cache_path) {
+            eprintln!("Couldn't cache program binary in {}: {}", cache_path.display(), err);
+        }
+    }
+    Ok(program)
+}
+
+fn has_extension(device: Device, extension: &str) -> bool {
+    match device.info(DeviceInfo::Extensions) {
+        Ok(extensions) => extensions.to_string().split_whitespace().any(|ext| ext == extension),
+        Err(_) => false,
+    }
+}
+
+fn program_cache_dir() -> Option<PathBuf> {
+    Some(dirs::home_dir()?.join(FOLDER_DIR).join(PROGRAM_CACHE_DIR))
+}
+
+/// Identifies a compiled program binary. Anything that could change it is
+/// hashed, so a driver update or kernel change simply misses the cache.
+fn program_cache_key(
+    platform: &Platform,
+    device: Device,
+    source: &str,
+    defines: &[&str],
+    settings: &MiningSettings,
+) -> Option<String> {
+    let platform_name = platform.name().ok()?;
+    let device_name = device.name().ok()?;
+    let driver_version = device.info(DeviceInfo::DriverVersion).ok()?.to_string();
+    Some(hash_cache_key(&[
+        &platform_name,
+        &device_name,
+        &driver_version,
+        source,
+        &format!("WORKSIZE={}", settings.local_work_size),
+        &format!("ITERATIONS={}", settings.inner_iter_size),
+        &format!("MAX_RESULTS={}", MAX_RESULTS),
+        &defines.join(" "),
+    ]))
+}
+
+fn hash_cache_key(parts: &[&str]) -> String {
+    let mut hasher = sha2::Sha256::new();
+    for part in parts {
+        // Length-prefixed so parts can't run into each other
+        hasher.update((part.len() as u64).to_le_bytes());
+        hasher.update(part.as_bytes());
+    }
+    hex::encode(hasher.finalize())
+}
+
+fn save_program_binary(program: &Program, path: &Path) -> Result<()> {
+    let binary = match program.info(ProgramInfo::Binaries)? {
+        ProgramInfoResult::Binaries(mut binaries) if !binaries.is_empty() => binaries.remove(0),
+        _ => return Ok(()),
+    };
+    if let Some(folder) = path.parent() {
+        std::fs::create_dir_all(folder)?;
+    }
+    // Written to a temporary file first, so other miners never read a partial binary
+    let tmp_path = path.with_extension("tmp");
+    std::fs::write(&tmp_path, binary)?;
+    std::fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// Source of the kernel to mine with: the `kernel_path` file, or
+/// `{kernel_name}.cl` in the `kernel_path` folder, or the embedded kernel
+/// `kernel_name`.
+fn kernel_source(settings: &MiningSettings) -> Result<String, MinerError> {
+    let mut available = Vec::new();
+    if let Some(kernel_path) = &settings.kernel_path {
+        let path = Path::new(kernel_path);
+        let read_kernel = |path: &Path| {
+            std::fs::read_to_string(path)
+                .map_err(|err| ReadKernel(path.to_string_lossy().to_string(), err))
+        };
+        if !path.is_dir() {
+            return read_kernel(path);
+        }
+        let kernel_file = path.join(format!("{}.cl", settings.kernel_name));
+        if kernel_file.exists() {
+            return read_kernel(&kernel_file);
+        }
+        // e.g. poclbm120327.cl for poclbm_v2
+        let shipped_file = KERNELS
+            .iter()
+            .find(|kernel| kernel.name == settings.kernel_name)
+            .map(|kernel| path.join(kernel.file_name));
+        if let Some(shipped_file) = shipped_file.filter(|file| file.exists()) {
+            return read_kernel(&shipped_file);
+        }
+        let entries = std::fs::read_dir(path)
+            .map_err(|err| ReadKernel(kernel_path.clone(), err))?;
+        for entry in entries.flatten() {
+            let entry_path = entry.path();
+            if entry_path.extension() == Some("cl".as_ref()) {
+                if let Some(name) = entry_path.file_stem() {
+                    available.push(name.to_string_lossy().to_string());
+                }
+            }
+        }
+    }
+    for kernel in KERNELS {
+        if kernel.name == settings.kernel_name {
+            return Ok(kernel.source.to_string());
+        }
+        available.push(kernel.name.to_string());
+    }
+    available.sort();
+    available.dedup();
+    Err(UnknownKernel(settings.kernel_name.clone(), available.join(", ")))
+}
+
+impl MiningBackend for OpenClBackend {
+    fn device_name(&self) -> String {
+        self.device_name.clone()
+    }
+
+    fn num_nonces_per_search(&self, settings: &MiningSettings) -> u64 {
+        settings.kernel_size as u64 * settings.inner_iter_size as u64
+    }
+
+    fn pipeline_depth(&self) -> usize {
+        self.slots.len()
+    }
+
+    fn enqueue_search(
+        &mut self,
+        work: &Work,
+        settings: &MiningSettings,
+        _log: &Log,
+    ) -> Result<()> {
+        assert!(
+            self.in_flight.len() < self.slots.len(),
+            "All search slots in flight, finish_search first"
+        );
+        let slot_idx = match self.in_flight.back() {
+            Some(&last_idx) => (last_idx + 1) % self.slots.len(),
+            None => 0,
+        };
+        let slot = &mut self.slots[slot_idx];
+        // Nothing blocks here; the queue is in order, so each command waits
+        // for the ones before it. `header` and `output` stay untouched until
+        // `done` completed.
+        slot.header = self.header_args.precalc(&work.header_bytes());
+        match self.header_args {
+            HeaderArgs::PartialHeader => {
+                unsafe {
+                    slot.header_buffer.write(&slot.header).block(false).enq().map_err(Ocl)?;
+                }
+                self.search_kernel
+                    .set_arg("partial_header", &slot.header_buffer).map_err(Ocl)?;
+            }
+            HeaderArgs::Precalc => {
+                for (&name, &value) in self.header_args.names().iter().zip(&slot.header) {
+                    self.search_kernel.set_arg(name, value).map_err(Ocl)?;
+                }
+            }
+        }
+        slot.output_buffer.cmd().fill(0, None).enq().map_err(Ocl)?;
+        self.search_kernel.set_arg("output", &slot.output_buffer).map_err(Ocl)?;
+        self.search_kernel.set_arg("offset", work.nonce_base).map_err(Ocl)?;
+        let cmd = self
+            .search_kernel
+            .cmd()
+            .global_work_size(settings.kernel_size);
+        unsafe {
+            cmd.enq().map_err(Ocl)?;
+        }
+        slot.done = Event::empty();
+        unsafe {
+            slot.output_buffer
+                .read(&mut slot.output)
+                .block(false)
+                .enew(&mut slot.done)
+                .enq()
+                .map_err(Ocl)?;
+        }
+        slot.work = Some(*work);
+        self.in_flight.push_back(slot_idx);
+        Ok(())
+    }
+
+    fn finish_search(&mut self, _settings: &MiningSettings, log: &Log) -> Result<Vec<Candidate>> {
+        let slot_idx = self
+            .in_flight
+            .pop_front()
+            .expect("finish_search called without a search in flight");
+        let slot = &mut self.slots[slot_idx];
+        let work = match slot.work.take() {
+            Some(work) => work,
+            None => return Ok(Vec::new()),
+        };
+        slot.done.wait_for().map_err(|err| Ocl(err.into()))?;
+        let output = &slot.output;
+        let num_found = output[RESULT_COUNT] as usize;
+        if output[RESULT_OVERFLOW] != 0 || num_found > MAX_RESULTS {
+            log.warn(format!(
+                "{} nonces found in one search, dropped {}. Lower the intensity if this \
+                 happens often.",
+                num_found,
+                num_found.saturating_sub(MAX_RESULTS),
+            ));
+        }
+        let mut candidates = Vec::new();
+        for &nonce in &output[RESULTS..RESULTS + num_found.min(MAX_RESULTS)] {
+            let candidate = check_candidate(&work, nonce, log);
+            if candidate.hash.last() != Some(&0) {
+                log.bug(
+                    "BUG: found nonce's hash has no leading zero byte. Contact the \
+                           developers.",
+                );
+            }
+            candidates.push(candidate);
+        }
+        Ok(candidates)
+    }
+}
+
+#[test]
+fn test_hash_cache_key() {
+    let key = hash_cache_key(&["platform", "device", "1.0", "source", "WORKSIZE=256"]);
+    assert_eq!(key.len(), 64);
+    assert_eq!(key, hash_cache_key(&["platform", "device", "1.0", "source", "WORKSIZE=256"]));
+    assert_ne!(key, hash_cache_key(&["platform", "device", "1.1", "source", "WORKSIZE=256"]));
+    assert_ne!(key, hash_cache_key(&["platform", "device", "1.0", "source", "WORKSIZE=128"]));
+    // Moving bytes between parts changes the key
+    assert_ne!(key, hash_cache_key(&["platform", "device", "1.0", "sourceW", "ORKSIZE=256"]));
+}
+
+#[test]
+fn test_kernel_source() {
+    use crate::{miner::BackendKind, settings::test_config};
+
+    let mut settings = MiningSettings {
+        backend: BackendKind::OpenCl,
+        ..MiningSettings::from_config(&test_config()).unwrap()
+    };
+    assert!(kernel_source(&settings).unwrap().contains("__kernel void search"));
+
+    let kernel_dir = std::env::temp_dir().join(format!("lotus-miner-{}-kernels", std::process::id()));
+    std::fs::create_dir_all(&kernel_dir).unwrap();
+    std::fs::write(kernel_dir.join("custom.cl"), "// custom").unwrap();
+    settings.kernel_path = Some(kernel_dir.to_string_lossy().to_string());
+    settings.kernel_name = "custom".to_string();
+    assert_eq!(kernel_source(&settings).unwrap(), "// custom");
+    settings.kernel_name = "unknown".to_string();
+    let err = kernel_source(&settings).unwrap_err().to_string();
+    assert!(err.contains("custom, lotus_midstate, lotus_og, poclbm"), "{}", err);
+    settings.kernel_path = Some(kernel_dir.join("custom.cl").to_string_lossy().to_string());
+    assert_eq!(kernel_source(&settings).unwrap(), "// custom");
+    std::fs::remove_dir_all(&kernel_dir).unwrap();
+}
@@ -0,0 +1,575 @@
+use std::{
+    collections::{BTreeMap, HashSet, VecDeque},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use serde_json::{json, Value};
+use thiserror::Error;
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::watch,
+};
+
+use crate::{
+    block::{split_block_from_template, Block, BlockTemplate, SplitBlock},
+    difficulty::{difficulty_to_target, hash_below_target},
+    fetch_block_template,
+    ledger::{record_found_block, run_block_status_checks},
+    node::{run_node_health_checks, select_active_node},
+    nonce::MAX_NTIME_ROLL,
+    notify::{poll_interval, run_tip_notifications},
+    stratum::{hex_int, notify_params, write_message, StratumMessage},
+    submit::{log_submit_error, submit_block},
+    ConfigSettings, Log, Server,
+};
+
+/// Jobs older than this are rejected as stale.
+const MAX_RECENT_JOBS: usize = 16;
+/// Each miner's extranonce1 goes into the coinbase before the extranonce2 it
+/// picks itself, so no two miners search the same headers.
+const EXTRANONCE1_SIZE: usize = 2;
+const EXTRANONCE2_SIZE: usize = 4;
+
+/// Stratum server holding the only node connection and handing out jobs to
+/// many downstream miners, each with its own nonce prefix.
+pub struct Proxy {
+    server: Arc<Server>,
+    bind_addr: String,
+    difficulty: f64,
+    share_target: [u8; 32],
+    jobs_sender: watch::Sender<Option<Arc<ProxyJob>>>,
+    jobs_receiver: watch::Receiver<Option<Arc<ProxyJob>>>,
+    /// Template of the latest job, to tell whether the node's next one is
+    /// for a different block.
+    template: std::sync::Mutex<Option<BlockTemplate>>,
+    recent_jobs: std::sync::Mutex<VecDeque<Arc<ProxyJob>>>,
+    next_job_id: AtomicU64,
+    extranonces: std::sync::Mutex<ExtranonceAllocator>,
+    workers: std::sync::RwLock<BTreeMap<String, WorkerStats>>,
+}
+
+/// Shares submitted by one downstream worker.
+#[derive(Debug, Clone, Default)]
+pub struct WorkerStats {
+    pub accepted: u64,
+    pub rejected: u64,
+    pub blocks: u64,
+}
+
+/// Hands out the extranonce1 values of connected miners, so no two of them
+/// search the same nonces.
+#[derive(Default)]
+struct ExtranonceAllocator {
+    in_use: HashSet<u16>,
+    next: u16,
+}
+
+/// An extranonce1 value, free for other miners again once dropped.
+struct ExtranonceLease<'a> {
+    proxy: &'a Proxy,
+    extranonce1: u16,
+}
+
+struct ProxyJob {
+    job_id: String,
+    block: SplitBlock,
+    clean_jobs: bool,
+    /// Extranonce, ntime and nonce of each share submitted for the job.
+    submitted_shares: std::sync::Mutex<HashSet<(Vec<u8>, u64, u64)>>,
+}
+
+#[derive(Debug, Error)]
+enum ProxyError {
+    #[error("All {0} extranonce1 values are in use")]
+    ExtranoncesExhausted(usize),
+}
+
+#[derive(Debug, Error)]
+enum ShareError {
+    #[error("Invalid share parameters")]
+    InvalidParams,
+    #[error("Job not found")]
+    UnknownJob,
+    #[error("ntime out of range")]
+    InvalidTime,
+    #[error("Duplicate share")]
+    Duplicate,
+    #[error("Low difficulty share")]
+    LowDifficulty,
+    #[error("Unauthorized worker")]
+    Unauthorized,
+}
+
+impl ShareError {
+    fn code(&self) -> i32 {
+        match self {
+            ShareError::UnknownJob => 21,
+            ShareError::Duplicate => 22,
+            ShareError::LowDifficulty => 23,
+            ShareError::Unauthorized => 24,
+            ShareError::InvalidParams | ShareError::InvalidTime => 20,
+        }
+    }
+}
+
+impl Proxy {
+    pub fn from_config(config: ConfigSettings) -> eyre::Result<Self> {
+        let bind_addr = config.proxy_bind.clone();
+        let difficulty = config.proxy_difficulty;
+        let (jobs_sender, jobs_receiver) = watch::channel(None);
+        let server = Server::without_devices(config, Duration::from_secs(10))?;
+        Ok(Proxy {
+            server: Arc::new(server),
+            bind_addr,
+            difficulty,
+            share_target: difficulty_to_target(difficulty),
+            jobs_sender,
+            jobs_receiver,
+            template: std::sync::Mutex::new(None),
+            recent_jobs: std::sync::Mutex::new(VecDeque::new()),
+            next_job_id: AtomicU64::new(0),
+            extranonces: std::sync::Mutex::new(ExtranonceAllocator::default()),
+            workers: std::sync::RwLock::new(BTreeMap::new()),
+        })
+    }
+
+    pub async fn run(self: Arc<Self>) -> Result<(), Box<dyn std::error::Error>> {
+        let listener = TcpListener::bind(&self.bind_addr).await?;
+        self.log()
+            .info(format!("Stratum proxy listening on {}", self.bind_addr));
+        tokio::spawn({
+            let proxy = Arc::clone(&self);
+            async move {
+                let log = proxy.log();
+                loop {
+                    let failed = match proxy.update_job().await {
+                        Ok(()) => false,
+                        Err(err) => {
+                            log.error(format!("update_job error: {:?}", err));
+                            true
+                        }
+                    };
+                    if failed && proxy.server.node_settings.lock().await.nodes.len() > 1 {
+                        select_active_node(&proxy.server).await;
+                    }
+                    let poll_interval = poll_interval(&proxy.server).await;
+                    tokio::select! {
+                        _ = tokio::time::sleep(poll_interval) => {}
+                        _ = proxy.server.new_tip.notified() => {}
+                    }
+                }
+            }
+        });
+        tokio::spawn({
+            let proxy = Arc::clone(&self);
+            async move { run_node_health_checks(&proxy.server).await }
+        });
+        tokio::spawn({
+            let proxy = Arc::clone(&self);
+            async move { run_block_status_checks(&proxy.server).await }
+        });
+        tokio::spawn(run_tip_notifications(Arc::clone(&self.server)));
+        loop {
+            let (stream, peer_addr) = listener.accept().await?;
+            tokio::spawn({
+                let proxy = Arc::clone(&self);
+                async move {
+                    if let Err(err) = proxy.handle_connection(stream, peer_addr).await {
+                        proxy
+                            .log()
+                            .warn(format!("Miner {} disconnected: {}", peer_addr, err));
+                    }
+                }
+            });
+        }
+    }
+
+    pub fn log(&self) -> &Log {
+        self.server.log()
+    }
+
+    /// Shares submitted so far, by worker name.
+    pub fn worker_stats(&self) -> BTreeMap<String, WorkerStats> {
+        self.workers.read().unwrap().clone()
+    }
+
+    async fn update_job(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let template = match fetch_block_template(&self.server).await? {
+            Some(template) => template,
+            None => return Ok(()),
+        };
+        let miner_addr = self.server.node_settings.lock().await.miner_addr.clone();
+        self.update_template(template, &miner_addr);
+        Ok(())
+    }
+
+    /// Sends a job for `template` to the miners, unless the latest job is
+    /// already for the same block.
+    fn update_template(&self, template: BlockTemplate, miner_addr: &str) {
+        let mut last_template = self.template.lock().unwrap();
+        if matches!(&*last_template, Some(last_template) if last_template.same_block(&template)) {
+            return;
+        }
+        let extranonce_size = EXTRANONCE1_SIZE + EXTRANONCE2_SIZE;
+        match split_block_from_template(&template, miner_addr, extranonce_size) {
+            Ok(block) => {
+                self.new_job(block);
+                *last_template = Some(template);
+            }
+            Err(err) => self.log().error(format!("Invalid block template: {}", err)),
+        }
+    }
+
+    /// Leases the next extranonce1 not used by a connected miner.
+    fn lease_extranonce1(&self) -> Result<ExtranonceLease<'_>, ProxyError> {
+        let mut extranonces = self.extranonces.lock().unwrap();
+        let num_values = 1 << (8 * EXTRANONCE1_SIZE);
+        if extranonces.in_use.len() >= num_values {
+            return Err(ProxyError::ExtranoncesExhausted(num_values));
+        }
+        while extranonces.in_use.contains(&extranonces.next) {
+            extranonces.next = extranonces.next.wrapping_add(1);
+        }
+        let extranonce1 = extranonces.next;
+        extranonces.in_use.insert(extranonce1);
+        extranonces.next = extranonce1.wrapping_add(1);
+        Ok(ExtranonceLease {
+            proxy: self,
+            extranonce1,
+        })
+    }
+
+    fn new_job(&self, block: SplitBlock) {
+        let mut recent_jobs = self.recent_jobs.lock().unwrap();
+        let clean_jobs = match recent_jobs.back() {
+            Some(last_job) => last_job.block.header.prev_hash != block.header.prev_hash,
+            None => true,
+        };
+        if clean_jobs {
+            recent_jobs.clear();
+        }
+        let job = Arc::new(ProxyJob {
+            job_id: format!("{:x}", self.next_job_id.fetch_add(1, Ordering::AcqRel)),
+            block,
+            clean_jobs,
+            submitted_shares: std::sync::Mutex::new(HashSet::new()),
+        });
+        recent_jobs.push_back(Arc::clone(&job));
+        if recent_jobs.len() > MAX_RECENT_JOBS {
+            recent_jobs.pop_front();
+        }
+        // We hold a receiver ourselves, so this can't fail
+        let _ = self.jobs_sender.send(Some(job));
+    }
+
+    async fn handle_connection(
+        &self,
+        stream: TcpStream,
+        peer_addr: SocketAddr,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let log = self.log();
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        let mut jobs = self.jobs_receiver.clone();
+        let extranonce_lease = self.lease_extranonce1()?;
+        let extranonce1 = extranonce_lease.extranonce1.to_le_bytes().to_vec();
+        let mut worker = None;
+        log.info(format!("Miner connected from {}", peer_addr));
+        loop {
+            tokio::select! {
+                line = lines.next_line() => {
+                    let line = match line? {
+                        Some(line) => line,
+                        None => return Ok(()),
+                    };
+                    let message: StratumMessage = match serde_json::from_str(&line) {
+                        Ok(message) => message,
+                        Err(err) => {
+                            log.warn(format!("Invalid message from miner {}: {}", peer_addr, err));
+                            let error = json!([20, "Invalid JSON-RPC message", null]);
+                            let reply = json!({"id": null, "result": null, "error": error});
+                            write_message(&mut writer, reply).await?;
+                            continue;
+                        }
+                    };
+                    let id = message.id;
+                    let reply = match message.method.as_deref() {
+                        Some("mining.subscribe") => {
+                            let result = json!([[], hex::encode(&extranonce1), EXTRANONCE2_SIZE]);
+                            json!({"id": id, "result": result, "error": null})
+                        }
+                        Some("mining.authorize") => {
+                            let name = message.params[0].as_str().unwrap_or_default();
+                            log.info(format!("Worker {} authorized from {}", name, peer_addr));
+                            self.workers.write().unwrap().entry(name.to_string()).or_default();
+                            worker = Some(name.to_string());
+                            let reply = json!({"id": id, "result": true, "error": null});
+                            write_message(&mut writer, reply).await?;
+                            let params = json!([self.difficulty]);
+                            let message =
+                                json!({"id": null, "method": "mining.set_difficulty", "params": params});
+                            write_message(&mut writer, message).await?;
+                            let job = jobs.borrow().clone();
+                            if let Some(job) = job {
+                                write_message(&mut writer, notify_message(&job)).await?;
+                            }
+                            continue;
+                        }
+                        Some("mining.submit") => {
+                            let result = match &worker {
+                                Some(worker) => {
+                                    self.handle_share(worker, &extranonce1, &message.params).await
+                                }
+                                None => Err(ShareError::Unauthorized),
+                            };
+                            match result {
+                                Ok(()) => json!({"id": id, "result": true, "error": null}),
+                                Err(err) => {
+                                    let error = json!([err.code(), err.to_string(), null]);
+                                    json!({"id": id, "result": false, "error": error})
+                                }
+                            }
+                        }
+                        _ => json!({"id": id, "result": null, "error": [20, "Unknown method", null]}),
+                    };
+                    write_message(&mut writer, reply).await?;
+                }
+                changed = jobs.changed(), if worker.is_some() => {
+                    changed?;
+                    let job = jobs.borrow().clone();
+                    if let Some(job) = job {
+                        write_message(&mut writer, notify_message(&job)).await?;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Validates a `mining.submit` share and submits it to the node if it
+    /// also meets the block target.
+    async fn handle_share(
+        &self,
+        worker: &str,
+        extranonce1: &[u8],
+        params: &Value,
+    ) -> Result<(), ShareError> {
+        let result = self.check_share(extranonce1, params);
+        {
+            let mut workers = self.workers.write().unwrap();
+            let stats = workers.entry(worker.to_string()).or_default();
+            match &result {
+                Ok(Some(_)) => {
+                    stats.accepted += 1;
+                    stats.blocks += 1;
+                }
+                Ok(None) => stats.accepted += 1,
+                Err(_) => stats.rejected += 1,
+            }
+        }
+        let block = result.map_err(|err| {
+            self.log()
+                .warn(format!("Rejected share from {}: {}", worker, err));
+            err
+        })?;
+        if let Some(block) = block {
+            self.log()
+                .info(format!("Worker {} found a block, submitting", worker));
+            let result = submit_block(&self.server, &block).await;
+            if let Err(err) = &result {
+                log_submit_error(self.log(), err);
+            }
+            record_found_block(&self.server, &block, worker, &result).await;
+        }
+        Ok(())
+    }
+
+    /// Returns the solved block if the share also meets the block target.
+    /// Params are `[worker, job_id, extranonce2, ntime, nonce]`.
+    fn check_share(&self, extranonce1: &[u8], params: &Value) -> Result<Option<Block>, ShareError> {
+        let job_id = params[1].as_str().ok_or(ShareError::InvalidParams)?;
+        let extranonce2 = params[2]
+            .as_str()
+            .and_then(|extranonce2| hex::decode(extranonce2).ok())
+            .filter(|extranonce2| extranonce2.len() == EXTRANONCE2_SIZE)
+            .ok_or(ShareError::InvalidParams)?;
+        let time = hex_int(&params[3], 12).ok_or(ShareError::InvalidParams)?;
+        let nonce = hex_int(&params[4], 16).ok_or(ShareError::InvalidParams)?;
+        let job = self
+            .recent_jobs
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|job| job.job_id == job_id)
+            .cloned()
+            .ok_or(ShareError::UnknownJob)?;
+        let job_time = job.block.header.time;
+        if time < job_time || time > job_time + MAX_NTIME_ROLL {
+            return Err(ShareError::InvalidTime);
+        }
+        let extranonce = [extranonce1, &extranonce2].concat();
+        if !job
+            .submitted_shares
+            .lock()
+            .unwrap()
+            .insert((extranonce.clone(), time, nonce))
+        {
+            return Err(ShareError::Duplicate);
+        }
+        let mut block = job.block.block(&extranonce);
+        block.header.time = time;
+        block.header.nonce = nonce;
+        let hash = block.header.hash();
+        if !hash_below_target(&hash, &self.share_target) {
+            return Err(ShareError::LowDifficulty);
+        }
+        if hash_below_target(&hash, &block.target) {
+            Ok(Some(block))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl Drop for ExtranonceLease<'_> {
+    fn drop(&mut self) {
+        let mut extranonces = self.proxy.extranonces.lock().unwrap();
+        extranonces.in_use.remove(&self.extranonce1);
+    }
+}
+
+fn notify_message(job: &ProxyJob) -> Value {
+    let params = notify_params(&job.job_id, &job.block, job.clean_jobs);
+    json!({"id": null, "method": "mining.notify", "params": params})
+}
+
+#[tokio::test]
+async fn test_proxy_accepts_shares() {
+    use crate::{block::test_template, settings::test_config, ServerRef};
+    use lotus_miner_mock_node::MINER_ADDR;
+
+    let proxy = Arc::new(
+        Proxy::from_config(ConfigSettings {
+            // One in 256 hashes meets this share difficulty
+            proxy_difficulty: 1.0 / (1u64 << 24) as f64,
+            ..test_config()
+        })
+        .unwrap(),
+    );
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let pool_url = format!("stratum+tcp://{}", listener.local_addr().unwrap());
+    tokio::spawn({
+        let proxy = Arc::clone(&proxy);
+        async move {
+            let (stream, peer_addr) = listener.accept().await.unwrap();
+            proxy.handle_connection(stream, peer_addr).await.unwrap();
+        }
+    });
+    let extranonce_size = EXTRANONCE1_SIZE + EXTRANONCE2_SIZE;
+    let block = split_block_from_template(&test_template(), MINER_ADDR, extranonce_size).unwrap();
+    proxy.new_job(block);
+
+    let miner: ServerRef = Arc::new(
+        Server::from_config(
+            ConfigSettings {
+                pool_url,
+                pool_user: "worker1".to_string(),
+                ..test_config()
+            },
+            Duration::from_secs(10),
+        )
+        .unwrap(),
+    );
+    tokio::spawn(async move { miner.run().await.unwrap() });
+    for _ in 0..300 {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        if let Some(stats) = proxy.worker_stats().get("worker1") {
+            if stats.accepted > 0 {
+                assert_eq!(stats.rejected, 0);
+                assert_eq!(stats.blocks, 0);
+                return;
+            }
+        }
+    }
+    panic!("no share accepted");
+}
+
+#[test]
+fn test_extranonce1_leases() {
+    use crate::settings::test_config;
+
+    let proxy = Proxy::from_config(test_config()).unwrap();
+    let first = proxy.lease_extranonce1().unwrap();
+    let mut leases = vec![];
+    for _ in 1..1 << (8 * EXTRANONCE1_SIZE) {
+        leases.push(proxy.lease_extranonce1().unwrap());
+    }
+    let extranonces = leases
+        .iter()
+        .map(|lease| lease.extranonce1)
+        .chain(std::iter::once(first.extranonce1))
+        .collect::<HashSet<_>>();
+    assert_eq!(extranonces.len(), 1 << 16);
+    assert!(matches!(
+        proxy.lease_extranonce1(),
+        Err(ProxyError::ExtranoncesExhausted(65536))
+    ));
+    // A disconnected miner's value is handed out again, not a live one's
+    let freed = first.extranonce1;
+    drop(first);
+    assert_eq!(proxy.lease_extranonce1().unwrap().extranonce1, freed);
+}
+
+#[test]
+fn test_jobs_for_new_blocks_only() {
+    use crate::{
+        block::{test_template, TemplateTransaction},
+        settings::test_config,
+    };
+    use lotus_miner_mock_node::MINER_ADDR;
+
+    let proxy = Proxy::from_config(test_config()).unwrap();
+    let last_job = || proxy.recent_jobs.lock().unwrap().back().cloned().unwrap();
+    let template = test_template();
+    proxy.update_template(template.clone(), MINER_ADDR);
+    let first_job = last_job();
+    assert!(first_job.clean_jobs);
+
+    // The node bumps curtime on every poll; that's still the same block
+    let later = BlockTemplate {
+        curtime: template.curtime + 5,
+        ..template.clone()
+    };
+    proxy.update_template(later, MINER_ADDR);
+    assert_eq!(last_job().job_id, first_job.job_id);
+
+    // New transactions extend the current tip
+    let with_tx = BlockTemplate {
+        transactions: vec![TemplateTransaction {
+            data: "00".to_string(),
+            txid: format!("{:064x}", 1),
+            hash: format!("{:064x}", 2),
+        }],
+        ..template.clone()
+    };
+    proxy.update_template(with_tx, MINER_ADDR);
+    let tx_job = last_job();
+    assert_ne!(tx_job.job_id, first_job.job_id);
+    assert!(!tx_job.clean_jobs);
+    assert_eq!(proxy.recent_jobs.lock().unwrap().len(), 2);
+
+    // A new tip invalidates all previous jobs
+    let new_tip = BlockTemplate {
+        previousblockhash: format!("{:064x}", 0xbeefu32),
+        height: template.height + 1,
+        ..template
+    };
+    proxy.update_template(new_tip, MINER_ADDR);
+    assert!(last_job().clean_jobs);
+    assert_eq!(proxy.recent_jobs.lock().unwrap().len(), 1);
+}
@@ -0,0 +1,132 @@
+use std::convert::TryInto;
+
+use hex_literal::hex;
+use thiserror::Error;
+
+use crate::{
+    block::LotusHeader,
+    display_hash,
+    miner::{Miner, Work},
+    Log, LogSeverity,
+};
+
+/// Header of Lotus' genesis block, whose hash is
+/// 000000006275dc5039da85620773f3223d629759495f80b49a381d79cae77c11.
+const GENESIS_HEADER: [u8; 160] = hex!("0000000000000000000000000000000000000000000000000000000000000000ffff001d00c273600000000041c6ddd303000000010e010000000000000000000000000000000000000000000000000000000000000000000000000000000000934755d60e905ec8778f554164bd9b7f21ab6c15cfed2956123a722a6f6fa62e1406e05881e299367766d313e26c05564ec91bf721d31726bd6e46e60689539a");
+
+#[derive(Debug, Error)]
+pub enum SelfTestError {
+    #[error("Search failed: {0}")]
+    Search(eyre::Report),
+    #[error("Reported a nonce with a wrong hash: {0}")]
+    WrongHash(String),
+    #[error("Didn't find the known nonce {0}")]
+    MissedNonce(u64),
+    #[error("Found nonce {0} instead of the known nonce {1}")]
+    WrongNonce(u64, u64),
+}
+
+/// Lets `miner` search the range of nonces containing the genesis block's
+/// nonce, with a target only it meets there. Fails if the nonce isn't found,
+/// or if any nonce the device reports doesn't meet the target by
+/// `lotus_hash`, e.g. because the driver miscompiled the kernel.
+pub fn self_test(miner: &mut Miner) -> Result<(), SelfTestError> {
+    let log = Log::new();
+    // 0000000063000000..., just above the genesis block's hash
+    let mut target = [0; 32];
+    target[27] = 0x63;
+    let mut work = Work::from_header(LotusHeader::from_bytes(&GENESIS_HEADER), target);
+    let known_nonce = work.header().nonce;
+    // Backends count in the big endian word of header bytes 44..48
+    let known_nonce_word = u64::from((known_nonce as u32).swap_bytes());
+    let num_nonces = miner.num_nonces_per_search();
+    let nonce_base = (known_nonce_word / num_nonces * num_nonces).min((1 << 32) - num_nonces);
+    work.nonce_base = nonce_base.try_into().unwrap();
+    let candidates = miner
+        .find_candidates(&work, &log)
+        .map_err(SelfTestError::Search)?;
+    // Backends log a bug for reported nonces that don't hash as expected
+    let logs = log.get_logs_and_clear();
+    if let Some(entry) = logs.iter().find(|entry| entry.severity == LogSeverity::Bug) {
+        return Err(SelfTestError::WrongHash(entry.msg.clone()));
+    }
+    if candidates.is_empty() {
+        return Err(SelfTestError::MissedNonce(known_nonce));
+    }
+    // Only the known nonce meets the target, anything else was reported
+    // for a hash the device got wrong
+    for candidate in candidates {
+        if !candidate.meets_target {
+            return Err(SelfTestError::WrongHash(format!(
+                "nonce {} hashes to {}, missing the target",
+                candidate.nonce,
+                display_hash(&candidate.hash)
+            )));
+        }
+        if candidate.nonce != known_nonce {
+            return Err(SelfTestError::WrongNonce(candidate.nonce, known_nonce));
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_self_test_cpu() {
+    use crate::{miner::MiningSettings, settings::test_config};
+
+    let settings = MiningSettings::from_config(&test_config()).unwrap();
+    let mut miner = Miner::setup(settings).unwrap();
+    self_test(&mut miner).unwrap();
+}
+
+#[test]
+fn test_self_test_rejects_wrong_nonces() {
+    use crate::{
+        miner::{check_candidate, Candidate, MiningBackend, MiningSettings},
+        settings::test_config,
+    };
+
+    /// Reports the first nonce of each search besides the genesis nonce, as
+    /// a miscompiled kernel might.
+    struct WrongNonceBackend(Option<Work>);
+
+    impl MiningBackend for WrongNonceBackend {
+        fn device_name(&self) -> String {
+            "wrong nonces".to_string()
+        }
+
+        fn num_nonces_per_search(&self, _settings: &MiningSettings) -> u64 {
+            1 << 16
+        }
+
+        fn enqueue_search(
+            &mut self,
+            work: &Work,
+            _settings: &MiningSettings,
+            _log: &Log,
+        ) -> eyre::Result<()> {
+            self.0 = Some(*work);
+            Ok(())
+        }
+
+        fn finish_search(
+            &mut self,
+            _settings: &MiningSettings,
+            log: &Log,
+        ) -> eyre::Result<Vec<Candidate>> {
+            let work = self.0.take().unwrap();
+            let known_nonce = (work.header().nonce as u32).swap_bytes();
+            Ok(vec![
+                check_candidate(&work, known_nonce, log),
+                check_candidate(&work, work.nonce_base, log),
+            ])
+        }
+    }
+
+    let settings = MiningSettings::from_config(&test_config()).unwrap();
+    let mut miner = Miner::with_backend(Box::new(WrongNonceBackend(None)), settings);
+    assert!(matches!(
+        self_test(&mut miner),
+        Err(SelfTestError::WrongHash(_))
+    ));
+}
@@ -1,16 +1,37 @@
-use std::io::Write;
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
 
-use clap::{crate_authors, crate_description, crate_version, load_yaml, App};
+use clap::{crate_authors, crate_description, crate_version, load_yaml, App, ArgMatches};
 use config::{Config, ConfigError, File};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+
+use crate::bench::BenchSettings;
 
 pub const DEFAULT_URL: &str = "http://127.0.0.1:10604";
 pub const DEFAULT_USER: &str = "lotus";
 pub const DEFAULT_PASSWORD: &str = "lotus";
 pub const DEFAULT_RPC_POLL_INTERVAL: i64 = 3;
 pub const FOLDER_DIR: &str = ".lotus-miner";
+pub const BLOCKS_FILE: &str = "blocks.jsonl";
 pub const DEFAULT_KERNEL_SIZE: i64 = 21;
 pub const DEFAULT_GPU_INDEX: i64 = 0;
+pub const DEFAULT_BACKEND: &str = "opencl";
+pub const DEFAULT_CPU_THREADS: i64 = 0;
+pub const DEFAULT_WORK_SOURCE: &str = "getrawunsolvedblock";
+pub const DEFAULT_POOL_PASSWORD: &str = "x";
+pub const DEFAULT_PROXY_BIND: &str = "0.0.0.0:3333";
+pub const DEFAULT_PROXY_DIFFICULTY: f64 = 1.0;
+pub const DEFAULT_AUTOTUNE_LATENCY_MS: i64 = 250;
+pub const DEFAULT_BENCH_INTENSITIES: &str = "18,20,22,24";
+pub const DEFAULT_BENCH_LOCAL_WORK_SIZES: &str = "64,128,256";
+pub const DEFAULT_BENCH_INNER_ITER_SIZES: &str = "16";
+pub const DEFAULT_KERNEL: &str = "lotus_og";
+pub const DEFAULT_BENCH_KERNELS: &str = "lotus_og";
+pub const DEFAULT_BENCH_DURATION: &str = "3";
 
 #[derive(Debug, Deserialize)]
 pub struct ConfigSettings {
@@ -21,6 +42,56 @@ pub struct ConfigSettings {
     pub mine_to_address: String,
     pub kernel_size: i64,
     pub gpu_index: i64,
+    pub gpu_indices: Vec<i64>,
+    pub backend: String,
+    pub cpu_threads: i64,
+    pub work_source: String,
+    pub pool_url: String,
+    pub pool_user: String,
+    pub pool_password: String,
+    pub proxy_bind: String,
+    pub proxy_difficulty: f64,
+    pub backup_nodes: Vec<NodeConfig>,
+    pub rpc_cookie_file: String,
+    pub datadir: String,
+    pub zmq_hashblock: String,
+    pub autotune: bool,
+    pub autotune_latency_ms: i64,
+    pub kernel_path: String,
+    pub kernel: String,
+    pub blocks_file: String,
+}
+
+/// A node to get work from and submit blocks to.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct NodeConfig {
+    pub url: String,
+    #[serde(default = "default_user")]
+    pub user: String,
+    #[serde(default = "default_password")]
+    pub password: String,
+    /// lotusd's `.cookie` file; if set, used instead of `user`/`password`.
+    #[serde(default)]
+    pub cookie_file: Option<String>,
+}
+
+/// What `lotus-miner` was asked to do on the command line.
+#[derive(Debug, Clone)]
+pub enum Command {
+    Mine,
+    Bench(BenchSettings),
+    /// Autotune the selected devices again, ignoring stored results.
+    Tune,
+    /// List found blocks with their status.
+    Blocks,
+}
+
+fn default_user() -> String {
+    DEFAULT_USER.to_string()
+}
+
+fn default_password() -> String {
+    DEFAULT_PASSWORD.to_string()
 }
 
 const DEFAULT_CONFIG_FILE_CONTENT: &str = r#"mine_to_address = ""
@@ -34,15 +105,50 @@ kernel_size = 23
 
 impl ConfigSettings {
     pub fn load(expect_mine_to_address: bool) -> Result<Self, ConfigError> {
-        let mut s = Config::new();
+        Ok(Self::load_command(expect_mine_to_address)?.0)
+    }
 
-        // Set defaults
+    /// Like `load`, but also returns the subcommand. `mine_to_address` is
+    /// only required for mining.
+    pub fn load_command(expect_mine_to_address: bool) -> Result<(Self, Command), ConfigError> {
         let yaml = load_yaml!("cli.yaml");
         let matches = App::from_yaml(yaml)
             .about(crate_description!())
             .author(crate_authors!("\n"))
             .version(crate_version!())
             .get_matches();
+        let command = match matches.subcommand() {
+            ("bench", Some(bench_matches)) => Command::Bench(bench_settings(bench_matches)?),
+            ("tune", _) => Command::Tune,
+            ("blocks", _) => Command::Blocks,
+            _ => Command::Mine,
+        };
+        let expect_mine_to_address = expect_mine_to_address && matches!(command, Command::Mine);
+        let config = Self::from_matches(&matches, expect_mine_to_address)?;
+        Ok((config, command))
+    }
+
+    /// Settings of `lotus-miner-proxy`, which only takes the node, ledger
+    /// and proxy options on the command line.
+    pub fn load_proxy() -> Result<Self, ConfigError> {
+        let yaml = load_yaml!("proxy.yaml");
+        let matches = App::from_yaml(yaml)
+            .about("Serves Stratum jobs from a Lotus node to many miners")
+            .author(crate_authors!("\n"))
+            .version(crate_version!())
+            .get_matches();
+        Self::from_matches(&matches, true)
+    }
+
+    /// Config file and defaults, overridden by the command line options in
+    /// `matches`.
+    fn from_matches(
+        matches: &ArgMatches,
+        expect_mine_to_address: bool,
+    ) -> Result<Self, ConfigError> {
+        let mut s = Config::new();
+
+        // Set defaults
         let home_dir = match dirs::home_dir() {
             Some(some) => some,
             None => return Err(ConfigError::Message("no home directory".to_string())),
@@ -53,6 +159,24 @@ impl ConfigSettings {
         s.set_default("rpc_password", DEFAULT_PASSWORD)?;
         s.set_default("kernel_size", DEFAULT_KERNEL_SIZE)?;
         s.set_default("gpu_index", DEFAULT_GPU_INDEX)?;
+        s.set_default("gpu_indices", Vec::<i64>::new())?;
+        s.set_default("backend", DEFAULT_BACKEND)?;
+        s.set_default("cpu_threads", DEFAULT_CPU_THREADS)?;
+        s.set_default("work_source", DEFAULT_WORK_SOURCE)?;
+        s.set_default("pool_url", "")?;
+        s.set_default("pool_user", "")?;
+        s.set_default("pool_password", DEFAULT_POOL_PASSWORD)?;
+        s.set_default("proxy_bind", DEFAULT_PROXY_BIND)?;
+        s.set_default("proxy_difficulty", DEFAULT_PROXY_DIFFICULTY)?;
+        s.set_default("backup_nodes", Vec::<String>::new())?;
+        s.set_default("rpc_cookie_file", "")?;
+        s.set_default("datadir", "")?;
+        s.set_default("zmq_hashblock", "")?;
+        s.set_default("autotune", false)?;
+        s.set_default("autotune_latency_ms", DEFAULT_AUTOTUNE_LATENCY_MS)?;
+        s.set_default("kernel_path", "")?;
+        s.set_default("kernel", DEFAULT_KERNEL)?;
+        s.set_default("blocks_file", "")?;
 
         // Load config from file
         let default_config = home_dir;
@@ -119,6 +243,19 @@ impl ConfigSettings {
             s.set("rpc_user", rpc_user)?;
         }
 
+        // Authenticate with lotusd's cookie file instead of user/password
+        if let Some(rpc_cookie_file) = matches.value_of("rpc_cookie_file") {
+            s.set("rpc_cookie_file", rpc_cookie_file)?;
+        }
+        if let Some(datadir) = matches.value_of("datadir") {
+            s.set("datadir", datadir)?;
+        }
+
+        // Get notified of new blocks instead of waiting for the next poll
+        if let Some(zmq_hashblock) = matches.value_of("zmq_hashblock") {
+            s.set("zmq_hashblock", zmq_hashblock)?;
+        }
+
         // Set the bitcoin network
         if let Some(mine_to_address) = matches.value_of("mine_to_address") {
             s.set("mine_to_address", mine_to_address)?;
@@ -130,7 +267,7 @@ impl ConfigSettings {
         {
             return Err(ConfigError::Message(format!(
                 "Must set mine_to_address config option. You can find it in {}.toml",
-                std::fs::canonicalize(&config_path)
+                std::fs::canonicalize(config_path)
                     .map(|path| path.to_string_lossy().to_string())
                     .unwrap_or_else(|_| config_path.to_string())
             )));
@@ -146,6 +283,200 @@ impl ConfigSettings {
             s.set("gpu_index", gpu_index.parse::<i64>().unwrap())?;
         }
 
+        // Set multiple GPU indices, e.g. "0,1,2"
+        if let Some(gpu_indices) = matches.value_of("gpu_indices") {
+            let gpu_indices = gpu_indices
+                .split(',')
+                .map(|gpu_index| {
+                    gpu_index.trim().parse::<u32>().map(i64::from).map_err(|_| {
+                        ConfigError::Message(format!(
+                            "Invalid GPU index {:?} in gpu_indices {:?}",
+                            gpu_index, gpu_indices
+                        ))
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            s.set("gpu_indices", gpu_indices)?;
+        }
+
+        // Select the OpenCL kernel to mine with
+        if let Some(kernel) = matches.value_of("kernel") {
+            s.set("kernel", kernel)?;
+        }
+
+        // Use kernels from a file or folder instead of the embedded ones
+        if let Some(kernel_path) = matches.value_of("kernel_path") {
+            s.set("kernel_path", kernel_path)?;
+        }
+
+        // Record found blocks somewhere else than ~/.lotus-miner
+        if let Some(blocks_file) = matches.value_of("blocks_file") {
+            s.set("blocks_file", blocks_file)?;
+        }
+
+        // Tune kernel settings per device at startup
+        if matches.is_present("autotune") {
+            s.set("autotune", true)?;
+        }
+        if let Some(autotune_latency_ms) = matches.value_of("autotune_latency_ms") {
+            s.set(
+                "autotune_latency_ms",
+                autotune_latency_ms.parse::<i64>().unwrap(),
+            )?;
+        }
+
+        // Set the mining backend
+        if let Some(backend) = matches.value_of("backend") {
+            s.set("backend", backend)?;
+        }
+
+        // Set the number of CPU mining threads
+        if let Some(cpu_threads) = matches.value_of("cpu_threads") {
+            s.set("cpu_threads", cpu_threads.parse::<i64>().unwrap())?;
+        }
+
+        // Set the RPC method used to get work
+        if let Some(work_source) = matches.value_of("work_source") {
+            s.set("work_source", work_source)?;
+        }
+
+        // Set the Stratum pool to mine on
+        if let Some(pool_url) = matches.value_of("pool_url") {
+            s.set("pool_url", pool_url)?;
+        }
+        if let Some(pool_user) = matches.value_of("pool_user") {
+            s.set("pool_user", pool_user)?;
+        }
+        if let Some(pool_password) = matches.value_of("pool_password") {
+            s.set("pool_password", pool_password)?;
+        }
+
+        // Set where lotus-miner-proxy serves Stratum jobs
+        if let Some(proxy_bind) = matches.value_of("proxy_bind") {
+            s.set("proxy_bind", proxy_bind)?;
+        }
+        if let Some(proxy_difficulty) = matches.value_of("proxy_difficulty") {
+            s.set("proxy_difficulty", proxy_difficulty.parse::<f64>().unwrap())?;
+        }
+
         s.try_into()
     }
+
+    /// Nodes in order of preference: the `rpc_url` node, then `backup_nodes`.
+    pub fn nodes(&self) -> Vec<NodeConfig> {
+        let mut nodes = vec![NodeConfig {
+            url: self.rpc_url.clone(),
+            user: self.rpc_user.clone(),
+            password: self.rpc_password.clone(),
+            cookie_file: self.cookie_file(),
+        }];
+        nodes.extend(self.backup_nodes.iter().cloned());
+        nodes
+    }
+
+    /// `rpc_cookie_file` if given, otherwise the `.cookie` in `datadir`.
+    pub fn cookie_file(&self) -> Option<String> {
+        if !self.rpc_cookie_file.is_empty() {
+            Some(self.rpc_cookie_file.clone())
+        } else if !self.datadir.is_empty() {
+            let cookie_file = Path::new(&self.datadir).join(".cookie");
+            Some(cookie_file.to_string_lossy().to_string())
+        } else {
+            None
+        }
+    }
+
+    /// `blocks_file` if given, otherwise `blocks.jsonl` in `~/.lotus-miner`.
+    pub fn blocks_file(&self) -> Option<PathBuf> {
+        if !self.blocks_file.is_empty() {
+            Some(PathBuf::from(&self.blocks_file))
+        } else {
+            Some(dirs::home_dir()?.join(FOLDER_DIR).join(BLOCKS_FILE))
+        }
+    }
+
+    /// GPUs to mine on; `gpu_indices` if given, otherwise just `gpu_index`.
+    pub fn selected_gpu_indices(&self) -> Vec<usize> {
+        if self.gpu_indices.is_empty() {
+            vec![self.gpu_index as usize]
+        } else {
+            self.gpu_indices
+                .iter()
+                .map(|&gpu_index| gpu_index as usize)
+                .collect()
+        }
+    }
+}
+
+fn bench_settings(matches: &ArgMatches) -> Result<BenchSettings, ConfigError> {
+    let value = |name, default| matches.value_of(name).unwrap_or(default);
+    let duration = value("duration", DEFAULT_BENCH_DURATION);
+    let duration = duration
+        .parse::<f64>()
+        .ok()
+        .filter(|&duration| duration > 0.0)
+        .ok_or_else(|| ConfigError::Message(format!("Invalid bench duration {:?}", duration)))?;
+    Ok(BenchSettings {
+        intensities: parse_list(value("intensities", DEFAULT_BENCH_INTENSITIES))?,
+        local_work_sizes: parse_list(value("local_work_sizes", DEFAULT_BENCH_LOCAL_WORK_SIZES))?,
+        inner_iter_sizes: parse_list(value("inner_iter_sizes", DEFAULT_BENCH_INNER_ITER_SIZES))?,
+        kernel_names: parse_list(value("kernels", DEFAULT_BENCH_KERNELS))?,
+        duration: Duration::from_secs_f64(duration),
+        json: matches.is_present("json"),
+    })
+}
+
+/// Parses a comma separated list like "18,20,22".
+fn parse_list<T: FromStr>(list: &str) -> Result<Vec<T>, ConfigError> {
+    list.split(',')
+        .map(|item| {
+            item.trim().parse().map_err(|_| {
+                ConfigError::Message(format!("Invalid value {:?} in {:?}", item, list))
+            })
+        })
+        .collect()
+}
+
+/// Small CPU-mining config for tests that run a `Server` against a mock pool.
+#[cfg(test)]
+pub(crate) fn test_config() -> ConfigSettings {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    // Each test gets its own ledger, tests run in parallel
+    static NEXT_TEST_ID: AtomicU64 = AtomicU64::new(0);
+    let test_id = NEXT_TEST_ID.fetch_add(1, Ordering::Relaxed);
+    ConfigSettings {
+        rpc_url: String::new(),
+        rpc_user: String::new(),
+        rpc_password: String::new(),
+        rpc_poll_interval: 1,
+        mine_to_address: String::new(),
+        kernel_size: 12,
+        gpu_index: 0,
+        gpu_indices: vec![],
+        backend: "cpu".to_string(),
+        cpu_threads: 2,
+        work_source: "stratum".to_string(),
+        pool_url: String::new(),
+        pool_user: String::new(),
+        pool_password: DEFAULT_POOL_PASSWORD.to_string(),
+        proxy_bind: "127.0.0.1:0".to_string(),
+        proxy_difficulty: DEFAULT_PROXY_DIFFICULTY,
+        backup_nodes: vec![],
+        rpc_cookie_file: String::new(),
+        datadir: String::new(),
+        zmq_hashblock: String::new(),
+        autotune: false,
+        autotune_latency_ms: DEFAULT_AUTOTUNE_LATENCY_MS,
+        kernel_path: String::new(),
+        kernel: DEFAULT_KERNEL.to_string(),
+        blocks_file: std::env::temp_dir()
+            .join(format!(
+                "lotus-miner-{}-{}.blocks.jsonl",
+                std::process::id(),
+                test_id
+            ))
+            .to_string_lossy()
+            .to_string(),
+    }
 }
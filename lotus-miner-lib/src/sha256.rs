@@ -1,17 +1,383 @@
-use sha2::Digest;
+use std::convert::TryInto;
 
+use sha2::{digest::generic_array::GenericArray, Digest};
+
+use crate::block::LotusHeader;
+
+const SHA256_INIT: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+/// Padding block of the 64 byte chain layer message.
+const CHAIN_LAYER_PAD: [u8; 64] = {
+    let mut pad = [0u8; 64];
+    pad[0] = 0x80;
+    pad[62] = 0x02;
+    pad
+};
+
+pub fn sha256d(data: &[u8]) -> [u8; 32] {
+    sha2::Sha256::digest(&sha2::Sha256::digest(data)).into()
+}
+
+/// Lotus hash of a serialized `LotusHeader`, little endian.
 pub fn lotus_hash(header: &[u8; 160]) -> [u8; 32] {
-    let tx_layer_hash = sha2::Sha256::digest(&header[52..]);
+    let tx_layer_hash = sha2::Sha256::digest(&header[LotusHeader::TX_LAYER]);
     let mut pow_layer = [0u8; 52];
-    pow_layer[..20].copy_from_slice(&header[32..52]);
+    pow_layer[..20].copy_from_slice(&header[LotusHeader::POW_LAYER]);
     pow_layer[20..].copy_from_slice(&tx_layer_hash[..]);
     let pow_layer_hash = sha2::Sha256::digest(&pow_layer);
     let mut chain_layer = [0u8; 64];
-    chain_layer[..32].copy_from_slice(&header[..32]);
+    chain_layer[..32].copy_from_slice(&header[LotusHeader::PREV_HASH]);
     chain_layer[32..].copy_from_slice(&pow_layer_hash);
     sha2::Sha256::digest(&chain_layer).into()
 }
 
+/// Precomputed state for hashing many nonces of the same header.
+///
+/// Only header bytes 44..48 change between nonces, so the tx layer hash and
+/// the padded pow layer and chain layer blocks are built once per header.
+#[derive(Debug, Clone, Copy)]
+pub struct LotusMidstate {
+    pow_layer_block: [u8; 64],
+    chain_layer_block: [u8; 64],
+}
+
+impl LotusMidstate {
+    pub fn new(header: &[u8; 160]) -> Self {
+        let tx_layer_hash = sha2::Sha256::digest(&header[LotusHeader::TX_LAYER]);
+        let mut pow_layer_block = [0u8; 64];
+        pow_layer_block[..20].copy_from_slice(&header[LotusHeader::POW_LAYER]);
+        pow_layer_block[20..52].copy_from_slice(&tx_layer_hash);
+        pow_layer_block[52] = 0x80;
+        pow_layer_block[56..].copy_from_slice(&(52u64 * 8).to_be_bytes());
+        let mut chain_layer_block = [0u8; 64];
+        chain_layer_block[..32].copy_from_slice(&header[LotusHeader::PREV_HASH]);
+        LotusMidstate {
+            pow_layer_block,
+            chain_layer_block,
+        }
+    }
+
+    /// Same as `lotus_hash` with `nonce` written to header bytes 44..48.
+    pub fn hash(&self, nonce: u32) -> [u8; 32] {
+        let mut pow_layer_block = self.pow_layer_block;
+        pow_layer_block[12..16].copy_from_slice(&nonce.to_le_bytes());
+        let mut pow_layer_state = SHA256_INIT;
+        sha2::compress256(
+            &mut pow_layer_state,
+            &[GenericArray::clone_from_slice(&pow_layer_block)],
+        );
+        let mut chain_layer_block = self.chain_layer_block;
+        for (chunk, word) in chain_layer_block[32..]
+            .chunks_mut(4)
+            .zip(pow_layer_state.iter())
+        {
+            chunk.copy_from_slice(&word.to_be_bytes());
+        }
+        let mut state = SHA256_INIT;
+        sha2::compress256(
+            &mut state,
+            &[
+                GenericArray::clone_from_slice(&chain_layer_block),
+                GenericArray::clone_from_slice(&CHAIN_LAYER_PAD),
+            ],
+        );
+        let mut hash = [0u8; 32];
+        for (chunk, word) in hash.chunks_mut(4).zip(state.iter()) {
+            chunk.copy_from_slice(&word.to_be_bytes());
+        }
+        hash
+    }
+}
+
+const SHA256_K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// Pow layer message words 13..16: padding of the 52 byte message.
+const POW_LAYER_PAD: [u32; 3] = [0x80000000, 0, 52 * 8];
+
+/// Everything of a header's Lotus hash that doesn't depend on the nonce, as
+/// passed to the `lotus_midstate` kernel, like poclbm's PreVal/D1A arguments.
+///
+/// `hash` and `last_word` mirror the kernel step by step, so the kernel can
+/// be checked against `lotus_hash` without a GPU. The nonce is header bytes
+/// 44..48 read as a big endian word, like the kernels see it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LotusPrecalc {
+    /// Pow layer state after round 3 without the nonce, i.e. the nonce is
+    /// added to `pre_val0` and `pre_val4`.
+    pub pre_val0: u32,
+    pub b1: u32,
+    pub c1: u32,
+    pub d1: u32,
+    pub pre_val4: u32,
+    pub f1: u32,
+    pub g1: u32,
+    pub h1: u32,
+    /// Pow layer message words 4..13.
+    pub pow_w: [u32; 9],
+    pub w16: u32,
+    pub w17: u32,
+    /// Word 18 without `schedule0(nonce)`.
+    pub pre_w18: u32,
+    /// Word 19 without the nonce.
+    pub pre_w19: u32,
+    /// Chain layer state after round 7.
+    pub chain_state: [u32; 8],
+    /// Chain layer message words 0..8, the previous block hash.
+    pub prev_hash: [u32; 8],
+}
+
+impl LotusPrecalc {
+    /// Names of the `lotus_midstate` kernel arguments set by `kernel_args`,
+    /// in order.
+    pub const KERNEL_ARG_NAMES: [&'static str; 37] = [
+        "PreVal0", "B1", "C1", "D1", "PreVal4", "F1", "G1", "H1", "W4", "W5", "W6", "W7", "W8",
+        "W9", "W10", "W11", "W12", "W16", "W17", "PreW18", "PreW19", "CA", "CB", "CC", "CD", "CE",
+        "CF", "CG", "CH", "Prev0", "Prev1", "Prev2", "Prev3", "Prev4", "Prev5", "Prev6", "Prev7",
+    ];
+
+    pub fn new(header: &[u8; 160]) -> Self {
+        let tx_layer_hash = sha2::Sha256::digest(&header[LotusHeader::TX_LAYER]);
+        let mut pow_w = [0u32; 16];
+        for (chunk, word) in header[LotusHeader::POW_LAYER]
+            .chunks(4)
+            .chain(tx_layer_hash.chunks(4))
+            .zip(pow_w.iter_mut())
+        {
+            *word = u32::from_be_bytes(chunk.try_into().unwrap());
+        }
+        pow_w[13..].copy_from_slice(&POW_LAYER_PAD);
+        let mut state = SHA256_INIT;
+        for i in 0..3 {
+            sha256_round(&mut state, SHA256_K[i].wrapping_add(pow_w[i]));
+        }
+        // Round 3 without the nonce in its message word
+        let [a, b, c, d, e, f, g, h] = state;
+        let d1a = h
+            .wrapping_add(big_sigma1(e))
+            .wrapping_add(choose(e, f, g))
+            .wrapping_add(SHA256_K[3]);
+        let w16 = pow_w[0]
+            .wrapping_add(schedule0(pow_w[1]))
+            .wrapping_add(pow_w[9])
+            .wrapping_add(schedule1(pow_w[14]));
+        let w17 = pow_w[1]
+            .wrapping_add(schedule0(pow_w[2]))
+            .wrapping_add(pow_w[10])
+            .wrapping_add(schedule1(pow_w[15]));
+        let mut prev_hash = [0u32; 8];
+        for (chunk, word) in header[LotusHeader::PREV_HASH]
+            .chunks(4)
+            .zip(prev_hash.iter_mut())
+        {
+            *word = u32::from_be_bytes(chunk.try_into().unwrap());
+        }
+        let mut chain_state = SHA256_INIT;
+        for (i, &word) in prev_hash.iter().enumerate() {
+            sha256_round(&mut chain_state, SHA256_K[i].wrapping_add(word));
+        }
+        LotusPrecalc {
+            pre_val0: d1a
+                .wrapping_add(big_sigma0(a))
+                .wrapping_add(majority(a, b, c)),
+            b1: a,
+            c1: b,
+            d1: c,
+            pre_val4: d.wrapping_add(d1a),
+            f1: e,
+            g1: f,
+            h1: g,
+            pow_w: pow_w[4..13].try_into().unwrap(),
+            w16,
+            w17,
+            pre_w18: pow_w[2]
+                .wrapping_add(pow_w[11])
+                .wrapping_add(schedule1(w16)),
+            pre_w19: pow_w[12]
+                .wrapping_add(schedule0(pow_w[4]))
+                .wrapping_add(schedule1(w17)),
+            chain_state,
+            prev_hash,
+        }
+    }
+
+    /// Values for `KERNEL_ARG_NAMES`.
+    pub fn kernel_args(&self) -> [u32; 37] {
+        let mut args = [0u32; 37];
+        args[..8].copy_from_slice(&[
+            self.pre_val0,
+            self.b1,
+            self.c1,
+            self.d1,
+            self.pre_val4,
+            self.f1,
+            self.g1,
+            self.h1,
+        ]);
+        args[8..17].copy_from_slice(&self.pow_w);
+        args[17..21].copy_from_slice(&[self.w16, self.w17, self.pre_w18, self.pre_w19]);
+        args[21..29].copy_from_slice(&self.chain_state);
+        args[29..].copy_from_slice(&self.prev_hash);
+        args
+    }
+}
+
+/// Reference implementation of the `lotus_midstate` kernel, step by step.
+#[cfg(test)]
+impl LotusPrecalc {
+    /// Same as `lotus_hash` with `nonce` as header bytes 44..48 (big endian).
+    pub fn hash(&self, nonce: u32) -> [u8; 32] {
+        let mut state = self.chain_layer_state(nonce);
+        let mid_state = state;
+        for &kw in CHAIN_LAYER_PAD_KW.iter() {
+            sha256_round(&mut state, kw);
+        }
+        let mut hash = [0u8; 32];
+        for ((chunk, &word), &mid_word) in hash.chunks_mut(4).zip(&state).zip(&mid_state) {
+            chunk.copy_from_slice(&word.wrapping_add(mid_word).to_be_bytes());
+        }
+        hash
+    }
+
+    /// Last word of `hash`, computed with the kernel's early exit; the
+    /// kernels report nonces for which it's 0.
+    pub fn last_word(&self, nonce: u32) -> u32 {
+        let mid_state = self.chain_layer_state(nonce);
+        let mut state = mid_state;
+        for &kw in &CHAIN_LAYER_PAD_KW[..61] {
+            sha256_round(&mut state, kw);
+        }
+        mid_state[7].wrapping_add(state[4])
+    }
+
+    /// State after the first block of the chain layer.
+    fn chain_layer_state(&self, nonce: u32) -> [u32; 8] {
+        let mut state = [
+            self.pre_val0.wrapping_add(nonce),
+            self.b1,
+            self.c1,
+            self.d1,
+            self.pre_val4.wrapping_add(nonce),
+            self.f1,
+            self.g1,
+            self.h1,
+        ];
+        let mut pow_w = [0u32; 64];
+        pow_w[3] = nonce;
+        pow_w[4..13].copy_from_slice(&self.pow_w);
+        pow_w[13..16].copy_from_slice(&POW_LAYER_PAD);
+        pow_w[16] = self.w16;
+        pow_w[17] = self.w17;
+        pow_w[18] = self.pre_w18.wrapping_add(schedule0(nonce));
+        pow_w[19] = self.pre_w19.wrapping_add(nonce);
+        sha256_extend(&mut pow_w, 20);
+        for i in 4..64 {
+            sha256_round(&mut state, SHA256_K[i].wrapping_add(pow_w[i]));
+        }
+        let mut chain_w = [0u32; 64];
+        chain_w[..8].copy_from_slice(&self.prev_hash);
+        for i in 0..8 {
+            chain_w[i + 8] = state[i].wrapping_add(SHA256_INIT[i]);
+        }
+        sha256_extend(&mut chain_w, 16);
+        let mut chain_state = self.chain_state;
+        for i in 8..64 {
+            sha256_round(&mut chain_state, SHA256_K[i].wrapping_add(chain_w[i]));
+        }
+        for (word, &init) in chain_state.iter_mut().zip(&SHA256_INIT) {
+            *word = word.wrapping_add(init);
+        }
+        chain_state
+    }
+}
+
+/// `SHA256_K[i]` plus schedule word `i` of `CHAIN_LAYER_PAD`, which doesn't
+/// depend on the header at all.
+#[cfg(test)]
+const CHAIN_LAYER_PAD_KW: [u32; 64] = {
+    let mut w = [0u32; 64];
+    w[0] = 0x80000000;
+    w[15] = 64 * 8;
+    let mut i = 16;
+    while i < 64 {
+        w[i] = w[i - 16]
+            .wrapping_add(schedule0(w[i - 15]))
+            .wrapping_add(w[i - 7])
+            .wrapping_add(schedule1(w[i - 2]));
+        i += 1;
+    }
+    let mut kw = [0u32; 64];
+    let mut i = 0;
+    while i < 64 {
+        kw[i] = SHA256_K[i].wrapping_add(w[i]);
+        i += 1;
+    }
+    kw
+};
+
+const fn big_sigma0(a: u32) -> u32 {
+    a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22)
+}
+
+const fn big_sigma1(e: u32) -> u32 {
+    e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25)
+}
+
+const fn choose(e: u32, f: u32, g: u32) -> u32 {
+    (e & f) ^ (!e & g)
+}
+
+const fn majority(a: u32, b: u32, c: u32) -> u32 {
+    (a & b) ^ (a & c) ^ (b & c)
+}
+
+const fn schedule0(w: u32) -> u32 {
+    w.rotate_right(7) ^ w.rotate_right(18) ^ (w >> 3)
+}
+
+const fn schedule1(w: u32) -> u32 {
+    w.rotate_right(17) ^ w.rotate_right(19) ^ (w >> 10)
+}
+
+fn sha256_round(state: &mut [u32; 8], kw: u32) {
+    let [a, b, c, d, e, f, g, h] = *state;
+    let tmp1 = h
+        .wrapping_add(big_sigma1(e))
+        .wrapping_add(choose(e, f, g))
+        .wrapping_add(kw);
+    let tmp2 = big_sigma0(a).wrapping_add(majority(a, b, c));
+    *state = [
+        tmp1.wrapping_add(tmp2),
+        a,
+        b,
+        c,
+        d.wrapping_add(tmp1),
+        e,
+        f,
+        g,
+    ];
+}
+
+#[cfg(test)]
+fn sha256_extend(w: &mut [u32; 64], start: usize) {
+    for i in start..64 {
+        w[i] = w[i - 16]
+            .wrapping_add(schedule0(w[i - 15]))
+            .wrapping_add(w[i - 7])
+            .wrapping_add(schedule1(w[i - 2]));
+    }
+}
+
 #[test]
 fn test_lotus_hash() {
     use hex_literal::hex;
@@ -19,7 +385,38 @@ fn test_lotus_hash() {
     let mut hash = lotus_hash(&header);
     hash.reverse();
     assert_eq!(
-        hex::encode(&hash),
+        hex::encode(hash),
         "000000006275dc5039da85620773f3223d629759495f80b49a381d79cae77c11"
     );
 }
+
+#[test]
+fn test_lotus_midstate() {
+    use hex_literal::hex;
+    let mut header = hex!("0000000000000000000000000000000000000000000000000000000000000000ffff001d00c273600000000041c6ddd303000000010e010000000000000000000000000000000000000000000000000000000000000000000000000000000000934755d60e905ec8778f554164bd9b7f21ab6c15cfed2956123a722a6f6fa62e1406e05881e299367766d313e26c05564ec91bf721d31726bd6e46e60689539a");
+    let midstate = LotusMidstate::new(&header);
+    for &nonce in &[0x41c6ddd3u32.swap_bytes(), 0, 1, 0xdeadbeef, u32::MAX] {
+        header[44..48].copy_from_slice(&nonce.to_le_bytes());
+        assert_eq!(midstate.hash(nonce), lotus_hash(&header));
+    }
+}
+
+#[test]
+fn test_lotus_precalc() {
+    use hex_literal::hex;
+    let mut header = hex!("0000000000000000000000000000000000000000000000000000000000000000ffff001d00c273600000000041c6ddd303000000010e010000000000000000000000000000000000000000000000000000000000000000000000000000000000934755d60e905ec8778f554164bd9b7f21ab6c15cfed2956123a722a6f6fa62e1406e05881e299367766d313e26c05564ec91bf721d31726bd6e46e60689539a");
+    // Give chain layer rounds 0..7 something to work with
+    header[..32].copy_from_slice(&sha256d(b"prev block"));
+    let precalc = LotusPrecalc::new(&header);
+    assert_eq!(precalc.kernel_args()[0], precalc.pre_val0);
+    assert_eq!(precalc.kernel_args()[36], precalc.prev_hash[7]);
+    for &nonce in &[0x41c6ddd3, 0, 1, 0xdeadbeef, u32::MAX] {
+        header[44..48].copy_from_slice(&u32::to_be_bytes(nonce));
+        let hash = lotus_hash(&header);
+        assert_eq!(precalc.hash(nonce), hash);
+        assert_eq!(
+            precalc.last_word(nonce),
+            u32::from_be_bytes(hash[28..].try_into().unwrap())
+        );
+    }
+}
@@ -0,0 +1,458 @@
+use std::{
+    collections::HashMap,
+    convert::{TryFrom, TryInto},
+};
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+use thiserror::Error;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{tcp::OwnedWriteHalf, TcpStream},
+};
+
+use crate::{
+    block::{coinbase_merkle_root, Block, LotusHeader, PoolJob, SplitBlock},
+    difficulty::difficulty_to_target,
+    set_current_block, Server,
+};
+
+const SUBSCRIBE_ID: u64 = 1;
+const AUTHORIZE_ID: u64 = 2;
+/// Longest extranonce2 we accept from `mining.subscribe`.
+const MAX_EXTRANONCE2_SIZE: usize = 32;
+
+#[derive(Debug, Error)]
+pub enum StratumError {
+    #[error("Invalid pool URL {0:?}, expected stratum+tcp://host:port")]
+    InvalidUrl(String),
+    #[error("Invalid {0} message from pool: {1}")]
+    InvalidMessage(&'static str, String),
+    #[error("Pool rejected authorization of worker {0:?}: {1}")]
+    Unauthorized(String, String),
+    #[error("Pool closed the connection")]
+    Disconnected,
+}
+
+/// Write half of the connection to the pool, used to submit shares.
+pub struct StratumConnection {
+    writer: OwnedWriteHalf,
+    worker: String,
+    next_id: u64,
+    pending_shares: HashMap<u64, String>,
+}
+
+/// A `mining.notify` job. Besides the Stratum v1 params `[job_id, prevhash,
+/// coinb1, coinb2, merkle_branch, version, nbits, ntime, clean_jobs]`, Lotus
+/// jobs carry the header fields Bitcoin headers lack: `height`, `epoch_hash`,
+/// `size` and `extended_metadata_hash`. Integers are hex in big endian,
+/// except `height` and `size`, which are JSON numbers; hashes are hex in
+/// header byte order, except `prevhash`, whose 32-bit words each have their
+/// bytes reversed.
+#[derive(Debug, Clone)]
+pub(crate) struct StratumJob {
+    pub job_id: String,
+    /// Header with a null merkle root, as that depends on the extranonce.
+    pub header: LotusHeader,
+    pub coinbase1: Vec<u8>,
+    pub coinbase2: Vec<u8>,
+    pub merkle_branch: Vec<[u8; 32]>,
+}
+
+/// A Stratum request, response or notification; one JSON object per line.
+#[derive(Deserialize, Debug)]
+pub(crate) struct StratumMessage {
+    #[serde(default)]
+    pub id: Option<u64>,
+    #[serde(default)]
+    pub method: Option<String>,
+    #[serde(default)]
+    pub params: Value,
+    #[serde(default)]
+    pub result: Value,
+    #[serde(default)]
+    pub error: Value,
+}
+
+pub(crate) async fn write_message(
+    writer: &mut OwnedWriteHalf,
+    message: Value,
+) -> std::io::Result<()> {
+    let mut line = message.to_string();
+    line.push('\n');
+    writer.write_all(line.as_bytes()).await
+}
+
+impl StratumConnection {
+    async fn send(&mut self, id: u64, method: &str, params: Value) -> std::io::Result<()> {
+        let message = json!({"id": id, "method": method, "params": params});
+        write_message(&mut self.writer, message).await
+    }
+
+    /// Submits a share, whose result is logged once the pool replies.
+    pub async fn submit_share(
+        &mut self,
+        job: &PoolJob,
+        time: u64,
+        nonce: u64,
+    ) -> std::io::Result<()> {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.pending_shares.insert(id, job.job_id.clone());
+        let params = json!([
+            self.worker,
+            job.job_id,
+            hex::encode(&job.extranonce2),
+            format!("{:08x}", time),
+            format!("{:016x}", nonce),
+        ]);
+        self.send(id, "mining.submit", params).await
+    }
+}
+
+impl StratumJob {
+    pub(crate) fn parse(params: &Value) -> Option<Self> {
+        // clean_jobs; every job replaces the last one anyway
+        params[8].as_bool()?;
+        let version = u8::try_from(hex_int(&params[5], 8)?).ok()?;
+        let header = LotusHeader {
+            prev_hash: swap_words(hex_hash(&params[1])?),
+            bits: hex_int(&params[6], 8)?.try_into().ok()?,
+            time: hex_int(&params[7], 12)?,
+            reserved: 0,
+            nonce: 0,
+            version,
+            size: params[11].as_u64()?,
+            height: params[9].as_u64()?.try_into().ok()?,
+            epoch_hash: hex_hash(&params[10])?,
+            merkle_root: [0; 32],
+            extended_metadata_hash: hex_hash(&params[12])?,
+        };
+        Some(StratumJob {
+            job_id: params[0].as_str()?.to_string(),
+            header,
+            coinbase1: hex::decode(params[2].as_str()?).ok()?,
+            coinbase2: hex::decode(params[3].as_str()?).ok()?,
+            merkle_branch: params[4]
+                .as_array()?
+                .iter()
+                .map(hex_hash)
+                .collect::<Option<_>>()?,
+        })
+    }
+
+    /// Header of the block whose coinbase carries `extranonce`, i.e.
+    /// extranonce1 and extranonce2; `None` if the coinbase is malformed.
+    pub(crate) fn header(&self, extranonce: &[u8]) -> Option<LotusHeader> {
+        let coinbase = [&self.coinbase1[..], extranonce, &self.coinbase2].concat();
+        let mut header = self.header;
+        header.merkle_root = coinbase_merkle_root(&coinbase, &self.merkle_branch)?;
+        Some(header)
+    }
+}
+
+/// `mining.notify` params of `block`, as parsed by `StratumJob::parse`.
+pub(crate) fn notify_params(job_id: &str, block: &SplitBlock, clean_jobs: bool) -> Value {
+    let header = &block.header;
+    let merkle_branch = block
+        .merkle_branch
+        .iter()
+        .map(hex::encode)
+        .collect::<Vec<_>>();
+    json!([
+        job_id,
+        hex::encode(swap_words(header.prev_hash)),
+        hex::encode(&block.coinbase1),
+        hex::encode(&block.coinbase2),
+        merkle_branch,
+        format!("{:08x}", header.version),
+        format!("{:08x}", header.bits),
+        format!("{:08x}", header.time),
+        clean_jobs,
+        header.height,
+        hex::encode(header.epoch_hash),
+        header.size,
+        hex::encode(header.extended_metadata_hash),
+    ])
+}
+
+/// Parses a big endian hex integer of at most `max_digits` digits.
+pub(crate) fn hex_int(param: &Value, max_digits: usize) -> Option<u64> {
+    let hex = param.as_str()?;
+    if hex.is_empty() || hex.len() > max_digits || !hex.bytes().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    u64::from_str_radix(hex, 16).ok()
+}
+
+fn hex_hash(param: &Value) -> Option<[u8; 32]> {
+    hex::decode(param.as_str()?).ok()?.try_into().ok()
+}
+
+/// Reverses the bytes of each 32-bit word, converting between header byte
+/// order and Stratum's `prevhash`.
+fn swap_words(mut hash: [u8; 32]) -> [u8; 32] {
+    for word in hash.chunks_mut(4) {
+        word.reverse();
+    }
+    hash
+}
+
+/// Connects to the pool in `NodeSettings::pool_url` and feeds its jobs into
+/// the device threads until the connection fails. The pool's job is dropped
+/// with the connection, as shares for it can't be submitted anymore.
+pub async fn run_stratum(server: &Server) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let result = run_pool_connection(server).await;
+    *server.stratum.lock().await = None;
+    let mut block_state = server.block_state.lock().await;
+    if block_state.current_block.is_some() {
+        server.log().warn("Lost the pool, stopped mining its job");
+    }
+    set_current_block(server, &mut block_state, None);
+    result
+}
+
+async fn run_pool_connection(
+    server: &Server,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let log = server.log();
+    let (pool_url, worker, password) = {
+        let node_settings = server.node_settings.lock().await;
+        let worker = if node_settings.pool_user.is_empty() {
+            node_settings.miner_addr.clone()
+        } else {
+            node_settings.pool_user.clone()
+        };
+        (
+            node_settings.pool_url.clone(),
+            worker,
+            node_settings.pool_password.clone(),
+        )
+    };
+    let addr = pool_url
+        .strip_prefix("stratum+tcp://")
+        .ok_or_else(|| StratumError::InvalidUrl(pool_url.clone()))?;
+    let stream = TcpStream::connect(addr).await?;
+    let (reader, writer) = stream.into_split();
+    let mut connection = StratumConnection {
+        writer,
+        worker: worker.clone(),
+        next_id: AUTHORIZE_ID + 1,
+        pending_shares: HashMap::new(),
+    };
+    connection
+        .send(SUBSCRIBE_ID, "mining.subscribe", json!(["lotus-gpu-miner"]))
+        .await?;
+    connection
+        .send(AUTHORIZE_ID, "mining.authorize", json!([worker, password]))
+        .await?;
+    *server.stratum.lock().await = Some(connection);
+    log.info(format!("Connected to pool {}", pool_url));
+
+    let mut lines = BufReader::new(reader).lines();
+    let mut extranonce1 = Vec::new();
+    let mut extranonce2_size = 0;
+    let mut share_target = difficulty_to_target(1.0);
+    while let Some(line) = lines.next_line().await? {
+        let message: StratumMessage = match serde_json::from_str(&line) {
+            Ok(message) => message,
+            Err(_) => {
+                log.warn(format!("Ignoring invalid message from pool: {}", line));
+                continue;
+            }
+        };
+        match (message.method.as_deref(), message.id) {
+            (Some("mining.set_difficulty"), _) => {
+                let difficulty = message.params[0]
+                    .as_f64()
+                    .filter(|&difficulty| difficulty > 0.0)
+                    .ok_or_else(|| StratumError::InvalidMessage("set_difficulty", line.clone()))?;
+                log.info(format!("Pool set share difficulty to {}", difficulty));
+                share_target = difficulty_to_target(difficulty);
+            }
+            (Some("mining.notify"), _) => {
+                let block = job_block(
+                    &message.params,
+                    &extranonce1,
+                    extranonce2_size,
+                    share_target,
+                )
+                .ok_or_else(|| StratumError::InvalidMessage("notify", line.clone()))?;
+                let mut block_state = server.block_state.lock().await;
+                if block_state.current_block.is_none() {
+                    log.info("Started mining on pool job");
+                }
+                set_current_block(server, &mut block_state, Some(block));
+            }
+            (Some(method), _) => log.warn(format!("Ignoring pool method {}", method)),
+            (None, Some(SUBSCRIBE_ID)) => {
+                let invalid = || StratumError::InvalidMessage("subscribe", line.clone());
+                extranonce1 = message.result[1]
+                    .as_str()
+                    .and_then(|extranonce1| hex::decode(extranonce1).ok())
+                    .ok_or_else(invalid)?;
+                extranonce2_size = message.result[2]
+                    .as_u64()
+                    .and_then(|size| usize::try_from(size).ok())
+                    .filter(|&size| size <= MAX_EXTRANONCE2_SIZE)
+                    .ok_or_else(invalid)?;
+            }
+            (None, Some(AUTHORIZE_ID)) => {
+                if message.result != Value::Bool(true) {
+                    return Err(
+                        StratumError::Unauthorized(worker, message.error.to_string()).into(),
+                    );
+                }
+                log.info(format!("Pool authorized worker {}", worker));
+            }
+            (None, Some(id)) => {
+                let job_id = match server.stratum.lock().await.as_mut() {
+                    Some(connection) => connection.pending_shares.remove(&id),
+                    None => None,
+                };
+                match (job_id, &message.result) {
+                    (Some(job_id), Value::Bool(true)) => {
+                        log.info(format!("Share for job {} accepted", job_id))
+                    }
+                    (Some(job_id), _) => log.error(format!(
+                        "Share for job {} rejected: {}",
+                        job_id, message.error
+                    )),
+                    (None, _) => log.warn(format!("Unexpected response from pool: {}", line)),
+                }
+            }
+            (None, None) => log.warn(format!("Unexpected message from pool: {}", line)),
+        }
+    }
+    Err(StratumError::Disconnected.into())
+}
+
+/// Block to mine for a `mining.notify` job. The pool only needs to tell
+/// our shares apart from other miners', so extranonce2 stays zero and the
+/// devices search the full 64-bit nonce instead.
+fn job_block(
+    params: &Value,
+    extranonce1: &[u8],
+    extranonce2_size: usize,
+    share_target: [u8; 32],
+) -> Option<Block> {
+    let job = StratumJob::parse(params)?;
+    let extranonce2 = vec![0; extranonce2_size];
+    let header = job.header(&[extranonce1, &extranonce2].concat())?;
+    Some(Block {
+        header,
+        body: Vec::new(),
+        target: share_target,
+        job: Some(PoolJob {
+            job_id: job.job_id,
+            extranonce2,
+        }),
+    })
+}
+
+#[tokio::test]
+async fn test_stratum_mock_pool() {
+    use crate::{settings::test_config, sha256::lotus_hash, ConfigSettings, ServerRef};
+    use std::{sync::Arc, time::Duration};
+    use tokio::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let pool_url = format!("stratum+tcp://{}", listener.local_addr().unwrap());
+    // Coinbase with a 2 byte extranonce1 and 4 byte extranonce2, paying to
+    // OP_RETURN
+    let coinb1 = format!("0100000001{}ffffffff0706", "00".repeat(32));
+    let coinb2 = "ffffffff010000000000000000016a00000000";
+    let merkle_branch = vec![format!("{:064x}", 7)];
+    let notify_params = json!([
+        "job1",
+        format!("{:064x}", 0xabcd),
+        coinb1,
+        coinb2,
+        merkle_branch,
+        "00000001",
+        "1d00ffff",
+        "60cbc200",
+        true,
+        1000,
+        format!("{:064x}", 0x1234),
+        300,
+        hex::encode(crate::sha256::sha256d(&[0])),
+    ]);
+    let mock_pool = tokio::spawn({
+        let notify_params = notify_params.clone();
+        async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = stream.into_split();
+            let mut lines = BufReader::new(reader).lines();
+            while let Some(line) = lines.next_line().await.unwrap() {
+                let request: Value = serde_json::from_str(&line).unwrap();
+                let replies = match request["method"].as_str().unwrap() {
+                    "mining.subscribe" => {
+                        let subscriptions = json!([["mining.notify", "1"]]);
+                        vec![json!({"id": 1, "result": [subscriptions, "abcd", 4], "error": null})]
+                    }
+                    "mining.authorize" => {
+                        assert_eq!(request["params"][0], "worker1");
+                        // One in 256 hashes meets a share difficulty of 2^-24
+                        let difficulty = 1.0 / (1u64 << 24) as f64;
+                        vec![
+                            json!({"id": 2, "result": true, "error": null}),
+                            json!({
+                                "id": null,
+                                "method": "mining.set_difficulty",
+                                "params": [difficulty]
+                            }),
+                            json!({"id": null, "method": "mining.notify", "params": notify_params}),
+                        ]
+                    }
+                    "mining.submit" => return request["params"].clone(),
+                    method => panic!("unexpected method {}", method),
+                };
+                for reply in replies {
+                    let line = format!("{}\n", reply);
+                    writer.write_all(line.as_bytes()).await.unwrap();
+                }
+            }
+            panic!("miner disconnected");
+        }
+    });
+
+    let config = ConfigSettings {
+        pool_url,
+        pool_user: "worker1".to_string(),
+        ..test_config()
+    };
+    let server: ServerRef = Arc::new(Server::from_config(config, Duration::from_secs(10)).unwrap());
+    tokio::spawn({
+        let server = Arc::clone(&server);
+        async move { server.run().await.unwrap() }
+    });
+    let submit = tokio::time::timeout(Duration::from_secs(30), mock_pool)
+        .await
+        .expect("no share submitted")
+        .unwrap();
+    // The mock pool hung up, so its job must not be mined anymore
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while server.block_state.lock().await.current_block.is_some() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("pool job still mined after disconnect");
+
+    // [worker, job_id, extranonce2, ntime, nonce]
+    assert_eq!(submit[0], "worker1");
+    assert_eq!(submit[1], "job1");
+    let extranonce2 = hex::decode(submit[2].as_str().unwrap()).unwrap();
+    assert_eq!(extranonce2.len(), 4);
+    assert_eq!(submit[3], "60cbc200");
+    let nonce = u64::from_str_radix(submit[4].as_str().unwrap(), 16).unwrap();
+    let job = StratumJob::parse(&notify_params).unwrap();
+    let mut header = job
+        .header(&[&[0xab, 0xcd], &extranonce2[..]].concat())
+        .unwrap();
+    assert_eq!(header.prev_hash[..4], [0, 0, 0, 0]);
+    assert_eq!(header.prev_hash[28..], [0xcd, 0xab, 0, 0]);
+    header.nonce = nonce;
+    assert_eq!(lotus_hash(&header.to_bytes())[31], 0);
+}
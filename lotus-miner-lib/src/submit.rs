@@ -0,0 +1,307 @@
+use std::time::Duration;
+
+use reqwest::StatusCode;
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::{
+    block::Block, difficulty::hash_below_target, display_hash, node::NodeError, send_request, Log,
+    Server,
+};
+
+/// How often `submitblock` is sent before giving up on transport errors.
+const SUBMIT_ATTEMPTS: u32 = 4;
+/// Wait before the first retry; doubled for every further one.
+const SUBMIT_RETRY_BACKOFF: Duration = Duration::from_millis(500);
+
+const UPDATE_ADVICE: &str = "Make sure you run the latest lotusd/Lotus-QT and lotus-gpu-miner.";
+const ORPHAN_RACE_ADVICE: &str = "This is an orphan race; might be fixed by lowering \
+                                  rpc_poll_interval or updating to the newest lotus-gpu-miner.";
+const OUTDATED_BLOCK_ADVICE: &str = "The block was built on outdated node state; lower \
+                                     rpc_poll_interval or set zmq_hashblock to switch blocks \
+                                     sooner.";
+
+#[derive(Debug, Error)]
+pub enum SubmitError {
+    #[error("Hash {0} of the found block isn't below its target")]
+    HashAboveTarget(String),
+    #[error("Found block builds on {0}, but the node's tip is {1} now")]
+    StaleTip(String, String),
+    #[error("{0}")]
+    Transport(#[from] NodeError),
+    #[error("Node answered submitblock with HTTP {0}: {1}")]
+    Http(StatusCode, String),
+    #[error("Node answered submitblock with error {0}: {1}")]
+    Rpc(i64, String),
+    #[error("Invalid submitblock response {0:?}: {1}")]
+    InvalidResponse(String, serde_json::Error),
+    #[error("REJECTED BLOCK: {0}")]
+    Rejected(SubmitBlockRejection),
+}
+
+/// Reasons lotusd's `submitblock` gives for not accepting a block. Displays
+/// as the node's reason.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum SubmitBlockRejection {
+    #[error("duplicate")]
+    Duplicate,
+    #[error("duplicate-invalid")]
+    DuplicateInvalid,
+    #[error("duplicate-inconclusive")]
+    DuplicateInconclusive,
+    #[error("inconclusive")]
+    Inconclusive,
+    #[error("high-hash")]
+    HighHash,
+    #[error("bad-diffbits")]
+    BadDiffBits,
+    /// "bad-prevblk", "stale-prevblk" or "prev-blk-not-found".
+    #[error("{0}")]
+    StalePrevBlock(String),
+    #[error("bad-txnmrklroot")]
+    BadMerkleRoot,
+    #[error("time-too-new")]
+    TimeTooNew,
+    #[error("time-too-old")]
+    TimeTooOld,
+    /// "bad-version(...)".
+    #[error("{0}")]
+    BadVersion(String),
+    /// "bad-cb-...".
+    #[error("{0}")]
+    BadCoinbase(String),
+    /// "bad-txns-...".
+    #[error("{0}")]
+    BadTransactions(String),
+    /// "bad-blk-...".
+    #[error("{0}")]
+    BadBlock(String),
+    #[error("{0}")]
+    Other(String),
+}
+
+impl SubmitBlockRejection {
+    pub fn from_reason(reason: &str) -> Self {
+        use SubmitBlockRejection::*;
+        match reason {
+            "duplicate" => Duplicate,
+            "duplicate-invalid" => DuplicateInvalid,
+            "duplicate-inconclusive" => DuplicateInconclusive,
+            "inconclusive" => Inconclusive,
+            "high-hash" => HighHash,
+            "bad-diffbits" => BadDiffBits,
+            "bad-prevblk" | "stale-prevblk" | "prev-blk-not-found" => {
+                StalePrevBlock(reason.to_string())
+            }
+            "bad-txnmrklroot" => BadMerkleRoot,
+            "time-too-new" => TimeTooNew,
+            "time-too-old" => TimeTooOld,
+            _ if reason.starts_with("bad-version") => BadVersion(reason.to_string()),
+            _ if reason.starts_with("bad-cb-") => BadCoinbase(reason.to_string()),
+            _ if reason.starts_with("bad-txns-") => BadTransactions(reason.to_string()),
+            _ if reason.starts_with("bad-blk-") => BadBlock(reason.to_string()),
+            _ => Other(reason.to_string()),
+        }
+    }
+
+    /// What the user can do about the rejection.
+    pub fn remediation(&self) -> &'static str {
+        use SubmitBlockRejection::*;
+        match self {
+            Duplicate => "The node already has this block; nothing to do.",
+            DuplicateInconclusive | Inconclusive => ORPHAN_RACE_ADVICE,
+            StalePrevBlock(_) | BadDiffBits | TimeTooOld | BadTransactions(_) => {
+                OUTDATED_BLOCK_ADVICE
+            }
+            HighHash => {
+                "The node computes a different target than the block had; make sure you run \
+                 the latest lotusd/Lotus-QT and lotus-gpu-miner."
+            }
+            TimeTooNew => "The block's timestamp is too far ahead; check this machine's clock.",
+            BadCoinbase(_) => {
+                "The coinbase is invalid; check mine_to_address, and make sure you run the \
+                 latest lotusd/Lotus-QT and lotus-gpu-miner."
+            }
+            DuplicateInvalid | BadMerkleRoot | BadVersion(_) | BadBlock(_) | Other(_) => {
+                UPDATE_ADVICE
+            }
+        }
+    }
+}
+
+impl SubmitError {
+    /// What the user can do about the error.
+    pub fn remediation(&self) -> &'static str {
+        match self {
+            SubmitError::HashAboveTarget(_) => {
+                "The device reported a wrong nonce; this is likely a driver bug, try another \
+                 kernel."
+            }
+            SubmitError::StaleTip(..) => OUTDATED_BLOCK_ADVICE,
+            SubmitError::Transport(_) | SubmitError::Http(..) => {
+                "This could be a connection issue; check the node is running and reachable."
+            }
+            SubmitError::Rpc(..) | SubmitError::InvalidResponse(..) => UPDATE_ADVICE,
+            SubmitError::Rejected(rejection) => rejection.remediation(),
+        }
+    }
+
+    /// Whether the block failed verification, so it was never sent.
+    pub(crate) fn is_dropped(&self) -> bool {
+        matches!(
+            self,
+            SubmitError::HashAboveTarget(_) | SubmitError::StaleTip(..)
+        )
+    }
+
+    /// Whether sending the block again might succeed.
+    fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            SubmitError::Transport(NodeError::Request(_))
+                | SubmitError::Http(StatusCode::SERVICE_UNAVAILABLE, _)
+        )
+    }
+}
+
+/// Logs a failed submission with what to do about it.
+pub(crate) fn log_submit_error(log: &Log, err: &SubmitError) {
+    log.error(format!("submit_block error: {}", err));
+    match err {
+        SubmitError::Rejected(SubmitBlockRejection::Duplicate) => log.info(err.remediation()),
+        SubmitError::Rejected(SubmitBlockRejection::Inconclusive)
+        | SubmitError::Rejected(SubmitBlockRejection::DuplicateInconclusive)
+        | SubmitError::StaleTip(..) => log.warn(err.remediation()),
+        _ => log.error(err.remediation()),
+    }
+}
+
+/// Submits a solved `block` to the active node. Before that, the block is
+/// hashed again and checked against its target and the node's tip, so a
+/// wrong nonce or an outdated block is never sent. Transport errors are
+/// retried with exponential backoff.
+pub(crate) async fn submit_block(server: &Server, block: &Block) -> Result<(), SubmitError> {
+    let log = server.log();
+    verify_block(server, block).await?;
+    let serialized_block = block.serialize();
+    let body = format!(
+        r#"{{"method":"submitblock","params":[{:?}]}}"#,
+        hex::encode(&serialized_block)
+    );
+    let mut backoff = SUBMIT_RETRY_BACKOFF;
+    for attempt in 1..=SUBMIT_ATTEMPTS {
+        match send_submitblock(server, body.clone()).await {
+            Ok(()) => {
+                log.info("BLOCK ACCEPTED!");
+                return Ok(());
+            }
+            // An earlier attempt reached the node, only its answer got lost
+            Err(SubmitError::Rejected(SubmitBlockRejection::Duplicate)) if attempt > 1 => {
+                log.info("BLOCK ACCEPTED! (by an earlier attempt)");
+                return Ok(());
+            }
+            Err(err) if err.is_transient() && attempt < SUBMIT_ATTEMPTS => {
+                log.warn(format!(
+                    "submitblock attempt {} failed: {}. Retrying in {:?}",
+                    attempt, err, backoff
+                ));
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    unreachable!("the last attempt always returns")
+}
+
+/// Checks the found block's hash against its target, and its previous block
+/// against the node's tip. If the tip can't be fetched, the block is
+/// submitted anyway.
+async fn verify_block(server: &Server, block: &Block) -> Result<(), SubmitError> {
+    #[derive(Deserialize)]
+    struct GetBestBlockHashResponse {
+        result: Option<String>,
+    }
+    let hash = block.header.hash();
+    if !hash_below_target(&hash, &block.target) {
+        return Err(SubmitError::HashAboveTarget(display_hash(&hash)));
+    }
+    let body = r#"{"method":"getbestblockhash","params":[]}"#.to_string();
+    let tip = match send_request(server, body).await {
+        Ok((status, response)) if status.is_success() => {
+            serde_json::from_str::<GetBestBlockHashResponse>(&response)
+                .ok()
+                .and_then(|response| response.result)
+        }
+        _ => None,
+    };
+    let prev_hash = display_hash(block.prev_hash());
+    match tip {
+        Some(tip) if tip != prev_hash => Err(SubmitError::StaleTip(prev_hash, tip)),
+        Some(_) => Ok(()),
+        None => {
+            server
+                .log()
+                .warn("Couldn't get the node's tip, submitting the block unchecked");
+            Ok(())
+        }
+    }
+}
+
+async fn send_submitblock(server: &Server, body: String) -> Result<(), SubmitError> {
+    #[derive(Deserialize)]
+    struct SubmitBlockResponse {
+        result: Option<String>,
+        error: Option<RpcError>,
+    }
+    #[derive(Deserialize)]
+    struct RpcError {
+        code: i64,
+        message: String,
+    }
+    let (status, response) = send_request(server, body).await?;
+    let parsed = serde_json::from_str::<SubmitBlockResponse>(&response);
+    match parsed {
+        Ok(SubmitBlockResponse {
+            error: Some(error), ..
+        }) => Err(SubmitError::Rpc(error.code, error.message)),
+        Ok(_) if !status.is_success() => Err(SubmitError::Http(status, response)),
+        Ok(SubmitBlockResponse { result: None, .. }) => Ok(()),
+        Ok(SubmitBlockResponse {
+            result: Some(reason),
+            ..
+        }) => Err(SubmitError::Rejected(SubmitBlockRejection::from_reason(
+            &reason,
+        ))),
+        Err(_) if !status.is_success() => Err(SubmitError::Http(status, response)),
+        Err(err) => Err(SubmitError::InvalidResponse(response, err)),
+    }
+}
+
+#[test]
+fn test_submit_block_rejection() {
+    use SubmitBlockRejection::*;
+    assert_eq!(SubmitBlockRejection::from_reason("duplicate"), Duplicate);
+    assert_eq!(SubmitBlockRejection::from_reason("high-hash"), HighHash);
+    assert_eq!(
+        SubmitBlockRejection::from_reason("bad-txnmrklroot"),
+        BadMerkleRoot
+    );
+    assert_eq!(
+        SubmitBlockRejection::from_reason("bad-cb-amount"),
+        BadCoinbase("bad-cb-amount".to_string())
+    );
+    assert_eq!(
+        SubmitBlockRejection::from_reason("stale-prevblk"),
+        StalePrevBlock("stale-prevblk".to_string())
+    );
+    assert_eq!(
+        SubmitBlockRejection::from_reason("bad-version(0x00000001)").to_string(),
+        "bad-version(0x00000001)"
+    );
+    assert_eq!(
+        SubmitBlockRejection::from_reason("rejected"),
+        Other("rejected".to_string())
+    );
+    assert!(Inconclusive.remediation().contains("orphan race"));
+}
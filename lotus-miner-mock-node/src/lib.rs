@@ -0,0 +1,285 @@
+//! In-process mock of lotusd's JSON-RPC interface, so the miner can be tested
+//! end to end without a node.
+
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::watch,
+};
+
+/// Address the coinbase of the default block pays; set it as
+/// `mine_to_address`, as the miner checks it.
+pub const MINER_ADDR: &str = "bitcoincash:qpm2qsznhks23z7629mms6s4cwef74vcwvy22gdx6a";
+
+/// Block at height 1000 on top of a recognizable previous block hash, with
+/// nBits 2000ffff and only a coinbase paying `MINER_ADDR`. Laid out like
+/// lotusd serializes blocks: header, transactions, then the empty extended
+/// metadata (a single 0x00), whose sha256d is also the metadata hash in
+/// Lotus' genesis header. It is built by hand from that layout rather than
+/// captured from a lotusd node, so it can't catch a misreading of the
+/// format; a `getrawunsolvedblock` response from a regtest node should
+/// replace it.
+const DEFAULT_UNSOLVED_BLOCK: &str = "\
+    1111111111111111111111111111111111111111111111111111111111111111ffff00200046cc6000000000\
+    00000000000000000110010000000000e8030000000000000000000000000000000000000000000000000000\
+    000000000000000001c1abfe1a8649ebd286225ff00b4369ae6bba8e4a80ffc9b6ef9d2a6f24cfe41406e058\
+    81e299367766d313e26c05564ec91bf721d31726bd6e46e60689539a01010000000100000000000000000000\
+    00000000000000000000000000000000000000000000ffffffff0c02e803080000000000000000ffffffff02\
+    0000000000000000046a02e80300497f0f000000001976a91476a04053bda0a88bda5177b86a15c3b29f5598\
+    7388ac0000000000";
+
+/// A failure to inject into the mock's answers, consumed in order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// Answer the next request with 401 Unauthorized.
+    Unauthorized,
+    /// Answer the next request with a body that isn't JSON.
+    MalformedJson,
+    /// Reject the next submitted block as "inconclusive", like lotusd does
+    /// when it lost an orphan race.
+    Inconclusive,
+    /// Accept the next submitted block, but close the connection without
+    /// answering.
+    DropSubmission,
+}
+
+/// A mock lotusd serving `getrawunsolvedblock`, `submitblock`,
+/// `getbestblockhash`, `getblock` and `getblockcount` on localhost. Its tip is
+/// the unsolved block's previous block, and blocks submitted before are
+/// rejected as "duplicate".
+pub struct MockNode {
+    addr: SocketAddr,
+    state: Arc<Mutex<MockState>>,
+    num_submitted: watch::Receiver<usize>,
+}
+
+struct MockState {
+    unsolved_block: Vec<u8>,
+    target: [u8; 32],
+    height: u64,
+    block_confirmations: i64,
+    faults: VecDeque<Fault>,
+    methods: Vec<String>,
+    submitted_blocks: Vec<Vec<u8>>,
+    num_submitted: watch::Sender<usize>,
+}
+
+impl MockNode {
+    /// Starts serving on a free port. The default block has an easy target,
+    /// met by one in 256 hashes, matching its nBits.
+    pub async fn start() -> std::io::Result<MockNode> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let (num_submitted_sender, num_submitted) = watch::channel(0);
+        let mut target = [0; 32];
+        target[29..].copy_from_slice(&[0xff, 0xff, 0]);
+        let state = Arc::new(Mutex::new(MockState {
+            unsolved_block: hex::decode(DEFAULT_UNSOLVED_BLOCK).unwrap(),
+            target,
+            height: 1000,
+            block_confirmations: 1,
+            faults: VecDeque::new(),
+            methods: Vec::new(),
+            submitted_blocks: Vec::new(),
+            num_submitted: num_submitted_sender,
+        }));
+        tokio::spawn(accept_connections(listener, Arc::clone(&state)));
+        Ok(MockNode {
+            addr,
+            state,
+            num_submitted,
+        })
+    }
+
+    /// URL to use as `rpc_url`; any user and password are accepted.
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// Header and body served by `getrawunsolvedblock`.
+    pub fn unsolved_block(&self) -> Vec<u8> {
+        self.state.lock().unwrap().unsolved_block.clone()
+    }
+
+    pub fn set_unsolved_block(&self, block: Vec<u8>) {
+        self.state.lock().unwrap().unsolved_block = block;
+    }
+
+    /// Target (little endian) served by `getrawunsolvedblock`.
+    pub fn target(&self) -> [u8; 32] {
+        self.state.lock().unwrap().target
+    }
+
+    pub fn set_target(&self, target: [u8; 32]) {
+        self.state.lock().unwrap().target = target;
+    }
+
+    /// Confirmations `getblock` reports for any block; -1 for orphaned.
+    pub fn set_block_confirmations(&self, confirmations: i64) {
+        self.state.lock().unwrap().block_confirmations = confirmations;
+    }
+
+    pub fn inject_fault(&self, fault: Fault) {
+        self.state.lock().unwrap().faults.push_back(fault);
+    }
+
+    /// Methods of all requests so far, in order.
+    pub fn methods(&self) -> Vec<String> {
+        self.state.lock().unwrap().methods.clone()
+    }
+
+    /// All blocks passed to `submitblock`, including rejected ones.
+    pub fn submitted_blocks(&self) -> Vec<Vec<u8>> {
+        self.state.lock().unwrap().submitted_blocks.clone()
+    }
+
+    /// Waits until at least `num_blocks` blocks have been submitted.
+    pub async fn wait_for_submitted_blocks(&self, num_blocks: usize) -> Vec<Vec<u8>> {
+        let mut num_submitted = self.num_submitted.clone();
+        while *num_submitted.borrow() < num_blocks {
+            num_submitted.changed().await.expect("mock node stopped");
+        }
+        self.submitted_blocks()
+    }
+}
+
+async fn accept_connections(listener: TcpListener, state: Arc<Mutex<MockState>>) {
+    loop {
+        let (stream, _) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(_) => continue,
+        };
+        tokio::spawn(handle_connection(stream, Arc::clone(&state)));
+    }
+}
+
+/// Answers a single request and closes the connection.
+async fn handle_connection(
+    mut stream: TcpStream,
+    state: Arc<Mutex<MockState>>,
+) -> std::io::Result<()> {
+    let body = match read_request_body(&mut stream).await? {
+        Some(body) => body,
+        None => return Ok(()),
+    };
+    let (status, body) = match answer_request(&state, &body) {
+        Some(answer) => answer,
+        None => return Ok(()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Reads the headers and as much body as `Content-Length` announces.
+async fn read_request_body(stream: &mut TcpStream) -> std::io::Result<Option<Vec<u8>>> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 4096];
+    loop {
+        if let Some(headers_len) = request.windows(4).position(|window| window == b"\r\n\r\n") {
+            let headers = String::from_utf8_lossy(&request[..headers_len]).to_lowercase();
+            let content_length = headers
+                .lines()
+                .find_map(|line| line.strip_prefix("content-length:"))
+                .and_then(|content_length| content_length.trim().parse().ok())
+                .unwrap_or(0);
+            let body_start = headers_len + 4;
+            if request.len() >= body_start + content_length {
+                return Ok(Some(
+                    request[body_start..body_start + content_length].to_vec(),
+                ));
+            }
+        }
+        let num_bytes = stream.read(&mut buf).await?;
+        if num_bytes == 0 {
+            return Ok(None);
+        }
+        request.extend_from_slice(&buf[..num_bytes]);
+    }
+}
+
+/// Status and body to answer with; `None` to close the connection instead.
+fn answer_request(state: &Mutex<MockState>, body: &[u8]) -> Option<(&'static str, String)> {
+    let mut state = state.lock().unwrap();
+    let request: Value = serde_json::from_slice(body).unwrap_or(Value::Null);
+    let method = request["method"].as_str().unwrap_or_default().to_string();
+    state.methods.push(method.clone());
+    match state.faults.front() {
+        Some(Fault::Unauthorized) => {
+            state.faults.pop_front();
+            return Some(("401 Unauthorized", String::new()));
+        }
+        Some(Fault::MalformedJson) => {
+            state.faults.pop_front();
+            return Some(("200 OK", "{\"result\":".to_string()));
+        }
+        _ => {}
+    }
+    let result = match method.as_str() {
+        "getrawunsolvedblock" => {
+            let mut target = state.target;
+            target.reverse();
+            json!({
+                "blockhex": hex::encode(&state.unsolved_block),
+                "target": hex::encode(target),
+            })
+        }
+        "submitblock" => {
+            let block = match request["params"][0].as_str().map(hex::decode) {
+                Some(Ok(block)) => block,
+                _ => return Some(rpc_error(-22, "Block decode failed")),
+            };
+            let is_duplicate = state.submitted_blocks.contains(&block);
+            state.submitted_blocks.push(block);
+            let num_submitted = state.submitted_blocks.len();
+            let _ = state.num_submitted.send(num_submitted);
+            match state.faults.front() {
+                Some(Fault::Inconclusive) => {
+                    state.faults.pop_front();
+                    json!("inconclusive")
+                }
+                Some(Fault::DropSubmission) => {
+                    state.faults.pop_front();
+                    return None;
+                }
+                _ if is_duplicate => json!("duplicate"),
+                _ => Value::Null,
+            }
+        }
+        "getbestblockhash" => {
+            let mut tip = state.unsolved_block[..32].to_vec();
+            tip.reverse();
+            json!(hex::encode(tip))
+        }
+        "getblock" => json!({
+            "hash": request["params"][0],
+            "confirmations": state.block_confirmations,
+        }),
+        "getblockcount" => json!(state.height),
+        _ => return Some(rpc_error(-32601, "Method not found")),
+    };
+    let response = json!({"result": result, "error": null, "id": request["id"]});
+    Some(("200 OK", response.to_string()))
+}
+
+fn rpc_error(code: i32, message: &str) -> (&'static str, String) {
+    let response = json!({
+        "result": null,
+        "error": {"code": code, "message": message},
+        "id": null,
+    });
+    ("500 Internal Server Error", response.to_string())
+}
@@ -0,0 +1,27 @@
+use std::{sync::Arc, time::Duration};
+
+use lotus_miner_lib::{ConfigSettings, Proxy};
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let config = ConfigSettings::load_proxy()?;
+    let report_workers_interval = Duration::from_secs(60);
+    let proxy = Arc::new(Proxy::from_config(config)?);
+    tokio::spawn({
+        let proxy = Arc::clone(&proxy);
+        async move {
+            loop {
+                tokio::time::sleep(report_workers_interval).await;
+                for (worker, stats) in proxy.worker_stats() {
+                    println!(
+                        "Worker {}: {} accepted, {} rejected, {} blocks",
+                        worker, stats.accepted, stats.rejected, stats.blocks
+                    );
+                }
+            }
+        }
+    });
+    proxy.run().await?;
+
+    Ok(())
+}